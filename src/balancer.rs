@@ -0,0 +1,434 @@
+use crate::canary::Group;
+use crate::config::{Balancer, HashKey, Route, Upstream};
+use hyper::Request;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Ring points per unit of upstream weight.
+const POINTS_PER_WEIGHT: u32 = 100;
+
+/// Weight of the newest latency sample in the moving average.
+const EWMA_ALPHA: f64 = 0.3;
+
+/// Latency charged for a failed attempt, so a fast-failing upstream does not
+/// look like the quickest one.
+const FAILURE_PENALTY: Duration = Duration::from_secs(1);
+
+/// The string a hashing balancer places on the ring for `req`, or `None`
+/// when the route's balancer does not hash.
+pub fn hash_key<B>(route: &Route, req: &Request<B>, client_ip: IpAddr) -> Option<String> {
+    let value = match (route.balancer, &route.hash_key) {
+        (Balancer::IpHash, _) => None,
+        (Balancer::ConsistentHash, HashKey::Path) => Some(req.uri().path().to_string()),
+        (Balancer::ConsistentHash, HashKey::Header(name)) => req
+            .headers()
+            .get(name.as_str())
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string),
+        (Balancer::ConsistentHash, HashKey::Cookie(name)) => cookie(req, name),
+        _ => return None,
+    };
+    Some(value.unwrap_or_else(|| client_ip.to_string()))
+}
+
+/// The value of cookie `name` in `req`, if any.
+pub fn cookie<B>(req: &Request<B>, name: &str) -> Option<String> {
+    req.headers()
+        .get_all(hyper::header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v.to_string())
+}
+
+/// FNV-1a with a final mix, so ring positions survive restarts and
+/// toolchain upgrades.
+pub fn hash(value: &str) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in value.bytes() {
+        h ^= u64::from(byte);
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^ (h >> 33)
+}
+
+struct RouteBalancer {
+    balancer: Balancer,
+    urls: Vec<String>,
+    weights: Vec<i64>,
+    /// Smooth weighted round-robin counters, one per upstream.
+    current: Vec<i64>,
+    /// Sorted `(position, upstream index)` points for the hashing balancers.
+    ring: Vec<(u64, usize)>,
+}
+
+impl RouteBalancer {
+    fn new(balancer: Balancer, upstreams: &[Upstream]) -> Self {
+        let hashing = matches!(balancer, Balancer::IpHash | Balancer::ConsistentHash);
+        let mut ring = Vec::new();
+        if hashing {
+            for (index, upstream) in upstreams.iter().enumerate() {
+                for point in 0..upstream.weight * POINTS_PER_WEIGHT {
+                    ring.push((hash(&format!("{}#{}", upstream.url, point)), index));
+                }
+            }
+            ring.sort_unstable();
+        }
+        Self {
+            balancer,
+            urls: upstreams.iter().map(|u| u.url.clone()).collect(),
+            weights: upstreams.iter().map(|u| i64::from(u.weight)).collect(),
+            current: vec![0; upstreams.len()],
+            ring,
+        }
+    }
+
+    fn round_robin(&mut self, candidates: &[usize]) -> Option<usize> {
+        let total: i64 = candidates.iter().map(|&i| self.weights[i]).sum();
+        for &i in candidates {
+            self.current[i] += self.weights[i];
+        }
+        let chosen = *candidates.iter().max_by(|&&a, &&b| {
+            // Earlier upstreams win ties.
+            self.current[a].cmp(&self.current[b]).then(b.cmp(&a))
+        })?;
+        self.current[chosen] -= total;
+        Some(chosen)
+    }
+
+    /// Requests in flight to upstream `i` per unit of weight.
+    fn busyness(&self, loads: &HashMap<String, Load>, i: usize) -> f64 {
+        let in_flight = loads.get(&self.urls[i]).map_or(0, |l| l.in_flight);
+        in_flight as f64 / self.weights[i].max(1) as f64
+    }
+
+    /// The first point at or after `key`'s position owned by a candidate.
+    fn ring_lookup(&self, candidates: &[usize], key: &str) -> Option<usize> {
+        let position = hash(key);
+        let start = self.ring.partition_point(|&(point, _)| point < position);
+        self.ring[start..]
+            .iter()
+            .chain(&self.ring[..start])
+            .map(|&(_, index)| index)
+            .find(|index| candidates.contains(index))
+    }
+}
+
+#[derive(Default)]
+struct Load {
+    in_flight: usize,
+    ewma_ms: Option<f64>,
+}
+
+/// Load of one upstream for `/metrics`.
+#[derive(Debug, Clone, Serialize)]
+pub struct UpstreamLoad {
+    pub in_flight: usize,
+    pub latency_ewma_ms: Option<f64>,
+}
+
+/// Balancer state per route and upstream group, and the per-upstream load
+/// it decides on.
+pub struct Balancers {
+    routes: Mutex<HashMap<(String, Group), RouteBalancer>>,
+    loads: Arc<Mutex<HashMap<String, Load>>>,
+}
+
+impl Balancers {
+    pub fn new() -> Self {
+        Self {
+            routes: Mutex::new(HashMap::new()),
+            loads: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn configure(&self, routes: &[Route]) {
+        let mut balancers = HashMap::new();
+        for route in routes {
+            for group in [Group::Primary, Group::Canary] {
+                let upstreams = group.upstreams(route);
+                balancers.insert(
+                    (route.name.clone(), group),
+                    RouteBalancer::new(route.balancer, upstreams),
+                );
+            }
+        }
+        *self.routes.lock() = balancers;
+    }
+
+    /// Chooses among `candidates`, indexes into the upstreams of the
+    /// route's `group`. `key` comes from [`hash_key`].
+    pub fn pick(
+        &self,
+        route: &str,
+        group: Group,
+        candidates: &[usize],
+        key: Option<&str>,
+    ) -> Option<usize> {
+        if candidates.len() <= 1 {
+            return candidates.first().copied();
+        }
+        let mut routes = self.routes.lock();
+        let balancer = routes.get_mut(&(route.to_string(), group))?;
+        match (balancer.balancer, key) {
+            (Balancer::RoundRobin, _) => balancer.round_robin(candidates),
+            (Balancer::IpHash | Balancer::ConsistentHash, Some(key)) => {
+                balancer.ring_lookup(candidates, key)
+            }
+            (Balancer::IpHash | Balancer::ConsistentHash, None) => balancer.round_robin(candidates),
+            (Balancer::LeastConn, _) => {
+                let loads = self.loads.lock();
+                candidates.iter().copied().min_by(|&a, &b| {
+                    let (a, b) = (balancer.busyness(&loads, a), balancer.busyness(&loads, b));
+                    a.total_cmp(&b)
+                })
+            }
+            (Balancer::RandomTwoChoices, _) => {
+                let first = rand::random_range(0..candidates.len());
+                let second = (first + rand::random_range(1..candidates.len())) % candidates.len();
+                let (a, b) = (candidates[first], candidates[second]);
+                let loads = self.loads.lock();
+                if balancer.busyness(&loads, b) < balancer.busyness(&loads, a) {
+                    Some(b)
+                } else {
+                    Some(a)
+                }
+            }
+            (Balancer::EwmaLatency, _) => {
+                let loads = self.loads.lock();
+                candidates.iter().copied().min_by(|&a, &b| {
+                    // Upstreams without samples score zero so they get tried.
+                    let score = |i: usize| {
+                        let load = loads.get(&balancer.urls[i]);
+                        let ewma = load.and_then(|l| l.ewma_ms).unwrap_or(0.0);
+                        let in_flight = load.map_or(0, |l| l.in_flight);
+                        ewma * (in_flight + 1) as f64 / balancer.weights[i].max(1) as f64
+                    };
+                    score(a).total_cmp(&score(b))
+                })
+            }
+        }
+    }
+
+    /// Counts a request to `url` as in flight until the guard is dropped.
+    pub fn start(&self, url: &str) -> LoadGuard {
+        self.loads
+            .lock()
+            .entry(url.to_string())
+            .or_default()
+            .in_flight += 1;
+        LoadGuard {
+            loads: Arc::clone(&self.loads),
+            url: url.to_string(),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn stats(&self, url: &str) -> Option<UpstreamLoad> {
+        self.loads.lock().get(url).map(|load| UpstreamLoad {
+            in_flight: load.in_flight,
+            latency_ewma_ms: load.ewma_ms,
+        })
+    }
+}
+
+impl Default for Balancers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One request in flight to an upstream.
+pub struct LoadGuard {
+    loads: Arc<Mutex<HashMap<String, Load>>>,
+    url: String,
+    started: Instant,
+}
+
+impl LoadGuard {
+    /// Folds the time to the response head into the upstream's latency
+    /// average.
+    pub fn observe(&self, success: bool) {
+        let mut elapsed = self.started.elapsed();
+        if !success {
+            elapsed = elapsed.max(FAILURE_PENALTY);
+        }
+        let sample = elapsed.as_secs_f64() * 1000.0;
+        if let Some(load) = self.loads.lock().get_mut(&self.url) {
+            load.ewma_ms = Some(match load.ewma_ms {
+                Some(ewma) => EWMA_ALPHA * sample + (1.0 - EWMA_ALPHA) * ewma,
+                None => sample,
+            });
+        }
+    }
+}
+
+impl Drop for LoadGuard {
+    fn drop(&mut self) {
+        if let Some(load) = self.loads.lock().get_mut(&self.url) {
+            load.in_flight = load.in_flight.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balancers(balancer: &str) -> Balancers {
+        let route: Route = toml::from_str(&format!(
+            r#"
+                name = "api"
+                hosts = ["example.com"]
+                path_prefix = "/"
+                balancer = "{}"
+                hash_key = {{ header = "X-User" }}
+
+                [[upstreams]]
+                url = "http://a"
+                weight = 2
+
+                [[upstreams]]
+                url = "http://b"
+
+                [[upstreams]]
+                url = "http://c"
+            "#,
+            balancer
+        ))
+        .unwrap();
+        let balancers = Balancers::new();
+        balancers.configure(&[route]);
+        balancers
+    }
+
+    #[test]
+    fn test_round_robin_follows_weights() {
+        let balancers = balancers("round_robin");
+        let picks: Vec<_> = (0..8)
+            .map(|_| {
+                balancers
+                    .pick("api", Group::Primary, &[0, 1, 2], None)
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(picks, vec![0, 1, 2, 0, 0, 1, 2, 0]);
+        assert_eq!(balancers.pick("api", Group::Primary, &[1], None), Some(1));
+        assert_eq!(balancers.pick("api", Group::Primary, &[], None), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_least_conn_and_ewma_use_load() {
+        let balancers = balancers("least_conn");
+        let _a = [balancers.start("http://a"), balancers.start("http://a")];
+        let b = balancers.start("http://b");
+        // a has 2 in flight at weight 2, b has 1 at weight 1; c has none.
+        assert_eq!(
+            balancers.pick("api", Group::Primary, &[0, 1, 2], None),
+            Some(2)
+        );
+        assert_eq!(
+            balancers.pick("api", Group::Primary, &[0, 1], None),
+            Some(0)
+        );
+        drop(b);
+        assert_eq!(
+            balancers.pick("api", Group::Primary, &[0, 1], None),
+            Some(1)
+        );
+
+        let balancers = self::balancers("ewma_latency");
+        for (url, ms) in [("http://a", 200), ("http://b", 50), ("http://c", 10)] {
+            let guard = balancers.start(url);
+            tokio::time::advance(Duration::from_millis(ms)).await;
+            guard.observe(url != "http://c");
+        }
+        // c answered fastest but failed, so it carries the penalty.
+        assert_eq!(
+            balancers.pick("api", Group::Primary, &[0, 1, 2], None),
+            Some(1)
+        );
+        assert_eq!(balancers.stats("http://b").unwrap().in_flight, 0);
+        assert_eq!(
+            balancers.stats("http://c").unwrap().latency_ewma_ms,
+            Some(1000.0)
+        );
+    }
+
+    #[test]
+    fn test_consistent_hash_is_sticky_and_skips_unavailable() {
+        let balancers = balancers("consistent_hash");
+        let mut counts = [0; 3];
+        for user in 0..400 {
+            let key = format!("user-{}", user);
+            let first = balancers
+                .pick("api", Group::Primary, &[0, 1, 2], Some(&key))
+                .unwrap();
+            assert_eq!(
+                balancers.pick("api", Group::Primary, &[0, 1, 2], Some(&key)),
+                Some(first)
+            );
+            counts[first] += 1;
+
+            // Losing an upstream only moves the keys it owned.
+            let moved = balancers
+                .pick("api", Group::Primary, &[0, 1], Some(&key))
+                .unwrap();
+            if first != 2 {
+                assert_eq!(moved, first);
+            }
+        }
+        assert!(counts.iter().all(|&n| n > 50), "{:?}", counts);
+        assert!(
+            counts[0] > counts[1] && counts[0] > counts[2],
+            "{:?}",
+            counts
+        );
+    }
+
+    #[test]
+    fn test_hash_key_sources() {
+        let client_ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut route: Route = toml::from_str(
+            r#"
+                name = "api"
+                hosts = ["example.com"]
+                path_prefix = "/"
+                balancer = "consistent_hash"
+                hash_key = { cookie = "session" }
+                upstreams = []
+            "#,
+        )
+        .unwrap();
+        let req = Request::builder()
+            .uri("/a/b?x=1")
+            .header("cookie", "theme=dark; session=abc")
+            .body(())
+            .unwrap();
+        assert_eq!(hash_key(&route, &req, client_ip).as_deref(), Some("abc"));
+
+        route.hash_key = HashKey::Header("X-User".to_string());
+        assert_eq!(
+            hash_key(&route, &req, client_ip).as_deref(),
+            Some("10.0.0.1")
+        );
+        route.hash_key = HashKey::Path;
+        assert_eq!(hash_key(&route, &req, client_ip).as_deref(), Some("/a/b"));
+        route.balancer = Balancer::IpHash;
+        assert_eq!(
+            hash_key(&route, &req, client_ip).as_deref(),
+            Some("10.0.0.1")
+        );
+        route.balancer = Balancer::LeastConn;
+        assert_eq!(hash_key(&route, &req, client_ip), None);
+    }
+}
@@ -0,0 +1,283 @@
+use crate::config::BreakpointRule;
+use crate::proxy::host_matches;
+use crate::url_path;
+use chrono::{DateTime, Utc};
+use hyper::HeaderMap;
+use hyper::header::{HeaderName, HeaderValue};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BreakpointPhase {
+    Request,
+    Response,
+}
+
+/// An exchange held at a breakpoint, as shown to the control API and TUI.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingBreakpoint {
+    pub id: u64,
+    pub phase: BreakpointPhase,
+    pub created_at: DateTime<Utc>,
+    pub method: String,
+    pub host: String,
+    pub path: String,
+    /// Upstream status, only set in the response phase.
+    pub status: Option<u16>,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+/// Changes applied to a paused exchange before it continues.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BreakpointEdits {
+    #[serde(default)]
+    pub status: Option<u16>,
+    #[serde(default)]
+    pub headers: Option<Vec<(String, String)>>,
+    #[serde(default)]
+    pub body: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum BreakpointDecision {
+    Continue(BreakpointEdits),
+    Abort(u16),
+}
+
+type PendingEntry = (PendingBreakpoint, oneshot::Sender<BreakpointDecision>);
+
+pub struct BreakpointManager {
+    rules: RwLock<Vec<BreakpointRule>>,
+    pending: Mutex<HashMap<u64, PendingEntry>>,
+    next_id: AtomicU64,
+}
+
+impl BreakpointManager {
+    pub fn new() -> Self {
+        Self {
+            rules: RwLock::new(Vec::new()),
+            pending: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    pub fn set_rules(&self, rules: Vec<BreakpointRule>) {
+        *self.rules.write() = rules;
+    }
+
+    pub fn add_rule(&self, rule: BreakpointRule) {
+        self.rules.write().push(rule);
+    }
+
+    pub fn remove_rule(&self, index: usize) -> bool {
+        let mut rules = self.rules.write();
+        if index < rules.len() {
+            rules.remove(index);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn get_rules(&self) -> Vec<BreakpointRule> {
+        self.rules.read().clone()
+    }
+
+    pub fn matches(&self, phase: BreakpointPhase, method: &str, host: &str, path: &str) -> bool {
+        self.rules
+            .read()
+            .iter()
+            .any(|rule| rule_matches(rule, phase, method, host, path))
+    }
+
+    pub fn get_pending(&self) -> Vec<PendingBreakpoint> {
+        let mut pending: Vec<_> = self
+            .pending
+            .lock()
+            .values()
+            .map(|(entry, _)| entry.clone())
+            .collect();
+        pending.sort_by_key(|entry| entry.id);
+        pending
+    }
+
+    /// Hands a decision to a paused exchange. Returns false if it is no
+    /// longer waiting (already resolved, timed out or client gone).
+    pub fn resolve(&self, id: u64, decision: BreakpointDecision) -> bool {
+        match self.pending.lock().remove(&id) {
+            Some((_, tx)) => tx.send(decision).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Parks `entry` until someone resolves it or `timeout` elapses.
+    /// `None` means the exchange should pass through unchanged.
+    pub async fn pause(
+        &self,
+        mut entry: PendingBreakpoint,
+        timeout: Duration,
+    ) -> Option<BreakpointDecision> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        entry.id = id;
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().insert(id, (entry, tx));
+
+        // Drop the entry even if the client disconnects while we wait.
+        let _guard = PendingGuard { manager: self, id };
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(decision)) => Some(decision),
+            _ => None,
+        }
+    }
+}
+
+impl Default for BreakpointManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct PendingGuard<'a> {
+    manager: &'a BreakpointManager,
+    id: u64,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.manager.pending.lock().remove(&self.id);
+    }
+}
+
+fn rule_matches(
+    rule: &BreakpointRule,
+    phase: BreakpointPhase,
+    method: &str,
+    host: &str,
+    path: &str,
+) -> bool {
+    let phase_enabled = match phase {
+        BreakpointPhase::Request => rule.request,
+        BreakpointPhase::Response => rule.response,
+    };
+    phase_enabled
+        && rule
+            .method
+            .as_ref()
+            .is_none_or(|m| m.eq_ignore_ascii_case(method))
+        && rule.host.as_ref().is_none_or(|h| host_matches(h, host))
+        && rule
+            .path_prefix
+            .as_ref()
+            .is_none_or(|p| url_path::has_prefix(path, p))
+}
+
+pub fn header_pairs(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.to_string(),
+                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            )
+        })
+        .collect()
+}
+
+/// Rebuilds a header map from edited pairs, skipping invalid entries.
+pub fn header_map(pairs: &[(String, String)]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (name, value) in pairs {
+        match (
+            HeaderName::from_bytes(name.trim().as_bytes()),
+            HeaderValue::from_str(value.trim()),
+        ) {
+            (Ok(name), Ok(value)) => {
+                headers.append(name, value);
+            }
+            _ => tracing::warn!("Ignoring invalid edited header: {}: {}", name, value),
+        }
+    }
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(host: Option<&str>, path: Option<&str>, method: Option<&str>) -> BreakpointRule {
+        BreakpointRule {
+            host: host.map(str::to_string),
+            path_prefix: path.map(str::to_string),
+            method: method.map(str::to_string),
+            request: true,
+            response: false,
+        }
+    }
+
+    #[test]
+    fn test_rule_matching() {
+        let manager = BreakpointManager::new();
+        manager.add_rule(rule(Some("*.example.org"), Some("/api"), Some("post")));
+
+        assert!(manager.matches(BreakpointPhase::Request, "POST", "a.example.org", "/api/x"));
+        assert!(!manager.matches(BreakpointPhase::Request, "GET", "a.example.org", "/api/x"));
+        assert!(!manager.matches(BreakpointPhase::Request, "POST", "example.com", "/api/x"));
+        assert!(!manager.matches(BreakpointPhase::Request, "POST", "a.example.org", "/apiv2"));
+        assert!(!manager.matches(BreakpointPhase::Response, "POST", "a.example.org", "/api"));
+    }
+
+    #[tokio::test]
+    async fn test_pause_and_resolve() {
+        let manager = std::sync::Arc::new(BreakpointManager::new());
+        let entry = PendingBreakpoint {
+            id: 0,
+            phase: BreakpointPhase::Request,
+            created_at: Utc::now(),
+            method: "GET".to_string(),
+            host: "example.com".to_string(),
+            path: "/".to_string(),
+            status: None,
+            headers: Vec::new(),
+            body: String::new(),
+        };
+
+        let waiter = {
+            let manager = std::sync::Arc::clone(&manager);
+            tokio::spawn(async move { manager.pause(entry, Duration::from_secs(5)).await })
+        };
+        while manager.get_pending().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        let id = manager.get_pending()[0].id;
+        assert!(manager.resolve(id, BreakpointDecision::Abort(418)));
+
+        let decision = waiter.await.unwrap();
+        assert!(matches!(decision, Some(BreakpointDecision::Abort(418))));
+        assert!(manager.get_pending().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pause_times_out() {
+        let manager = BreakpointManager::new();
+        let entry = PendingBreakpoint {
+            id: 0,
+            phase: BreakpointPhase::Response,
+            created_at: Utc::now(),
+            method: "GET".to_string(),
+            host: "example.com".to_string(),
+            path: "/".to_string(),
+            status: Some(200),
+            headers: Vec::new(),
+            body: String::new(),
+        };
+
+        assert!(manager.pause(entry, Duration::from_secs(1)).await.is_none());
+        assert!(manager.get_pending().is_empty());
+    }
+}
@@ -0,0 +1,763 @@
+use crate::config::{CacheConfig, Route};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::{Method, Request, Response, StatusCode};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Statuses a cache may store without being told to (RFC 9110 §15.1).
+const CACHEABLE_STATUSES: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+/// How the cache took part in an exchange.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheStatus {
+    /// Answered from a fresh stored response.
+    Hit,
+    /// A stale stored response was confirmed by the upstream.
+    Revalidated,
+    /// Sent upstream; the response may have been stored.
+    Miss,
+    /// Not eligible for the cache, such as a POST or an authorized request.
+    Bypass,
+}
+
+/// The `Cache-Control` directives the cache acts on.
+#[derive(Debug, Default, PartialEq)]
+struct Directives {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+}
+
+fn directives(headers: &HeaderMap) -> Directives {
+    let mut directives = Directives::default();
+    let values = headers.get_all(header::CACHE_CONTROL);
+    for directive in values
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+    {
+        let (name, argument) = match directive.split_once('=') {
+            Some((name, argument)) => (name, Some(argument.trim().trim_matches('"'))),
+            None => (directive, None),
+        };
+        let seconds = argument.and_then(|a| a.parse().ok());
+        match name.trim().to_ascii_lowercase().as_str() {
+            "no-store" => directives.no_store = true,
+            "no-cache" => directives.no_cache = true,
+            "private" => directives.private = true,
+            "max-age" => directives.max_age = seconds,
+            "s-maxage" => directives.s_maxage = seconds,
+            _ => {}
+        }
+    }
+    // Pragma only counts when Cache-Control is absent (RFC 9111 §5.4).
+    if values.iter().next().is_none()
+        && headers
+            .get_all(header::PRAGMA)
+            .iter()
+            .any(|v| v.as_bytes().eq_ignore_ascii_case(b"no-cache"))
+    {
+        directives.no_cache = true;
+    }
+    directives
+}
+
+fn http_date(value: &HeaderValue) -> Option<DateTime<Utc>> {
+    let value = value.to_str().ok()?;
+    DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+/// How long a response stays fresh for a shared cache.
+fn freshness(headers: &HeaderMap, directives: &Directives, default_ttl: Duration) -> Duration {
+    if let Some(seconds) = directives.s_maxage.or(directives.max_age) {
+        return Duration::from_secs(seconds);
+    }
+    if let Some(expires) = headers.get(header::EXPIRES) {
+        let date = headers
+            .get(header::DATE)
+            .and_then(http_date)
+            .unwrap_or_else(Utc::now);
+        // An invalid date such as `0` means already expired.
+        return http_date(expires)
+            .and_then(|expires| (expires - date).to_std().ok())
+            .unwrap_or_default();
+    }
+    default_ttl
+}
+
+/// Identifies a resource: host plus path and query.
+pub fn key<B>(req: &Request<B>, host: &str) -> String {
+    let path = req.uri().path_and_query().map_or("/", |pq| pq.as_str());
+    format!("{}{}", host.to_ascii_lowercase(), path)
+}
+
+/// A stored response.
+pub struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    /// Request header values the response varies on.
+    vary: Vec<(HeaderName, Vec<HeaderValue>)>,
+    stored_at: Instant,
+    /// `Age` the response already had when it arrived.
+    initial_age: Duration,
+    ttl: Duration,
+    /// Must be revalidated before every use.
+    no_cache: bool,
+}
+
+impl Entry {
+    fn age(&self) -> Duration {
+        self.initial_age + self.stored_at.elapsed()
+    }
+
+    fn fresh(&self) -> bool {
+        !self.no_cache && self.age() < self.ttl
+    }
+
+    fn size(&self) -> u64 {
+        let headers: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        (self.body.len() + headers) as u64
+    }
+
+    fn matches(&self, headers: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, values)| headers.get_all(name).iter().eq(values.iter()))
+    }
+
+    fn has_validators(&self) -> bool {
+        self.headers.contains_key(header::ETAG) || self.headers.contains_key(header::LAST_MODIFIED)
+    }
+
+    /// Whether the client's own conditional headers are satisfied by this
+    /// entry, so it can be answered with a 304.
+    fn not_modified(&self, headers: &HeaderMap) -> bool {
+        if let Some(tags) = headers.get(header::IF_NONE_MATCH) {
+            let Some(etag) = self.headers.get(header::ETAG) else {
+                return false;
+            };
+            let weak = |tag: &[u8]| tag.strip_prefix(b"W/").unwrap_or(tag).to_vec();
+            let etag = weak(etag.as_bytes());
+            return tags.as_bytes() == b"*"
+                || tags
+                    .as_bytes()
+                    .split(|&b| b == b',')
+                    .any(|tag| weak(tag.trim_ascii()) == etag);
+        }
+        match (
+            headers.get(header::IF_MODIFIED_SINCE).and_then(http_date),
+            self.headers.get(header::LAST_MODIFIED).and_then(http_date),
+        ) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        }
+    }
+
+    fn response(&self, head: bool, not_modified: bool) -> Response<Bytes> {
+        let mut response = Response::new(if head || not_modified {
+            Bytes::new()
+        } else {
+            self.body.clone()
+        });
+        *response.status_mut() = if not_modified {
+            StatusCode::NOT_MODIFIED
+        } else {
+            self.status
+        };
+        *response.headers_mut() = self.headers.clone();
+        if not_modified {
+            response.headers_mut().remove(header::CONTENT_LENGTH);
+        }
+        response
+            .headers_mut()
+            .insert(header::AGE, HeaderValue::from(self.age().as_secs()));
+        response
+    }
+}
+
+/// What the cache can do for a request.
+pub enum Lookup {
+    Hit(Response<Bytes>),
+    /// A stored response needing revalidation; send the request upstream
+    /// with [`add_validators`].
+    Stale(Arc<Entry>),
+    Miss,
+    Bypass,
+}
+
+/// Adds conditional headers so the upstream can answer a stale entry with
+/// 304 Not Modified.
+pub fn add_validators<B>(req: &mut Request<B>, entry: &Entry) {
+    if let Some(etag) = entry.headers.get(header::ETAG) {
+        req.headers_mut()
+            .insert(header::IF_NONE_MATCH, etag.clone());
+    }
+    if let Some(modified) = entry.headers.get(header::LAST_MODIFIED) {
+        req.headers_mut()
+            .insert(header::IF_MODIFIED_SINCE, modified.clone());
+    }
+}
+
+/// A response on its way to the client that is worth keeping once its body
+/// has been read.
+pub struct Storable {
+    key: String,
+    entry: Entry,
+}
+
+/// Hit and miss counts and usage of one route's cache.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: u64,
+    pub hits: u64,
+    pub revalidated: u64,
+    pub misses: u64,
+    pub bypassed: u64,
+}
+
+struct Slot {
+    variants: Vec<Arc<Entry>>,
+    used: u64,
+}
+
+/// One route's stored responses, evicted least recently used first.
+struct Store {
+    config: CacheConfig,
+    slots: HashMap<String, Slot>,
+    /// Keys by when they were last used.
+    recency: BTreeMap<u64, String>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl Store {
+    fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            slots: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    fn touch(&mut self, key: &str) {
+        if let Some(slot) = self.slots.get_mut(key) {
+            self.recency.remove(&slot.used);
+            self.clock += 1;
+            slot.used = self.clock;
+            self.recency.insert(self.clock, key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        let Some(slot) = self.slots.remove(key) else {
+            return false;
+        };
+        self.recency.remove(&slot.used);
+        self.stats.entries -= slot.variants.len();
+        self.stats.bytes -= slot.variants.iter().map(|e| e.size()).sum::<u64>();
+        true
+    }
+
+    fn insert(&mut self, key: String, entry: Entry) {
+        let size = entry.size();
+        if size > self.config.max_bytes {
+            return;
+        }
+        let slot = self.slots.entry(key.clone()).or_insert(Slot {
+            variants: Vec::new(),
+            used: 0,
+        });
+        if let Some(index) = slot.variants.iter().position(|v| v.vary == entry.vary) {
+            let old = slot.variants.swap_remove(index);
+            self.stats.entries -= 1;
+            self.stats.bytes -= old.size();
+        }
+        slot.variants.push(Arc::new(entry));
+        self.stats.entries += 1;
+        self.stats.bytes += size;
+        self.touch(&key);
+
+        while self.stats.bytes > self.config.max_bytes {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            self.remove(&oldest);
+        }
+    }
+}
+
+/// Per-route response caches.
+pub struct ResponseCache {
+    stores: Mutex<BTreeMap<String, Store>>,
+}
+
+impl ResponseCache {
+    pub fn new() -> Self {
+        Self {
+            stores: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn configure(&self, routes: &[Route]) {
+        *self.stores.lock() = routes
+            .iter()
+            .filter_map(|route| {
+                let config = route.cache.clone()?;
+                Some((route.name.clone(), Store::new(config)))
+            })
+            .collect();
+    }
+
+    pub fn lookup<B>(&self, route: &str, key: &str, req: &Request<B>) -> Lookup {
+        let head = req.method() == Method::HEAD;
+        if !(req.method() == Method::GET || head)
+            || req.headers().contains_key(header::AUTHORIZATION)
+        {
+            return Lookup::Bypass;
+        }
+        let request = directives(req.headers());
+        if request.no_store {
+            return Lookup::Bypass;
+        }
+        let conditional = req.headers().contains_key(header::IF_NONE_MATCH)
+            || req.headers().contains_key(header::IF_MODIFIED_SINCE);
+
+        let mut stores = self.stores.lock();
+        let Some(store) = stores.get_mut(route) else {
+            return Lookup::Bypass;
+        };
+        let entry = store
+            .slots
+            .get(key)
+            .and_then(|slot| slot.variants.iter().find(|e| e.matches(req.headers())))
+            .cloned();
+        let Some(entry) = entry else {
+            return if conditional {
+                Lookup::Bypass
+            } else {
+                Lookup::Miss
+            };
+        };
+        store.touch(key);
+
+        let acceptable = !request.no_cache
+            && request
+                .max_age
+                .is_none_or(|max_age| entry.age() <= Duration::from_secs(max_age));
+        if entry.fresh() && acceptable {
+            let not_modified = conditional && entry.not_modified(req.headers());
+            Lookup::Hit(entry.response(head, not_modified))
+        } else if conditional || head {
+            // The client's validators or a bodiless response would be
+            // mixed up with the cache's own revalidation.
+            Lookup::Bypass
+        } else if entry.has_validators() {
+            Lookup::Stale(entry)
+        } else {
+            Lookup::Miss
+        }
+    }
+
+    /// Checks whether `response` to a request with `request_headers` may be
+    /// stored, before its body is read.
+    pub fn storable<B>(
+        &self,
+        route: &str,
+        key: &str,
+        request_headers: &HeaderMap,
+        response: &Response<B>,
+    ) -> Option<Storable> {
+        let stores = self.stores.lock();
+        let config = &stores.get(route)?.config;
+        let headers = response.headers();
+        let status = response.status();
+        let response_directives = directives(headers);
+        if !CACHEABLE_STATUSES.contains(&status.as_u16())
+            || response_directives.no_store
+            || response_directives.private
+            || headers.contains_key(header::SET_COOKIE)
+        {
+            return None;
+        }
+        if headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse::<u64>().ok())
+            .is_some_and(|len| len > config.max_body_bytes)
+        {
+            return None;
+        }
+
+        let mut vary = Vec::new();
+        let names = headers.get_all(header::VARY);
+        for name in names
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+        {
+            let name = name.trim();
+            if name == "*" {
+                return None;
+            }
+            let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+            let values = request_headers.get_all(&name).iter().cloned().collect();
+            vary.push((name, values));
+        }
+
+        let default_ttl = Duration::from_secs(config.default_ttl_secs);
+        let entry = Entry {
+            status,
+            headers: headers.clone(),
+            body: Bytes::new(),
+            vary,
+            stored_at: Instant::now(),
+            initial_age: age(headers),
+            ttl: freshness(headers, &response_directives, default_ttl),
+            no_cache: response_directives.no_cache,
+        };
+        if !entry.fresh() && !entry.has_validators() {
+            return None;
+        }
+        Some(Storable {
+            key: key.to_string(),
+            entry,
+        })
+    }
+
+    /// Largest body the route stores.
+    pub fn max_body_bytes(&self, route: &str) -> u64 {
+        self.stores
+            .lock()
+            .get(route)
+            .map_or(0, |store| store.config.max_body_bytes)
+    }
+
+    pub fn insert(&self, route: &str, storable: Storable, body: Bytes) {
+        if let Some(store) = self.stores.lock().get_mut(route) {
+            let Storable { key, mut entry } = storable;
+            entry.body = body;
+            store.insert(key, entry);
+        }
+    }
+
+    /// Freshens `entry` with the headers of the upstream's 304 and returns
+    /// the stored response to send instead.
+    pub fn revalidated(
+        &self,
+        route: &str,
+        key: &str,
+        entry: &Entry,
+        not_modified: &HeaderMap,
+    ) -> Response<Bytes> {
+        let mut headers = entry.headers.clone();
+        for name in not_modified.keys() {
+            if *name == header::CONTENT_LENGTH || *name == header::TRANSFER_ENCODING {
+                continue;
+            }
+            headers.remove(name);
+            for value in not_modified.get_all(name) {
+                headers.append(name, value.clone());
+            }
+        }
+        let mut stores = self.stores.lock();
+        let default_ttl = stores.get(route).map_or(Duration::ZERO, |store| {
+            Duration::from_secs(store.config.default_ttl_secs)
+        });
+        let response_directives = directives(&headers);
+        let fresh = Entry {
+            status: entry.status,
+            body: entry.body.clone(),
+            vary: entry.vary.clone(),
+            stored_at: Instant::now(),
+            initial_age: age(not_modified),
+            ttl: freshness(&headers, &response_directives, default_ttl),
+            no_cache: response_directives.no_cache,
+            headers,
+        };
+        let response = fresh.response(false, false);
+        if let Some(store) = stores.get_mut(route) {
+            store.insert(key.to_string(), fresh);
+        }
+        response
+    }
+
+    /// Drops whatever is stored for `key` on the route, after a request
+    /// that may have changed it.
+    pub fn invalidate(&self, route: &str, key: &str) {
+        if let Some(store) = self.stores.lock().get_mut(route) {
+            store.remove(key);
+        }
+    }
+
+    /// Drops the entries for `key`, or for every key starting with
+    /// `prefix`, on all routes. Returns how many keys went.
+    pub fn purge(&self, key: Option<&str>, prefix: Option<&str>) -> usize {
+        let mut purged = 0;
+        for store in self.stores.lock().values_mut() {
+            let doomed: Vec<String> = store
+                .slots
+                .keys()
+                .filter(|k| Some(k.as_str()) == key || prefix.is_some_and(|p| k.starts_with(p)))
+                .cloned()
+                .collect();
+            for k in doomed {
+                purged += usize::from(store.remove(&k));
+            }
+        }
+        purged
+    }
+
+    pub fn record(&self, route: &str, status: CacheStatus) {
+        if let Some(store) = self.stores.lock().get_mut(route) {
+            let counter = match status {
+                CacheStatus::Hit => &mut store.stats.hits,
+                CacheStatus::Revalidated => &mut store.stats.revalidated,
+                CacheStatus::Miss => &mut store.stats.misses,
+                CacheStatus::Bypass => &mut store.stats.bypassed,
+            };
+            *counter += 1;
+        }
+    }
+
+    pub fn stats(&self) -> BTreeMap<String, CacheStats> {
+        self.stores
+            .lock()
+            .iter()
+            .map(|(route, store)| (route.clone(), store.stats.clone()))
+            .collect()
+    }
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn age(headers: &HeaderMap) -> Duration {
+    let seconds = headers
+        .get(header::AGE)
+        .and_then(|v| v.to_str().ok()?.parse().ok())
+        .unwrap_or(0);
+    Duration::from_secs(seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(max_bytes: u64) -> ResponseCache {
+        let route: Route = toml::from_str(&format!(
+            r#"
+                name = "api"
+                hosts = ["example.com"]
+                upstreams = []
+                cache = {{ max_bytes = {max_bytes} }}
+            "#
+        ))
+        .unwrap();
+        let cache = ResponseCache::new();
+        cache.configure(&[route]);
+        cache
+    }
+
+    fn get(headers: &[(&str, &str)]) -> Request<()> {
+        let mut req = Request::builder().uri("/items?page=1");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(()).unwrap()
+    }
+
+    fn response(headers: &[(&str, &str)]) -> Response<()> {
+        let mut response = Response::builder();
+        for (name, value) in headers {
+            response = response.header(*name, *value);
+        }
+        response.body(()).unwrap()
+    }
+
+    fn store(cache: &ResponseCache, req: &Request<()>, headers: &[(&str, &str)], body: &str) {
+        let key = key(req, "Example.com");
+        let storable = cache
+            .storable("api", &key, req.headers(), &response(headers))
+            .expect("storable");
+        cache.insert("api", storable, Bytes::from(body.to_string()));
+    }
+
+    #[test]
+    fn test_directives_and_freshness() {
+        let headers = response(&[("cache-control", "public, S-MaxAge=60, max-age=\"10\"")]);
+        let parsed = directives(headers.headers());
+        assert_eq!(parsed.s_maxage, Some(60));
+        assert_eq!(
+            freshness(headers.headers(), &parsed, Duration::ZERO),
+            Duration::from_secs(60)
+        );
+
+        let expires = response(&[
+            ("date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+            ("expires", "Sun, 06 Nov 1994 08:50:07 GMT"),
+        ]);
+        let parsed = directives(expires.headers());
+        assert_eq!(
+            freshness(expires.headers(), &parsed, Duration::ZERO),
+            Duration::from_secs(30)
+        );
+        let invalid = response(&[("expires", "0")]);
+        assert_eq!(
+            freshness(invalid.headers(), &parsed, Duration::from_secs(5)),
+            Duration::ZERO
+        );
+
+        let pragma = response(&[("pragma", "no-cache")]);
+        assert!(directives(pragma.headers()).no_cache);
+    }
+
+    #[test]
+    fn test_hit_miss_and_bypass() {
+        let cache = cache(1 << 20);
+        let req = get(&[]);
+        let key = key(&req, "Example.com");
+        assert_eq!(key, "example.com/items?page=1");
+        assert!(matches!(cache.lookup("api", &key, &req), Lookup::Miss));
+
+        store(
+            &cache,
+            &req,
+            &[("cache-control", "max-age=60"), ("etag", "\"v1\"")],
+            "one",
+        );
+        let Lookup::Hit(hit) = cache.lookup("api", &key, &req) else {
+            panic!("expected a hit");
+        };
+        assert_eq!(hit.body(), "one");
+        assert_eq!(hit.headers()["age"], "0");
+
+        let conditional = get(&[("if-none-match", "W/\"v1\"")]);
+        let Lookup::Hit(hit) = cache.lookup("api", &key, &conditional) else {
+            panic!("expected a hit");
+        };
+        assert_eq!(hit.status(), StatusCode::NOT_MODIFIED);
+
+        let no_cache = get(&[("cache-control", "no-cache")]);
+        assert!(matches!(
+            cache.lookup("api", &key, &no_cache),
+            Lookup::Stale(_)
+        ));
+        let authorized = get(&[("authorization", "Bearer x")]);
+        assert!(matches!(
+            cache.lookup("api", &key, &authorized),
+            Lookup::Bypass
+        ));
+
+        for refused in [
+            &[("cache-control", "no-store")][..],
+            &[("cache-control", "private, max-age=60")],
+            &[("cache-control", "max-age=60"), ("vary", "*")],
+            &[("cache-control", "max-age=60"), ("set-cookie", "a=b")],
+            &[],
+        ] {
+            assert!(
+                cache
+                    .storable("api", &key, req.headers(), &response(refused))
+                    .is_none(),
+                "{refused:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_vary_keeps_variants_apart() {
+        let cache = cache(1 << 20);
+        let headers = [("cache-control", "max-age=60"), ("vary", "Accept-Language")];
+        let en = get(&[("accept-language", "en")]);
+        let de = get(&[("accept-language", "de")]);
+        let key = key(&en, "example.com");
+        store(&cache, &en, &headers, "hello");
+        assert!(matches!(cache.lookup("api", &key, &de), Lookup::Miss));
+        store(&cache, &de, &headers, "hallo");
+
+        for (req, body) in [(&en, "hello"), (&de, "hallo")] {
+            let Lookup::Hit(hit) = cache.lookup("api", &key, req) else {
+                panic!("expected a hit");
+            };
+            assert_eq!(hit.body(), body);
+        }
+        assert_eq!(cache.stats()["api"].entries, 2);
+    }
+
+    #[test]
+    fn test_revalidation_refreshes_entry() {
+        let cache = cache(1 << 20);
+        let req = get(&[]);
+        let key = key(&req, "example.com");
+        store(
+            &cache,
+            &req,
+            &[("cache-control", "no-cache"), ("etag", "\"v1\"")],
+            "one",
+        );
+        let Lookup::Stale(entry) = cache.lookup("api", &key, &req) else {
+            panic!("expected a stale entry");
+        };
+        let mut upstream = get(&[]);
+        add_validators(&mut upstream, &entry);
+        assert_eq!(upstream.headers()["if-none-match"], "\"v1\"");
+
+        let not_modified = response(&[("cache-control", "max-age=60"), ("etag", "\"v1\"")]);
+        let response = cache.revalidated("api", &key, &entry, not_modified.headers());
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), "one");
+        assert!(matches!(cache.lookup("api", &key, &req), Lookup::Hit(_)));
+    }
+
+    #[test]
+    fn test_lru_eviction_and_purge() {
+        let cache = cache(250);
+        let headers = [("cache-control", "max-age=60")];
+        let body = "x".repeat(80);
+        let paths = ["/a", "/b", "/c"];
+        for path in paths {
+            let req = Request::builder().uri(path).body(()).unwrap();
+            store(&cache, &req, &headers, &body);
+            if path == "/b" {
+                // Using /a makes /b the least recently used.
+                let a = Request::builder().uri("/a").body(()).unwrap();
+                assert!(matches!(
+                    cache.lookup("api", "example.com/a", &a),
+                    Lookup::Hit(_)
+                ));
+            }
+        }
+        let stats = &cache.stats()["api"];
+        assert_eq!(stats.entries, 2);
+        assert!(stats.bytes <= 250);
+        let b = Request::builder().uri("/b").body(()).unwrap();
+        assert!(matches!(
+            cache.lookup("api", "example.com/b", &b),
+            Lookup::Miss
+        ));
+
+        assert_eq!(cache.purge(Some("example.com/a"), None), 1);
+        assert_eq!(cache.purge(None, Some("example.com/")), 1);
+        assert_eq!(cache.stats()["api"].entries, 0);
+    }
+}
@@ -0,0 +1,204 @@
+use crate::balancer;
+use crate::config::{Canary, Route, Upstream};
+use hyper::Request;
+use parking_lot::RwLock;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The upstream group a request is sent to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Group {
+    Primary,
+    Canary,
+}
+
+impl Group {
+    pub fn upstreams(self, route: &Route) -> &[Upstream] {
+        match (self, &route.canary) {
+            (Group::Canary, Some(canary)) => &canary.upstreams,
+            _ => &route.upstreams,
+        }
+    }
+}
+
+struct Split {
+    percent: RwLock<f64>,
+    canary: AtomicU64,
+    primary: AtomicU64,
+}
+
+/// Snapshot of one route's split for the control API.
+#[derive(Debug, Clone, Serialize)]
+pub struct SplitStats {
+    pub route: String,
+    pub percent: f64,
+    pub canary_requests: u64,
+    pub primary_requests: u64,
+}
+
+/// Live traffic split of every route with a canary group.
+pub struct CanaryManager {
+    splits: RwLock<BTreeMap<String, Split>>,
+}
+
+impl CanaryManager {
+    pub fn new() -> Self {
+        Self {
+            splits: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn configure(&self, routes: &[Route]) {
+        *self.splits.write() = routes
+            .iter()
+            .filter_map(|route| {
+                let canary = route.canary.as_ref()?;
+                let split = Split {
+                    percent: RwLock::new(canary.percent.clamp(0.0, 100.0)),
+                    canary: AtomicU64::new(0),
+                    primary: AtomicU64::new(0),
+                };
+                Some((route.name.clone(), split))
+            })
+            .collect();
+    }
+
+    /// Picks the group for `req` and counts it.
+    pub fn choose<B>(&self, route: &Route, req: &Request<B>) -> Group {
+        let Some(canary) = &route.canary else {
+            return Group::Primary;
+        };
+        let splits = self.splits.read();
+        let Some(split) = splits.get(&route.name) else {
+            return Group::Primary;
+        };
+        if forced(canary, req) || rand::random_range(0.0..100.0) < *split.percent.read() {
+            split.canary.fetch_add(1, Ordering::Relaxed);
+            Group::Canary
+        } else {
+            split.primary.fetch_add(1, Ordering::Relaxed);
+            Group::Primary
+        }
+    }
+
+    /// Changes a route's split; false when the route has no canary group.
+    pub fn set_percent(&self, route: &str, percent: f64) -> bool {
+        match self.splits.read().get(route) {
+            Some(split) => {
+                *split.percent.write() = percent.clamp(0.0, 100.0);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn stats(&self) -> Vec<SplitStats> {
+        self.splits
+            .read()
+            .iter()
+            .map(|(route, split)| SplitStats {
+                route: route.clone(),
+                percent: *split.percent.read(),
+                canary_requests: split.canary.load(Ordering::Relaxed),
+                primary_requests: split.primary.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+impl Default for CanaryManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn forced<B>(canary: &Canary, req: &Request<B>) -> bool {
+    let header = canary.header.as_ref().is_some_and(|m| {
+        req.headers()
+            .get_all(m.name.as_str())
+            .iter()
+            .any(|v| v.as_bytes() == m.value.as_bytes())
+    });
+    let cookie = canary
+        .cookie
+        .as_ref()
+        .is_some_and(|m| balancer::cookie(req, &m.name).is_some_and(|v| v == m.value));
+    header || cookie
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(percent: f64) -> Route {
+        toml::from_str(&format!(
+            r#"
+                name = "api"
+                hosts = ["example.com"]
+
+                [[upstreams]]
+                url = "http://stable"
+
+                [canary]
+                percent = {percent}
+                header = {{ name = "x-canary", value = "1" }}
+                cookie = {{ name = "beta", value = "yes" }}
+
+                [[canary.upstreams]]
+                url = "http://canary"
+            "#
+        ))
+        .unwrap()
+    }
+
+    fn request(header: Option<(&str, &str)>) -> Request<()> {
+        let mut req = Request::builder();
+        if let Some((name, value)) = header {
+            req = req.header(name, value);
+        }
+        req.body(()).unwrap()
+    }
+
+    #[test]
+    fn test_header_and_cookie_force_canary() {
+        let route = route(0.0);
+        let manager = CanaryManager::new();
+        manager.configure(std::slice::from_ref(&route));
+
+        assert_eq!(manager.choose(&route, &request(None)), Group::Primary);
+        let forced = [("x-canary", "1"), ("cookie", "a=b; beta=yes")];
+        for header in forced {
+            assert_eq!(
+                manager.choose(&route, &request(Some(header))),
+                Group::Canary,
+                "{header:?}"
+            );
+        }
+        assert_eq!(
+            manager.choose(&route, &request(Some(("x-canary", "0")))),
+            Group::Primary
+        );
+
+        let stats = &manager.stats()[0];
+        assert_eq!((stats.canary_requests, stats.primary_requests), (2, 2));
+        assert_eq!(Group::Canary.upstreams(&route)[0].url, "http://canary");
+    }
+
+    #[test]
+    fn test_percent_adjusts_live() {
+        let route = route(100.0);
+        let manager = CanaryManager::new();
+        manager.configure(std::slice::from_ref(&route));
+        assert_eq!(manager.choose(&route, &request(None)), Group::Canary);
+
+        assert!(manager.set_percent("api", 0.0));
+        assert!(!manager.set_percent("web", 50.0));
+        for _ in 0..50 {
+            assert_eq!(manager.choose(&route, &request(None)), Group::Primary);
+        }
+
+        manager.set_percent("api", 150.0);
+        assert_eq!(manager.stats()[0].percent, 100.0);
+    }
+}
@@ -0,0 +1,386 @@
+use crate::config::{Route, Upstream};
+use crate::retry::Failure;
+use hyper::Response;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Requests flow normally.
+    #[default]
+    Closed,
+    /// The upstream is skipped until its cooldown ends.
+    Open,
+    /// A few trial requests decide whether to close or reopen.
+    HalfOpen,
+}
+
+impl std::fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CircuitState::Closed => write!(f, "closed"),
+            CircuitState::Open => write!(f, "open"),
+            CircuitState::HalfOpen => write!(f, "half-open"),
+        }
+    }
+}
+
+/// Whether an upstream handled `response` well: `None` when the failure
+/// was not its doing, such as the client's body breaking.
+pub fn outcome<B>(response: &Response<B>) -> Option<bool> {
+    match response.extensions().get::<Failure>() {
+        Some(Failure::Final) => None,
+        Some(_) => Some(false),
+        None => Some(!response.status().is_server_error()),
+    }
+}
+
+/// Requests and failures seen in one second of the window.
+struct Bucket {
+    second: u64,
+    requests: u32,
+    failures: u32,
+}
+
+struct Breaker {
+    url: String,
+    fail_threshold: u32,
+    error_rate_threshold: f64,
+    window_secs: u64,
+    cooldown: Duration,
+    half_open_requests: u32,
+    state: CircuitState,
+    window: VecDeque<Bucket>,
+    epoch: Instant,
+    opened_at: Instant,
+    /// Trial requests admitted while half-open and not yet finished.
+    trials: u32,
+    trial_successes: u32,
+}
+
+impl Breaker {
+    fn new(upstream: &Upstream) -> Self {
+        let now = Instant::now();
+        Self {
+            url: upstream.url.clone(),
+            fail_threshold: upstream.fail_threshold,
+            error_rate_threshold: upstream.error_rate_threshold,
+            window_secs: upstream.window_secs.max(1),
+            cooldown: Duration::from_secs(upstream.cooldown_secs),
+            half_open_requests: upstream.half_open_requests.max(1),
+            state: CircuitState::Closed,
+            window: VecDeque::new(),
+            epoch: now,
+            opened_at: now,
+            trials: 0,
+            trial_successes: 0,
+        }
+    }
+
+    fn transition(&mut self, to: CircuitState, now: Instant) {
+        if to == CircuitState::Open && self.state == CircuitState::Closed {
+            let (requests, failures) = self.totals(now);
+            warn!(
+                "Circuit for {} closed -> open ({}/{} requests failed)",
+                self.url, failures, requests
+            );
+        } else if to == CircuitState::Open {
+            warn!(
+                "Circuit for {} {} -> open (trial request failed)",
+                self.url, self.state
+            );
+        } else {
+            info!("Circuit for {} {} -> {}", self.url, self.state, to);
+        }
+        if to == CircuitState::Open {
+            self.opened_at = now;
+        }
+        self.state = to;
+        self.window.clear();
+        self.trials = 0;
+        self.trial_successes = 0;
+    }
+
+    fn second(&self, now: Instant) -> u64 {
+        now.duration_since(self.epoch).as_secs()
+    }
+
+    fn totals(&mut self, now: Instant) -> (u32, u32) {
+        let oldest = self.second(now).saturating_sub(self.window_secs - 1);
+        while self.window.front().is_some_and(|b| b.second < oldest) {
+            self.window.pop_front();
+        }
+        self.window.iter().fold((0, 0), |(requests, failures), b| {
+            (requests + b.requests, failures + b.failures)
+        })
+    }
+
+    fn allow(&mut self, now: Instant) -> bool {
+        match self.state {
+            CircuitState::Closed => true,
+            CircuitState::Open if now.duration_since(self.opened_at) >= self.cooldown => {
+                self.transition(CircuitState::HalfOpen, now);
+                self.trials = 1;
+                true
+            }
+            CircuitState::Open => false,
+            CircuitState::HalfOpen if self.trials < self.half_open_requests => {
+                self.trials += 1;
+                true
+            }
+            CircuitState::HalfOpen => false,
+        }
+    }
+
+    fn record(&mut self, success: bool, now: Instant) {
+        match self.state {
+            CircuitState::Closed => {
+                let second = self.second(now);
+                if self.window.back().is_none_or(|b| b.second != second) {
+                    self.window.push_back(Bucket {
+                        second,
+                        requests: 0,
+                        failures: 0,
+                    });
+                }
+                let bucket = self.window.back_mut().expect("bucket just pushed");
+                bucket.requests += 1;
+                bucket.failures += u32::from(!success);
+
+                let (requests, failures) = self.totals(now);
+                if !success
+                    && failures >= self.fail_threshold
+                    && failures as f64 >= self.error_rate_threshold * requests as f64
+                {
+                    self.transition(CircuitState::Open, now);
+                }
+            }
+            CircuitState::HalfOpen if !success => self.transition(CircuitState::Open, now),
+            CircuitState::HalfOpen => {
+                self.trials = self.trials.saturating_sub(1);
+                self.trial_successes += 1;
+                if self.trial_successes >= self.half_open_requests {
+                    self.transition(CircuitState::Closed, now);
+                }
+            }
+            // Results of requests admitted before the circuit opened.
+            CircuitState::Open => {}
+        }
+    }
+
+    fn cancel(&mut self) {
+        if self.state == CircuitState::HalfOpen {
+            self.trials = self.trials.saturating_sub(1);
+        }
+    }
+}
+
+/// State of one upstream's circuit for `UpstreamStatus`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CircuitSnapshot {
+    pub state: CircuitState,
+    pub requests: u32,
+    pub failures: u32,
+}
+
+/// A circuit breaker per upstream URL.
+pub struct CircuitBreakers {
+    breakers: Mutex<HashMap<String, Breaker>>,
+}
+
+impl CircuitBreakers {
+    pub fn new() -> Self {
+        Self {
+            breakers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Upstreams listed by several routes share the settings of the first.
+    pub fn configure(&self, routes: &[Route]) {
+        let mut breakers = HashMap::new();
+        for upstream in routes.iter().flat_map(Route::all_upstreams) {
+            breakers
+                .entry(upstream.url.clone())
+                .or_insert_with(|| Breaker::new(upstream));
+        }
+        *self.breakers.lock() = breakers;
+    }
+
+    /// Admits a request to `url` if its circuit lets one through now. The
+    /// admission is given back on drop unless its outcome is recorded.
+    pub fn allow(&self, url: &str) -> Option<Admission<'_>> {
+        let allowed = self
+            .breakers
+            .lock()
+            .get_mut(url)
+            .is_none_or(|breaker| breaker.allow(Instant::now()));
+        allowed.then(|| Admission {
+            breakers: self,
+            url: url.to_string(),
+            settled: false,
+        })
+    }
+
+    fn record(&self, url: &str, success: bool) {
+        if let Some(breaker) = self.breakers.lock().get_mut(url) {
+            breaker.record(success, Instant::now());
+        }
+    }
+
+    fn cancel(&self, url: &str) {
+        if let Some(breaker) = self.breakers.lock().get_mut(url) {
+            breaker.cancel();
+        }
+    }
+
+    pub fn snapshot(&self, url: &str) -> Option<CircuitSnapshot> {
+        let mut breakers = self.breakers.lock();
+        let breaker = breakers.get_mut(url)?;
+        let (requests, failures) = breaker.totals(Instant::now());
+        Some(CircuitSnapshot {
+            state: breaker.state,
+            requests,
+            failures,
+        })
+    }
+}
+
+/// A request a circuit let through. Dropping it without `record`, say
+/// because the client went away first, frees its half-open trial slot.
+pub struct Admission<'a> {
+    breakers: &'a CircuitBreakers,
+    url: String,
+    settled: bool,
+}
+
+impl Admission<'_> {
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn record(mut self, success: bool) {
+        self.settled = true;
+        self.breakers.record(&self.url, success);
+    }
+}
+
+impl Drop for Admission<'_> {
+    fn drop(&mut self) {
+        if !self.settled {
+            self.breakers.cancel(&self.url);
+        }
+    }
+}
+
+impl Default for CircuitBreakers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breakers() -> CircuitBreakers {
+        let route: Route = toml::from_str(
+            r#"
+                name = "api"
+                hosts = ["example.com"]
+                path_prefix = "/"
+
+                [[upstreams]]
+                url = "http://a"
+                fail_threshold = 2
+                cooldown_secs = 5
+                window_secs = 10
+                half_open_requests = 2
+            "#,
+        )
+        .unwrap();
+        let breakers = CircuitBreakers::new();
+        breakers.configure(&[route]);
+        breakers
+    }
+
+    fn state(breakers: &CircuitBreakers) -> CircuitState {
+        breakers.snapshot("http://a").unwrap().state
+    }
+
+    fn send(breakers: &CircuitBreakers, success: bool) {
+        breakers.allow("http://a").unwrap().record(success);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_opens_on_error_rate_and_recovers() {
+        let breakers = breakers();
+        for _ in 0..2 {
+            send(&breakers, true);
+        }
+        // One failure is under `fail_threshold`; two of four is the 50% rate.
+        send(&breakers, false);
+        assert_eq!(state(&breakers), CircuitState::Closed);
+        send(&breakers, false);
+        assert_eq!(state(&breakers), CircuitState::Open);
+        assert!(breakers.allow("http://a").is_none());
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        let first = breakers.allow("http://a").unwrap();
+        assert_eq!(state(&breakers), CircuitState::HalfOpen);
+        let second = breakers.allow("http://a").unwrap();
+        assert!(
+            breakers.allow("http://a").is_none(),
+            "only two trial requests"
+        );
+
+        first.record(true);
+        assert_eq!(state(&breakers), CircuitState::HalfOpen);
+        second.record(true);
+        assert_eq!(state(&breakers), CircuitState::Closed);
+        assert!(breakers.allow("http://a").is_some());
+        assert!(breakers.allow("http://unknown").is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_trial_reopens_and_old_failures_expire() {
+        let breakers = breakers();
+        send(&breakers, false);
+        tokio::time::advance(Duration::from_secs(10)).await;
+        send(&breakers, false);
+        assert_eq!(state(&breakers), CircuitState::Closed);
+        send(&breakers, false);
+        assert_eq!(state(&breakers), CircuitState::Open);
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        drop(breakers.allow("http://a").unwrap());
+        send(&breakers, false);
+        assert_eq!(state(&breakers), CircuitState::Open);
+        assert!(breakers.allow("http://a").is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dropped_trial_is_given_back() {
+        let breakers = breakers();
+        for _ in 0..2 {
+            send(&breakers, false);
+        }
+        tokio::time::advance(Duration::from_secs(5)).await;
+
+        let trials = [
+            breakers.allow("http://a").unwrap(),
+            breakers.allow("http://a").unwrap(),
+        ];
+        assert!(breakers.allow("http://a").is_none());
+        // As when the client disconnects before the upstream answers.
+        drop(trials);
+        assert_eq!(state(&breakers), CircuitState::HalfOpen);
+        send(&breakers, true);
+        send(&breakers, true);
+        assert_eq!(state(&breakers), CircuitState::Closed);
+    }
+}
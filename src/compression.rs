@@ -0,0 +1,429 @@
+use crate::config::{CompressionConfig, Encoding};
+use bytes::Bytes;
+use http_body_util::{BodyExt, combinators::BoxBody};
+use hyper::body::{Body, Frame};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Method, Response, StatusCode};
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+type BoxedBody = BoxBody<Bytes, GenericError>;
+
+const GZIP_LEVEL: u32 = 6;
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+const ZSTD_LEVEL: i32 = 3;
+const BUFFER_SIZE: usize = 4096;
+
+/// Encodes or decodes a body a chunk at a time, so responses keep
+/// streaming.
+enum Coder {
+    GzipEncoder(flate2::write::GzEncoder<Vec<u8>>),
+    BrotliEncoder(Box<brotli::CompressorWriter<Vec<u8>>>),
+    ZstdEncoder(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    GzipDecoder(flate2::write::GzDecoder<Vec<u8>>),
+    BrotliDecoder(Box<brotli::DecompressorWriter<Vec<u8>>>),
+    ZstdDecoder(zstd::stream::write::Decoder<'static, Vec<u8>>),
+}
+
+impl Coder {
+    fn encoder(encoding: Encoding) -> io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Gzip => Coder::GzipEncoder(flate2::write::GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::new(GZIP_LEVEL),
+            )),
+            Encoding::Brotli => Coder::BrotliEncoder(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                BUFFER_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+            Encoding::Zstd => {
+                Coder::ZstdEncoder(zstd::stream::write::Encoder::new(Vec::new(), ZSTD_LEVEL)?)
+            }
+        })
+    }
+
+    fn decoder(encoding: Encoding) -> io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Gzip => Coder::GzipDecoder(flate2::write::GzDecoder::new(Vec::new())),
+            Encoding::Brotli => Coder::BrotliDecoder(Box::new(brotli::DecompressorWriter::new(
+                Vec::new(),
+                BUFFER_SIZE,
+            ))),
+            Encoding::Zstd => Coder::ZstdDecoder(zstd::stream::write::Decoder::new(Vec::new())?),
+        })
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Coder::GzipEncoder(w) => w,
+            Coder::BrotliEncoder(w) => w.as_mut(),
+            Coder::ZstdEncoder(w) => w,
+            Coder::GzipDecoder(w) => w,
+            Coder::BrotliDecoder(w) => w.as_mut(),
+            Coder::ZstdDecoder(w) => w,
+        }
+    }
+
+    fn output(&mut self) -> &mut Vec<u8> {
+        match self {
+            Coder::GzipEncoder(w) => w.get_mut(),
+            Coder::BrotliEncoder(w) => w.get_mut(),
+            Coder::ZstdEncoder(w) => w.get_mut(),
+            Coder::GzipDecoder(w) => w.get_mut(),
+            Coder::BrotliDecoder(w) => w.get_mut(),
+            Coder::ZstdDecoder(w) => w.get_mut(),
+        }
+    }
+
+    /// Feeds in `data` and returns whatever output it produced. Flushing
+    /// each chunk costs a little ratio but keeps event streams live.
+    fn write(&mut self, data: &[u8]) -> io::Result<Bytes> {
+        let writer = self.writer();
+        writer.write_all(data)?;
+        writer.flush()?;
+        Ok(Bytes::from(std::mem::take(self.output())))
+    }
+
+    fn finish(self) -> io::Result<Bytes> {
+        let output = match self {
+            Coder::GzipEncoder(w) => w.finish()?,
+            Coder::BrotliEncoder(w) => w.into_inner(),
+            Coder::ZstdEncoder(w) => w.finish()?,
+            Coder::GzipDecoder(w) => w.finish()?,
+            Coder::BrotliDecoder(mut w) => {
+                w.close()?;
+                std::mem::take(w.get_mut())
+            }
+            Coder::ZstdDecoder(mut w) => {
+                w.flush()?;
+                w.into_inner()
+            }
+        };
+        Ok(Bytes::from(output))
+    }
+}
+
+/// Runs a body through a [`Coder`].
+struct CodingBody<B> {
+    inner: B,
+    coder: Option<Coder>,
+    trailers: Option<HeaderMap>,
+}
+
+impl<B> Body for CodingBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: Into<GenericError>,
+{
+    type Data = Bytes;
+    type Error = GenericError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, GenericError>>> {
+        let this = self.get_mut();
+        loop {
+            let Some(coder) = this.coder.as_mut() else {
+                return Poll::Ready(this.trailers.take().map(|t| Ok(Frame::trailers(t))));
+            };
+            match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => match coder.write(&data) {
+                        Ok(output) if output.is_empty() => continue,
+                        Ok(output) => return Poll::Ready(Some(Ok(Frame::data(output)))),
+                        Err(e) => return Poll::Ready(Some(Err(e.into()))),
+                    },
+                    Err(frame) => this.trailers = frame.into_trailers().ok(),
+                },
+                Some(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                None => {}
+            }
+            // The body ended, or only trailers are left.
+            match this.coder.take().map(Coder::finish) {
+                Some(Ok(output)) if !output.is_empty() => {
+                    return Poll::Ready(Some(Ok(Frame::data(output))));
+                }
+                Some(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                _ => {}
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.coder.is_none() && self.trailers.is_none()
+    }
+}
+
+fn recode(response: Response<BoxedBody>, coder: Coder) -> Response<BoxedBody> {
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    // The bytes differ now, so a strong validator no longer holds.
+    if let Some(etag) = parts.headers.get(header::ETAG)
+        && !etag.as_bytes().starts_with(b"W/")
+    {
+        let mut weak = b"W/".to_vec();
+        weak.extend_from_slice(etag.as_bytes());
+        if let Ok(weak) = HeaderValue::from_bytes(&weak) {
+            parts.headers.insert(header::ETAG, weak);
+        }
+    }
+    let body = CodingBody {
+        inner: body,
+        coder: Some(coder),
+        trailers: None,
+    };
+    Response::from_parts(parts, body.boxed())
+}
+
+/// The configured encoding the client rates highest in `Accept-Encoding`;
+/// ties go to the one listed first in `offered`.
+pub fn negotiate(accept_encoding: Option<&HeaderValue>, offered: &[Encoding]) -> Option<Encoding> {
+    let accept = accept_encoding?.to_str().ok()?;
+    let mut weights = Vec::new();
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let name = params
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        weights.push((name, q));
+    }
+    let weight = |encoding: Encoding| {
+        let named = weights
+            .iter()
+            .find(|(name, _)| Encoding::from_token(name) == Some(encoding));
+        let any = weights.iter().find(|(name, _)| name == "*");
+        named.or(any).map_or(0.0, |(_, q)| *q)
+    };
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in offered {
+        let q = weight(encoding);
+        if q > 0.0 && best.is_none_or(|(_, top)| q > top) {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+fn compressible(content_type: &str, patterns: &[String]) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    patterns.iter().any(|pattern| {
+        let pattern = pattern.to_ascii_lowercase();
+        match pattern.strip_suffix("/*") {
+            Some(top) => essence.split('/').next() == Some(top),
+            None => essence == pattern,
+        }
+    })
+}
+
+/// Compresses `response` to a `method` request when the client accepts one
+/// of the configured encodings and the response is worth it.
+pub fn compress(
+    config: &CompressionConfig,
+    method: &Method,
+    accept_encoding: Option<&HeaderValue>,
+    mut response: Response<BoxedBody>,
+) -> Response<BoxedBody> {
+    let status = response.status();
+    let headers = response.headers();
+    let skip = config.encodings.is_empty()
+        || *method == Method::HEAD
+        || status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::PARTIAL_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+        || headers
+            .get(header::CONTENT_ENCODING)
+            .is_some_and(|v| v != "identity")
+        || headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .any(|v| v.to_str().is_ok_and(|v| v.contains("no-transform")))
+        || !headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| compressible(v, &config.content_types))
+        || response
+            .body()
+            .size_hint()
+            .exact()
+            .is_some_and(|len| len < config.min_bytes);
+    if skip {
+        return response;
+    }
+
+    // Whatever is chosen, the response depends on Accept-Encoding.
+    let varies = headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case("accept-encoding") || v.trim() == "*");
+    if !varies {
+        response
+            .headers_mut()
+            .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+    let Some(encoding) = negotiate(accept_encoding, &config.encodings) else {
+        return response;
+    };
+    let coder = match Coder::encoder(encoding) {
+        Ok(coder) => coder,
+        Err(e) => {
+            tracing::warn!("Failed to start {} encoder: {}", encoding.token(), e);
+            return response;
+        }
+    };
+    let mut response = recode(response, coder);
+    response.headers_mut().insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.token()),
+    );
+    response
+}
+
+/// Decodes a response the upstream compressed with one known encoding.
+pub fn decompress(response: Response<BoxedBody>) -> Response<BoxedBody> {
+    let Some(encoding) = response
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .and_then(Encoding::from_token)
+    else {
+        return response;
+    };
+    let coder = match Coder::decoder(encoding) {
+        Ok(coder) => coder,
+        Err(e) => {
+            tracing::warn!("Failed to start {} decoder: {}", encoding.token(), e);
+            return response;
+        }
+    };
+    let mut response = recode(response, coder);
+    response.headers_mut().remove(header::CONTENT_ENCODING);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Full;
+
+    fn config() -> CompressionConfig {
+        toml::from_str("min_bytes = 16").unwrap()
+    }
+
+    fn response(content_type: &str, body: &str) -> Response<BoxedBody> {
+        let body = Full::new(Bytes::from(body.to_string()))
+            .map_err(|never| match never {})
+            .boxed();
+        Response::builder()
+            .header("content-type", content_type)
+            .header("content-length", body.size_hint().exact().unwrap())
+            .header("etag", "\"v1\"")
+            .body(body)
+            .unwrap()
+    }
+
+    async fn read(response: Response<BoxedBody>) -> Bytes {
+        response.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let offered = [Encoding::Zstd, Encoding::Brotli, Encoding::Gzip];
+        let cases = [
+            ("gzip, deflate, br, zstd", Some(Encoding::Zstd)),
+            ("gzip;q=1.0, br;q=0.5", Some(Encoding::Gzip)),
+            ("br;q=0.5, *;q=0.1", Some(Encoding::Brotli)),
+            ("*", Some(Encoding::Zstd)),
+            ("zstd;q=0, *", Some(Encoding::Brotli)),
+            ("identity", None),
+            ("deflate", None),
+        ];
+        for (accept, expected) in cases {
+            let value = HeaderValue::from_static(accept);
+            assert_eq!(negotiate(Some(&value), &offered), expected, "{accept}");
+        }
+        assert_eq!(negotiate(None, &offered), None);
+    }
+
+    #[test]
+    fn test_compressible() {
+        let patterns = config().content_types;
+        assert!(compressible("text/html; charset=utf-8", &patterns));
+        assert!(compressible("Application/JSON", &patterns));
+        assert!(!compressible("image/png", &patterns));
+        assert!(!compressible("textual/plain", &patterns));
+    }
+
+    #[tokio::test]
+    async fn test_round_trip_every_encoding() {
+        let text = "hello compression ".repeat(100);
+        for encoding in [Encoding::Gzip, Encoding::Brotli, Encoding::Zstd] {
+            let mut config = config();
+            config.encodings = vec![encoding];
+            let accept = HeaderValue::from_static("gzip, br, zstd");
+            let compressed = compress(
+                &config,
+                &Method::GET,
+                Some(&accept),
+                response("text/plain", &text),
+            );
+            let headers = compressed.headers().clone();
+            assert_eq!(headers["content-encoding"], encoding.token());
+            assert_eq!(headers["vary"], "accept-encoding");
+            assert_eq!(headers["etag"], "W/\"v1\"");
+            assert!(!headers.contains_key("content-length"));
+
+            let body = read(compressed).await;
+            assert!(body.len() < text.len(), "{encoding:?}");
+            let mut encoded = response("text/plain", "");
+            *encoded.headers_mut() = headers;
+            *encoded.body_mut() = Full::new(body).map_err(|never| match never {}).boxed();
+            let decoded = decompress(encoded);
+            assert!(!decoded.headers().contains_key("content-encoding"));
+            assert_eq!(read(decoded).await, text.as_bytes(), "{encoding:?}");
+        }
+    }
+
+    #[test]
+    fn test_skips_what_is_not_worth_it() {
+        let accept = HeaderValue::from_static("gzip");
+        let long = "x".repeat(64);
+        let skipped = |response: Response<BoxedBody>, method: &Method| {
+            let response = compress(&config(), method, Some(&accept), response);
+            response.headers().get("content-encoding") != Some(&accept)
+        };
+        assert!(skipped(response("text/plain", "short"), &Method::GET));
+        assert!(skipped(response("image/png", &long), &Method::GET));
+        assert!(skipped(response("text/plain", &long), &Method::HEAD));
+        let mut encoded = response("text/plain", &long);
+        encoded
+            .headers_mut()
+            .insert("content-encoding", HeaderValue::from_static("br"));
+        assert!(skipped(encoded, &Method::GET));
+        let mut no_transform = response("text/plain", &long);
+        no_transform
+            .headers_mut()
+            .insert("cache-control", HeaderValue::from_static("no-transform"));
+        assert!(skipped(no_transform, &Method::GET));
+        assert!(!skipped(response("text/plain", &long), &Method::GET));
+    }
+}
@@ -0,0 +1,313 @@
+use crate::config::{ConcurrencyLimit, Route};
+use hyper::body::{Body, Frame, SizeHint};
+use parking_lot::RwLock;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Why a request was turned away by a [`Limiter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejected {
+    /// Every slot and queue position was taken.
+    QueueFull,
+    /// The request queued but no slot freed up in time.
+    Timeout,
+}
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rejected::QueueFull => write!(f, "queue full"),
+            Rejected::Timeout => write!(f, "timed out waiting in queue"),
+        }
+    }
+}
+
+/// In-flight cap with a bounded wait queue.
+pub struct Limiter {
+    limit: ConcurrencyLimit,
+    permits: Arc<Semaphore>,
+    queued: AtomicUsize,
+    shed: AtomicU64,
+    timed_out: AtomicU64,
+}
+
+/// Takes a queue position back when the waiting request finishes or is
+/// dropped by its connection.
+struct QueueSlot<'a>(&'a AtomicUsize);
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Limiter {
+    pub fn new(limit: ConcurrencyLimit) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(limit.max_in_flight)),
+            limit,
+            queued: AtomicUsize::new(0),
+            shed: AtomicU64::new(0),
+            timed_out: AtomicU64::new(0),
+        }
+    }
+
+    /// Waits for a slot; the request counts as in flight until the permit
+    /// is dropped.
+    pub async fn acquire(&self) -> Result<OwnedSemaphorePermit, Rejected> {
+        if let Ok(permit) = Arc::clone(&self.permits).try_acquire_owned() {
+            return Ok(permit);
+        }
+
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= self.limit.queue_size {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            self.shed.fetch_add(1, Ordering::Relaxed);
+            return Err(Rejected::QueueFull);
+        }
+        let _slot = QueueSlot(&self.queued);
+
+        let wait = Duration::from_millis(self.limit.queue_timeout_ms);
+        match tokio::time::timeout(wait, Arc::clone(&self.permits).acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(permit),
+            // The semaphore is never closed, so only the timeout lands here.
+            _ => {
+                self.timed_out.fetch_add(1, Ordering::Relaxed);
+                Err(Rejected::Timeout)
+            }
+        }
+    }
+
+    fn stats(&self, scope: &str, name: &str) -> LimiterStats {
+        LimiterStats {
+            scope: scope.to_string(),
+            name: name.to_string(),
+            max_in_flight: self.limit.max_in_flight,
+            in_flight: self.limit.max_in_flight - self.permits.available_permits(),
+            queued: self.queued.load(Ordering::SeqCst),
+            shed: self.shed.load(Ordering::Relaxed),
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Snapshot of one limiter for `/metrics`.
+#[derive(Debug, Clone, Serialize)]
+pub struct LimiterStats {
+    /// `route` or `upstream`.
+    pub scope: String,
+    pub name: String,
+    pub max_in_flight: usize,
+    pub in_flight: usize,
+    pub queued: usize,
+    pub shed: u64,
+    pub timed_out: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionStats {
+    pub open: usize,
+    pub max: Option<usize>,
+}
+
+/// Route and upstream limiters plus the global connection cap.
+pub struct ConcurrencyManager {
+    routes: RwLock<BTreeMap<String, Arc<Limiter>>>,
+    upstreams: RwLock<BTreeMap<String, Arc<Limiter>>>,
+    connections: RwLock<Option<(usize, Arc<Semaphore>)>>,
+    open: Arc<AtomicUsize>,
+}
+
+impl ConcurrencyManager {
+    pub fn new() -> Self {
+        Self {
+            routes: RwLock::new(BTreeMap::new()),
+            upstreams: RwLock::new(BTreeMap::new()),
+            connections: RwLock::new(None),
+            open: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn configure(&self, routes: &[Route], max_connections: Option<usize>) {
+        *self.routes.write() = routes
+            .iter()
+            .filter_map(|route| {
+                let limit = route.concurrency.clone()?;
+                Some((route.name.clone(), Arc::new(Limiter::new(limit))))
+            })
+            .collect();
+
+        let mut upstreams = BTreeMap::new();
+        for upstream in routes.iter().flat_map(Route::all_upstreams) {
+            if let Some(limit) = &upstream.concurrency {
+                upstreams
+                    .entry(upstream.url.clone())
+                    .or_insert_with(|| Arc::new(Limiter::new(limit.clone())));
+            }
+        }
+        *self.upstreams.write() = upstreams;
+
+        *self.connections.write() = max_connections.map(|max| (max, Arc::new(Semaphore::new(max))));
+    }
+
+    pub fn route(&self, name: &str) -> Option<Arc<Limiter>> {
+        self.routes.read().get(name).cloned()
+    }
+
+    pub fn upstream(&self, url: &str) -> Option<Arc<Limiter>> {
+        self.upstreams.read().get(url).cloned()
+    }
+
+    /// Waits until another client connection may be accepted. Hold the
+    /// returned guard for the life of the connection.
+    pub async fn acquire_connection(&self) -> ConnectionGuard {
+        let semaphore = self
+            .connections
+            .read()
+            .as_ref()
+            .map(|(_, semaphore)| Arc::clone(semaphore));
+        let permit = match semaphore {
+            // The semaphore is never closed.
+            Some(semaphore) => semaphore.acquire_owned().await.ok(),
+            None => None,
+        };
+        self.open.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard {
+            _permit: permit,
+            open: Arc::clone(&self.open),
+        }
+    }
+
+    pub fn stats(&self) -> Vec<LimiterStats> {
+        let routes = self.routes.read();
+        let upstreams = self.upstreams.read();
+        routes
+            .iter()
+            .map(|(name, limiter)| limiter.stats("route", name))
+            .chain(
+                upstreams
+                    .iter()
+                    .map(|(url, limiter)| limiter.stats("upstream", url)),
+            )
+            .collect()
+    }
+
+    pub fn connection_stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            open: self.open.load(Ordering::SeqCst),
+            max: self.connections.read().as_ref().map(|(max, _)| *max),
+        }
+    }
+}
+
+impl Default for ConcurrencyManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An accepted client connection, counted until dropped.
+pub struct ConnectionGuard {
+    _permit: Option<OwnedSemaphorePermit>,
+    open: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Response body that keeps its request's limiter slots (or any other
+/// guard) taken until the body has been sent or dropped.
+pub struct PermitBody<B, P> {
+    inner: B,
+    _permits: P,
+}
+
+impl<B, P> PermitBody<B, P> {
+    pub fn new(inner: B, permits: P) -> Self {
+        Self {
+            inner,
+            _permits: permits,
+        }
+    }
+}
+
+impl<B, P> Body for PermitBody<B, P>
+where
+    P: Unpin,
+    B: Body + Unpin,
+{
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<B::Data>, B::Error>>> {
+        Pin::new(&mut self.get_mut().inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(max_in_flight: usize, queue_size: usize) -> Arc<Limiter> {
+        Arc::new(Limiter::new(ConcurrencyLimit {
+            max_in_flight,
+            queue_size,
+            queue_timeout_ms: 100,
+        }))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_queue_sheds_and_times_out() {
+        let limiter = limiter(1, 1);
+        let held = limiter.acquire().await.unwrap();
+
+        let waiting = tokio::spawn({
+            let limiter = Arc::clone(&limiter);
+            async move { limiter.acquire().await.map(drop) }
+        });
+        tokio::task::yield_now().await;
+        assert_eq!(limiter.stats("route", "api").queued, 1);
+        assert_eq!(limiter.acquire().await.unwrap_err(), Rejected::QueueFull);
+
+        assert_eq!(waiting.await.unwrap(), Err(Rejected::Timeout));
+        let stats = limiter.stats("route", "api");
+        assert_eq!((stats.in_flight, stats.queued), (1, 0));
+        assert_eq!((stats.shed, stats.timed_out), (1, 1));
+        drop(held);
+        assert_eq!(limiter.stats("route", "api").in_flight, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_queued_request_gets_released_slot() {
+        let limiter = limiter(1, 4);
+        let held = limiter.acquire().await.unwrap();
+
+        let waiting = tokio::spawn({
+            let limiter = Arc::clone(&limiter);
+            async move { limiter.acquire().await.is_ok() }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(held);
+        assert!(waiting.await.unwrap());
+        assert_eq!(limiter.stats("route", "api").timed_out, 0);
+    }
+}
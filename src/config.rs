@@ -1,5 +1,7 @@
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
 use std::path::Path;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -7,6 +9,39 @@ pub struct Config {
     pub listen: String,
     pub control: ControlConfig,
     pub routes: Vec<Route>,
+    /// Peers whose `X-Forwarded-*`/`Forwarded` headers are extended rather
+    /// than replaced.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    /// Name added to `Via`; requests already carrying it are rejected as loops.
+    #[serde(default = "default_via_name")]
+    pub via_name: String,
+    /// Record WebSocket frames on the handshake's request log entry.
+    #[serde(default)]
+    pub capture_websocket: bool,
+    #[serde(default)]
+    pub breakpoints: BreakpointConfig,
+    /// Map Local rules checked before route lookup.
+    #[serde(default)]
+    pub map_local: Vec<MapLocalRule>,
+    /// Map Remote rules checked before route lookup.
+    #[serde(default)]
+    pub map_remote: Vec<MapRemoteRule>,
+    #[serde(default)]
+    pub throttle: ThrottleConfig,
+    /// Where the interception CA certificate and key are kept.
+    #[serde(default = "default_ca_dir")]
+    pub ca_dir: String,
+    /// Optional second listener accepting absolute-form and CONNECT requests.
+    #[serde(default)]
+    pub forward_proxy: Option<ForwardProxyConfig>,
+    /// Open client connections allowed across both listeners; further
+    /// clients wait in the listen backlog until one closes.
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// Defaults for routes that leave a timeout unset.
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -18,12 +53,171 @@ pub struct ControlConfig {
 pub struct Route {
     pub name: String,
     pub hosts: Vec<String>,
+    #[serde(default)]
     pub path_prefix: String,
+    /// Regex the path must also match.
+    #[serde(default)]
+    pub path_regex: Option<String>,
     #[serde(default)]
     pub strip_prefix: bool,
     #[serde(default)]
     pub rewrite_prefix: Option<String>,
+    /// Upstream path built from `path_regex` captures (`$1`, `${name}`),
+    /// used instead of `strip_prefix` and `rewrite_prefix`.
+    #[serde(default)]
+    pub rewrite_path: Option<String>,
+    #[serde(default, rename = "match")]
+    pub conditions: RouteConditions,
+    /// Routes with a higher priority are tried before more specific ones.
+    #[serde(default)]
+    pub priority: i32,
+    /// Answer every request on this route from disk instead of an upstream.
+    #[serde(default)]
+    pub map_local: Option<MapLocal>,
+    /// Forward the client's `Host` header; when false upstreams see their own.
+    #[serde(default = "default_true")]
+    pub preserve_host: bool,
+    #[serde(default)]
+    pub request_headers: HeaderRules,
+    #[serde(default)]
+    pub response_headers: HeaderRules,
+    /// Throttle profile applied to this route instead of the global one.
+    #[serde(default)]
+    pub throttle: Option<String>,
+    /// Failures injected into a share of this route's requests.
+    #[serde(default)]
+    pub faults: Vec<FaultRule>,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    pub concurrency: Option<ConcurrencyLimit>,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    #[serde(default)]
+    pub retry: Option<RetryPolicy>,
+    #[serde(default)]
+    pub mirror: Option<Mirror>,
+    #[serde(default)]
+    pub cache: Option<CacheConfig>,
+    #[serde(default)]
+    pub compression: Option<CompressionConfig>,
+    #[serde(default)]
+    pub balancer: Balancer,
+    /// What `consistent_hash` hashes to pick an upstream.
+    #[serde(default)]
+    pub hash_key: HashKey,
+    #[serde(default)]
+    pub sticky: Option<StickyConfig>,
     pub upstreams: Vec<Upstream>,
+    /// Second upstream group taking a share of the traffic.
+    #[serde(default)]
+    pub canary: Option<Canary>,
+}
+
+impl Route {
+    /// Upstreams of every group on the route.
+    pub fn all_upstreams(&self) -> impl Iterator<Item = &Upstream> {
+        let canary = self.canary.iter().flat_map(|canary| &canary.upstreams);
+        self.upstreams.iter().chain(canary)
+    }
+}
+
+/// Requests matching `header` or `cookie` always go to the canary upstreams;
+/// of the rest, `percent` are picked at random.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Canary {
+    /// Share of traffic from 0 to 100; the control API can change it live.
+    #[serde(default)]
+    pub percent: f64,
+    #[serde(default)]
+    pub header: Option<CanaryMatch>,
+    #[serde(default)]
+    pub cookie: Option<CanaryMatch>,
+    pub upstreams: Vec<Upstream>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CanaryMatch {
+    pub name: String,
+    pub value: String,
+}
+
+/// Further conditions a request must meet for its route to match. A list
+/// is met by any of its entries; every map entry must hold.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct RouteConditions {
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Header name to required value; `*` only requires the header.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Query parameter to required value; `*` only requires the parameter.
+    #[serde(default)]
+    pub query: BTreeMap<String, String>,
+    /// Networks the connecting client's address must fall in.
+    #[serde(default)]
+    pub client_cidrs: Vec<IpNet>,
+}
+
+/// How a route spreads requests over its upstreams.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Balancer {
+    /// Smooth weighted round-robin.
+    #[default]
+    RoundRobin,
+    /// Fewest requests in flight relative to weight.
+    LeastConn,
+    /// The less loaded of two upstreams picked at random.
+    RandomTwoChoices,
+    /// Lowest moving average of response latency, scaled by load.
+    EwmaLatency,
+    /// Consistent hashing on the client IP address.
+    IpHash,
+    /// Consistent hashing on `hash_key`.
+    ConsistentHash,
+}
+
+/// Requests without the header or cookie hash their client IP instead.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashKey {
+    /// The request path, without the query string.
+    #[default]
+    Path,
+    Header(String),
+    Cookie(String),
+}
+
+/// Session affinity: the first response names its upstream in a cookie, and
+/// requests carrying it go back there while that upstream's circuit allows.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct StickyConfig {
+    #[serde(default = "default_sticky_cookie")]
+    pub cookie: String,
+    /// Mixed into the cookie value so it cannot be worked out from the
+    /// upstream URLs.
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// Cookie lifetime; unset makes it a browser-session cookie.
+    #[serde(default)]
+    pub max_age_secs: Option<u64>,
+    #[serde(default)]
+    pub secure: bool,
+}
+
+/// Header edits applied in the order remove, rename, set, add. Values may
+/// use `{client_ip}`, `{route}` and `{request_id}` placeholders.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct HeaderRules {
+    #[serde(default)]
+    pub remove: Vec<String>,
+    #[serde(default)]
+    pub rename: BTreeMap<String, String>,
+    #[serde(default)]
+    pub set: BTreeMap<String, String>,
+    #[serde(default)]
+    pub add: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -31,10 +225,375 @@ pub struct Upstream {
     pub url: String,
     #[serde(default = "default_weight")]
     pub weight: u32,
+    /// Failures needed within `window_secs` before the circuit can open.
     #[serde(default = "default_fail_threshold")]
     pub fail_threshold: u32,
+    /// How long an open circuit refuses requests before trying again.
     #[serde(default = "default_cooldown_secs")]
     pub cooldown_secs: u64,
+    /// Share of requests (0.0-1.0) that must fail for the circuit to open.
+    #[serde(default = "default_error_rate_threshold")]
+    pub error_rate_threshold: f64,
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    /// Trial requests let through after the cooldown; all must succeed to
+    /// close the circuit again.
+    #[serde(default = "default_half_open_requests")]
+    pub half_open_requests: u32,
+    /// Shared by every route that lists this upstream URL.
+    #[serde(default)]
+    pub concurrency: Option<ConcurrencyLimit>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ForwardProxyConfig {
+    pub listen: String,
+    /// Host patterns (exact or `*.suffix`) that may be reached; empty allows all.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Host patterns that are always refused, checked before `allow`.
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Host patterns whose CONNECT tunnels are decrypted instead of relayed.
+    #[serde(default)]
+    pub ssl_proxying: Vec<String>,
+}
+
+/// Token bucket applied separately to each client, as identified by `key`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RateLimit {
+    /// Tokens added to each bucket per second.
+    pub requests_per_second: f64,
+    /// Bucket capacity: how many requests may arrive back to back.
+    pub burst: u32,
+    #[serde(default)]
+    pub key: RateLimitKey,
+}
+
+/// Caps requests in flight. Once `max_in_flight` is reached up to
+/// `queue_size` more wait for a slot, each for at most `queue_timeout_ms`;
+/// anything beyond that is shed with a 503.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ConcurrencyLimit {
+    pub max_in_flight: usize,
+    #[serde(default)]
+    pub queue_size: usize,
+    #[serde(default = "default_queue_timeout_ms")]
+    pub queue_timeout_ms: u64,
+}
+
+/// Timeouts in milliseconds; `0` disables one, and unset ones fall back to
+/// the global value and then the built-in default. `header_read_ms` only
+/// applies globally, since the route is not known until the headers are in.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct TimeoutConfig {
+    /// Time for a client to send the complete request head.
+    #[serde(default)]
+    pub header_read_ms: Option<u64>,
+    /// Longest gap allowed between chunks of the request body.
+    #[serde(default)]
+    pub body_idle_ms: Option<u64>,
+    /// Time to open a connection to the upstream.
+    #[serde(default)]
+    pub connect_ms: Option<u64>,
+    /// Time from sending the request to receiving the upstream's response head.
+    #[serde(default)]
+    pub response_header_ms: Option<u64>,
+    /// Deadline for the whole exchange, including streaming the response.
+    #[serde(default)]
+    pub request_ms: Option<u64>,
+}
+
+/// Resends failed requests to another upstream. Connect failures are
+/// retried for any method, since nothing reached the upstream; other failures
+/// only for idempotent methods. Either way the body must have been buffered.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first.
+    #[serde(default = "default_retry_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<RetryOn>,
+    /// Upstream response statuses that are retried.
+    #[serde(default = "default_retry_statuses")]
+    pub statuses: Vec<u16>,
+    /// First backoff; each retry doubles it up to `max_backoff_ms`, and the
+    /// actual wait is a random share of that.
+    #[serde(default = "default_retry_backoff_ms")]
+    pub backoff_ms: u64,
+    #[serde(default = "default_retry_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    /// Largest request body kept in memory for resending. Longer or
+    /// unsized bodies are never retried.
+    #[serde(default = "default_retry_buffer_bytes")]
+    pub buffer_body_bytes: u64,
+    /// Retries earned per request on the route, capping retries at this
+    /// share of traffic once the initial allowance is spent.
+    #[serde(default = "default_retry_budget_ratio")]
+    pub budget_ratio: f64,
+}
+
+/// Compresses responses on the fly for clients that accept it, and can
+/// decode compressed upstream responses so they show as text.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CompressionConfig {
+    /// Encodings offered, preferred first; empty turns compression off.
+    #[serde(default = "default_compression_encodings")]
+    pub encodings: Vec<Encoding>,
+    /// Content types compressed; `text/*` covers a whole top-level type.
+    #[serde(default = "default_compression_types")]
+    pub content_types: Vec<String>,
+    /// Responses known to be shorter are sent as they are.
+    #[serde(default = "default_compression_min_bytes")]
+    pub min_bytes: u64,
+    /// Decode gzip, br and zstd upstream responses, for breakpoints and
+    /// clients alike.
+    #[serde(default)]
+    pub decompress: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Encoding {
+    #[serde(rename = "gzip")]
+    Gzip,
+    #[serde(rename = "br")]
+    Brotli,
+    #[serde(rename = "zstd")]
+    Zstd,
+}
+
+impl Encoding {
+    /// The `Content-Encoding` token.
+    pub fn token(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_token(token: &str) -> Option<Self> {
+        match token.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "br" => Some(Encoding::Brotli),
+            "zstd" => Some(Encoding::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Copies a sample of a route's requests to a shadow upstream whose
+/// responses are thrown away.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Mirror {
+    pub url: String,
+    /// Share of requests copied, from 0 to 100.
+    #[serde(default = "default_mirror_percent")]
+    pub percent: f64,
+    /// Largest request body copied; longer or unsized bodies are not
+    /// mirrored. Also the most of each response body compared.
+    #[serde(default = "default_mirror_body_bytes")]
+    pub max_body_bytes: u64,
+    /// Record how the shadow response differed from the primary one.
+    #[serde(default)]
+    pub compare: bool,
+}
+
+/// Keeps upstream responses in memory as a shared HTTP cache would,
+/// following their `Cache-Control`, `Expires` and validators.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CacheConfig {
+    /// Memory for the route's cached responses; the least recently used
+    /// are dropped first.
+    #[serde(default = "default_cache_max_bytes")]
+    pub max_bytes: u64,
+    /// Largest response body stored.
+    #[serde(default = "default_cache_max_body_bytes")]
+    pub max_body_bytes: u64,
+    /// Freshness for responses that give no `max-age` or `Expires`.
+    #[serde(default)]
+    pub default_ttl_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// The upstream could not be reached.
+    ConnectFailure,
+    /// The connection failed after the request was sent.
+    Reset,
+    /// No response head within the response-header timeout.
+    Timeout,
+}
+
+/// What identifies a client for rate limiting. Requests without the header
+/// or query parameter fall back to their IP address.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    #[default]
+    ClientIp,
+    /// Value of the named header, e.g. `X-Api-Key`.
+    Header(String),
+    /// Value of the named query parameter, e.g. `api_key`.
+    Query(String),
+}
+
+/// Injects `fault` into `percentage` percent of the route's requests that
+/// match the optional method and path prefix.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FaultRule {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default = "default_fault_percentage")]
+    pub percentage: f64,
+    #[serde(default)]
+    pub method: Option<String>,
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+    #[serde(flatten)]
+    pub fault: Fault,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Fault {
+    /// Wait before forwarding the request as usual.
+    Delay { delay_ms: u64 },
+    /// Answer with `status` and `body` without contacting the upstream.
+    Status {
+        status: u16,
+        #[serde(default)]
+        body: String,
+    },
+    /// Close the client connection without a response.
+    Reset,
+    /// Abort the response after `after_bytes` bytes of body.
+    Truncate {
+        #[serde(default)]
+        after_bytes: u64,
+    },
+}
+
+impl std::fmt::Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fault::Delay { delay_ms } => write!(f, "delay {}ms", delay_ms),
+            Fault::Status { status, .. } => write!(f, "status {}", status),
+            Fault::Reset => write!(f, "reset"),
+            Fault::Truncate { after_bytes } => write!(f, "truncate after {} bytes", after_bytes),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ThrottleConfig {
+    /// Profile applied to all traffic at startup.
+    #[serde(default)]
+    pub active: Option<String>,
+    /// Custom profiles, added to (or replacing) the built-in ones by name.
+    #[serde(default)]
+    pub profiles: Vec<ThrottleProfile>,
+}
+
+/// Simulated link conditions. An unset rate leaves that direction unlimited.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ThrottleProfile {
+    pub name: String,
+    #[serde(default)]
+    pub downstream_kbps: Option<u64>,
+    #[serde(default)]
+    pub upstream_kbps: Option<u64>,
+    /// Delay added before each request is handled.
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// Random variation of up to this much either side of `latency_ms`.
+    #[serde(default)]
+    pub jitter_ms: u64,
+    /// Chance (0.0-1.0) that each body chunk stalls for `stall_ms`, as a
+    /// lost packet would.
+    #[serde(default)]
+    pub stall_probability: f64,
+    #[serde(default = "default_stall_ms")]
+    pub stall_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BreakpointConfig {
+    /// Seconds a paused exchange waits for a decision before passing through.
+    #[serde(default = "default_breakpoint_timeout_secs")]
+    pub timeout_secs: u64,
+    #[serde(default)]
+    pub rules: Vec<BreakpointRule>,
+}
+
+impl Default for BreakpointConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: default_breakpoint_timeout_secs(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+/// Matches exchanges that should be paused. Unset fields match anything.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BreakpointRule {
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+    #[serde(default)]
+    pub method: Option<String>,
+    #[serde(default = "default_true")]
+    pub request: bool,
+    #[serde(default)]
+    pub response: bool,
+}
+
+/// Serves responses from a local file, or from a directory in which the
+/// request path (minus the matched prefix) is looked up.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MapLocal {
+    pub path: String,
+    #[serde(default)]
+    pub status: Option<u16>,
+    /// Overrides the content type inferred from the file extension.
+    #[serde(default)]
+    pub content_type: Option<String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MapLocalRule {
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+    #[serde(flatten)]
+    pub local: MapLocal,
+}
+
+/// Sends matching requests to `target` instead of the route's upstreams.
+/// `path` is a regex whose captures (`$1`, `${name}`) expand into `target`;
+/// `query` is a regex the raw query string must match.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MapRemoteRule {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub query: Option<String>,
+    pub target: String,
+    /// Keep the client's `Host` header instead of the target's authority.
+    #[serde(default)]
+    pub preserve_host: bool,
 }
 
 fn default_weight() -> u32 {
@@ -49,12 +608,166 @@ fn default_cooldown_secs() -> u64 {
     15
 }
 
+fn default_error_rate_threshold() -> f64 {
+    0.5
+}
+
+fn default_window_secs() -> u64 {
+    30
+}
+
+fn default_half_open_requests() -> u32 {
+    1
+}
+
+fn default_breakpoint_timeout_secs() -> u64 {
+    120
+}
+
+fn default_ca_dir() -> String {
+    "config/ca".to_string()
+}
+
+fn default_via_name() -> String {
+    "charles".to_string()
+}
+
+fn default_fault_percentage() -> f64 {
+    100.0
+}
+
+fn default_queue_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_retry_attempts() -> u32 {
+    3
+}
+
+fn default_retry_on() -> Vec<RetryOn> {
+    vec![RetryOn::ConnectFailure, RetryOn::Reset, RetryOn::Timeout]
+}
+
+fn default_retry_statuses() -> Vec<u16> {
+    vec![502, 503, 504]
+}
+
+fn default_retry_backoff_ms() -> u64 {
+    25
+}
+
+fn default_retry_max_backoff_ms() -> u64 {
+    250
+}
+
+fn default_retry_buffer_bytes() -> u64 {
+    64 * 1024
+}
+
+fn default_retry_budget_ratio() -> f64 {
+    0.2
+}
+
+fn default_cache_max_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_cache_max_body_bytes() -> u64 {
+    1024 * 1024
+}
+
+fn default_compression_encodings() -> Vec<Encoding> {
+    vec![Encoding::Zstd, Encoding::Brotli, Encoding::Gzip]
+}
+
+fn default_compression_types() -> Vec<String> {
+    [
+        "text/*",
+        "application/json",
+        "application/javascript",
+        "application/xml",
+        "image/svg+xml",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn default_compression_min_bytes() -> u64 {
+    1024
+}
+
+fn default_mirror_percent() -> f64 {
+    100.0
+}
+
+fn default_mirror_body_bytes() -> u64 {
+    64 * 1024
+}
+
+fn default_sticky_cookie() -> String {
+    "charles_upstream".to_string()
+}
+
+fn default_stall_ms() -> u64 {
+    1000
+}
+
+fn default_true() -> bool {
+    true
+}
+
 impl Config {
     pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
         let content = std::fs::read_to_string(path)?;
-        let config: Config = toml::from_str(&content)?;
+        let mut config: Config = toml::from_str(&content)?;
+        config.validate()?;
+        config.normalize();
         Ok(config)
     }
+
+    /// Puts host patterns in the form requests are matched in.
+    fn normalize(&mut self) {
+        if let Some(forward) = &mut self.forward_proxy {
+            for pattern in forward
+                .allow
+                .iter_mut()
+                .chain(&mut forward.deny)
+                .chain(&mut forward.ssl_proxying)
+            {
+                *pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+            }
+        }
+    }
+
+    /// Rejects settings that parse but could never work.
+    fn validate(&self) -> Result<()> {
+        let mut names = HashSet::new();
+        for route in &self.routes {
+            if !names.insert(route.name.as_str()) {
+                bail!("Duplicate route name: {}", route.name);
+            }
+            if let Some(pattern) = &route.path_regex {
+                regex::Regex::new(pattern)
+                    .with_context(|| format!("Invalid path_regex for route {}", route.name))?;
+            }
+            if let Some(limit) = &route.rate_limit {
+                let rate = limit.requests_per_second;
+                if !rate.is_finite() || rate <= 0.0 {
+                    bail!(
+                        "Invalid rate_limit for route {}: requests_per_second must be a positive number",
+                        route.name
+                    );
+                }
+                if limit.burst == 0 {
+                    bail!(
+                        "Invalid rate_limit for route {}: burst must be at least 1",
+                        route.name
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -87,4 +800,657 @@ mod tests {
         assert_eq!(config.routes.len(), 1);
         assert_eq!(config.routes[0].upstreams[0].weight, 2);
     }
+
+    #[test]
+    fn test_parse_breakpoints() {
+        let toml_str = r#"
+            listen = "127.0.0.1:8080"
+            routes = []
+
+            [control]
+            listen = "127.0.0.1:9000"
+
+            [breakpoints]
+            timeout_secs = 30
+
+            [[breakpoints.rules]]
+            host = "*.example.org"
+            path_prefix = "/api"
+            method = "POST"
+            response = true
+        "#;
+
+        let config: Config = toml::from_str(toml_str).unwrap();
+        assert_eq!(config.breakpoints.timeout_secs, 30);
+        let rule = &config.breakpoints.rules[0];
+        assert_eq!(rule.host.as_deref(), Some("*.example.org"));
+        assert!(rule.request);
+        assert!(rule.response);
+
+        let defaults: BreakpointConfig = toml::from_str("").unwrap();
+        assert_eq!(defaults.timeout_secs, 120);
+        assert!(defaults.rules.is_empty());
+    }
+
+    #[test]
+    fn test_parse_forwarding() {
+        let config = |extra: &str| -> Config {
+            toml::from_str(&format!(
+                r#"
+                    listen = "127.0.0.1:8080"
+                    routes = []
+                    {extra}
+
+                    [control]
+                    listen = "127.0.0.1:9000"
+                "#
+            ))
+            .unwrap()
+        };
+
+        let trusted = config(r#"trusted_proxies = ["10.0.0.0/8", "::1/128"]"#);
+        assert_eq!(
+            trusted.trusted_proxies,
+            vec![
+                "10.0.0.0/8".parse::<IpNet>().unwrap(),
+                "::1/128".parse().unwrap()
+            ]
+        );
+        let defaults = config("");
+        assert!(defaults.trusted_proxies.is_empty());
+        assert_eq!(defaults.via_name, "charles");
+        assert_eq!(config(r#"via_name = "edge-1""#).via_name, "edge-1");
+    }
+
+    #[test]
+    fn test_parse_circuit_breaker() {
+        let upstream: Upstream = toml::from_str(
+            r#"
+                url = "http://10.0.0.1"
+                error_rate_threshold = 0.25
+                window_secs = 10
+                half_open_requests = 3
+            "#,
+        )
+        .unwrap();
+        assert_eq!(upstream.error_rate_threshold, 0.25);
+        assert_eq!(upstream.window_secs, 10);
+        assert_eq!(upstream.half_open_requests, 3);
+
+        let defaults: Upstream = toml::from_str(r#"url = "http://10.0.0.1""#).unwrap();
+        assert_eq!(defaults.fail_threshold, 3);
+        assert_eq!(defaults.cooldown_secs, 15);
+        assert_eq!(defaults.error_rate_threshold, 0.5);
+        assert_eq!(defaults.window_secs, 30);
+        assert_eq!(defaults.half_open_requests, 1);
+    }
+
+    #[test]
+    fn test_parse_header_rules() {
+        let toml_str = r#"
+            listen = "127.0.0.1:8080"
+
+            [control]
+            listen = "127.0.0.1:9000"
+
+            [[routes]]
+            name = "api"
+            hosts = ["example.com"]
+            path_prefix = "/"
+            preserve_host = false
+            upstreams = []
+
+            [routes.request_headers]
+            set = { "X-Client" = "{client_ip}" }
+            remove = ["Cookie"]
+
+            [routes.response_headers]
+            rename = { "Server" = "X-Upstream-Server" }
+
+            [[routes]]
+            name = "plain"
+            hosts = ["example.org"]
+            upstreams = []
+        "#;
+
+        let config: Config = toml::from_str(toml_str).unwrap();
+        let route = &config.routes[0];
+        assert!(!route.preserve_host);
+        assert_eq!(route.request_headers.set["X-Client"], "{client_ip}");
+        assert_eq!(route.request_headers.remove, vec!["Cookie"]);
+        assert_eq!(route.response_headers.rename["Server"], "X-Upstream-Server");
+
+        let plain = &config.routes[1];
+        assert!(plain.preserve_host);
+        assert!(plain.request_headers.set.is_empty());
+        assert!(plain.response_headers.rename.is_empty());
+    }
+
+    #[test]
+    fn test_parse_map_remote() {
+        let toml_str = r#"
+            listen = "127.0.0.1:8080"
+            routes = []
+
+            [control]
+            listen = "127.0.0.1:9000"
+
+            [[map_remote]]
+            name = "v2-dev"
+            host = "api.example.com"
+            path = "^/api/v2/(.*)$"
+            target = "http://127.0.0.1:3000/$1"
+        "#;
+
+        let config: Config = toml::from_str(toml_str).unwrap();
+        let rule = &config.map_remote[0];
+        assert!(rule.enabled);
+        assert!(!rule.preserve_host);
+        assert_eq!(rule.target, "http://127.0.0.1:3000/$1");
+    }
+
+    #[test]
+    fn test_parse_forward_proxy() {
+        let toml_str = r#"
+            listen = "127.0.0.1:8080"
+            routes = []
+
+            [control]
+            listen = "127.0.0.1:9000"
+
+            [forward_proxy]
+            listen = "127.0.0.1:8888"
+            deny = ["*.internal"]
+        "#;
+
+        let config: Config = toml::from_str(toml_str).unwrap();
+        let forward = config.forward_proxy.unwrap();
+        assert_eq!(forward.listen, "127.0.0.1:8888");
+        assert!(forward.allow.is_empty());
+        assert_eq!(forward.deny, vec!["*.internal"]);
+        assert!(forward.ssl_proxying.is_empty());
+        assert_eq!(config.ca_dir, "config/ca");
+    }
+
+    #[test]
+    fn test_parse_throttle() {
+        let toml_str = r#"
+            listen = "127.0.0.1:8080"
+
+            [control]
+            listen = "127.0.0.1:9000"
+
+            [throttle]
+            active = "flaky"
+
+            [[throttle.profiles]]
+            name = "flaky"
+            downstream_kbps = 512
+            latency_ms = 200
+            stall_probability = 0.05
+
+            [[routes]]
+            name = "api"
+            hosts = ["example.com"]
+            path_prefix = "/"
+            throttle = "3g"
+            upstreams = []
+        "#;
+
+        let config: Config = toml::from_str(toml_str).unwrap();
+        assert_eq!(config.throttle.active.as_deref(), Some("flaky"));
+        let profile = &config.throttle.profiles[0];
+        assert_eq!(profile.downstream_kbps, Some(512));
+        assert_eq!(profile.upstream_kbps, None);
+        assert_eq!(profile.stall_ms, 1000);
+        assert_eq!(config.routes[0].throttle.as_deref(), Some("3g"));
+    }
+
+    #[test]
+    fn test_parse_faults() {
+        let toml_str = r#"
+            listen = "127.0.0.1:8080"
+
+            [control]
+            listen = "127.0.0.1:9000"
+
+            [[routes]]
+            name = "api"
+            hosts = ["example.com"]
+            path_prefix = "/"
+            upstreams = []
+
+            [[routes.faults]]
+            type = "status"
+            percentage = 10
+            status = 503
+            body = "try later"
+
+            [[routes.faults]]
+            type = "reset"
+            method = "POST"
+        "#;
+
+        let config: Config = toml::from_str(toml_str).unwrap();
+        let faults = &config.routes[0].faults;
+        assert_eq!(faults[0].percentage, 10.0);
+        assert_eq!(
+            faults[0].fault,
+            Fault::Status {
+                status: 503,
+                body: "try later".to_string()
+            }
+        );
+        assert_eq!(faults[1].fault, Fault::Reset);
+        assert_eq!(faults[1].percentage, 100.0);
+        assert!(faults[1].enabled);
+    }
+
+    #[test]
+    fn test_parse_rate_limit() {
+        let toml_str = r#"
+            listen = "127.0.0.1:8080"
+
+            [control]
+            listen = "127.0.0.1:9000"
+
+            [[routes]]
+            name = "api"
+            hosts = ["example.com"]
+            path_prefix = "/"
+            rate_limit = { requests_per_second = 5, burst = 10, key = { header = "X-Api-Key" } }
+            upstreams = []
+
+            [[routes]]
+            name = "web"
+            hosts = ["example.com"]
+            path_prefix = "/web"
+            rate_limit = { requests_per_second = 0.5, burst = 1 }
+            upstreams = []
+        "#;
+
+        let config: Config = toml::from_str(toml_str).unwrap();
+        let api = config.routes[0].rate_limit.as_ref().unwrap();
+        assert_eq!(api.burst, 10);
+        assert_eq!(api.key, RateLimitKey::Header("X-Api-Key".to_string()));
+        let web = config.routes[1].rate_limit.as_ref().unwrap();
+        assert_eq!(web.requests_per_second, 0.5);
+        assert_eq!(web.key, RateLimitKey::ClientIp);
+    }
+
+    #[test]
+    fn test_normalize_forward_patterns() {
+        let mut config: Config = toml::from_str(
+            r#"
+                listen = "127.0.0.1:8080"
+                routes = []
+
+                [control]
+                listen = "127.0.0.1:9000"
+
+                [forward_proxy]
+                listen = "127.0.0.1:8081"
+                allow = ["*.Example.COM"]
+                deny = ["Evil.com."]
+                ssl_proxying = ["API.example.com"]
+            "#,
+        )
+        .unwrap();
+        config.normalize();
+        let forward = config.forward_proxy.unwrap();
+        assert_eq!(forward.allow, ["*.example.com"]);
+        assert_eq!(forward.deny, ["evil.com"]);
+        assert_eq!(forward.ssl_proxying, ["api.example.com"]);
+    }
+
+    #[test]
+    fn test_validate_rejects_unusable_rates() {
+        let config = |rate: &str, burst: u32| -> Config {
+            toml::from_str(&format!(
+                r#"
+                    listen = "127.0.0.1:8080"
+
+                    [control]
+                    listen = "127.0.0.1:9000"
+
+                    [[routes]]
+                    name = "api"
+                    hosts = ["example.com"]
+                    rate_limit = {{ requests_per_second = {rate}, burst = {burst} }}
+                    upstreams = []
+                "#
+            ))
+            .unwrap()
+        };
+        assert!(config("2", 1).validate().is_ok());
+        for rate in ["0", "-1", "inf", "nan"] {
+            assert!(config(rate, 1).validate().is_err(), "{rate}");
+        }
+        assert!(config("2", 0).validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_duplicate_route_names() {
+        let config = |second: &str| -> Config {
+            toml::from_str(&format!(
+                r#"
+                    listen = "127.0.0.1:8080"
+
+                    [control]
+                    listen = "127.0.0.1:9000"
+
+                    [[routes]]
+                    name = "api"
+                    hosts = ["example.com"]
+                    upstreams = []
+
+                    [[routes]]
+                    name = "{second}"
+                    hosts = ["example.org"]
+                    upstreams = []
+                "#
+            ))
+            .unwrap()
+        };
+        assert!(config("web").validate().is_ok());
+        assert!(config("api").validate().is_err());
+    }
+
+    #[test]
+    fn test_parse_concurrency_limits() {
+        let toml_str = r#"
+            listen = "127.0.0.1:8080"
+            max_connections = 512
+
+            [control]
+            listen = "127.0.0.1:9000"
+
+            [[routes]]
+            name = "api"
+            hosts = ["example.com"]
+            path_prefix = "/"
+            concurrency = { max_in_flight = 8, queue_size = 16 }
+
+            [[routes.upstreams]]
+            url = "http://10.0.0.1"
+            concurrency = { max_in_flight = 4, queue_timeout_ms = 250 }
+        "#;
+
+        let config: Config = toml::from_str(toml_str).unwrap();
+        assert_eq!(config.max_connections, Some(512));
+        let route = config.routes[0].concurrency.as_ref().unwrap();
+        assert_eq!(route.max_in_flight, 8);
+        assert_eq!(route.queue_size, 16);
+        assert_eq!(route.queue_timeout_ms, 1000);
+        let upstream = config.routes[0].upstreams[0].concurrency.as_ref().unwrap();
+        assert_eq!(upstream.queue_size, 0);
+        assert_eq!(upstream.queue_timeout_ms, 250);
+    }
+
+    #[test]
+    fn test_parse_timeouts() {
+        let toml_str = r#"
+            listen = "127.0.0.1:8080"
+
+            [control]
+            listen = "127.0.0.1:9000"
+
+            [timeouts]
+            header_read_ms = 5000
+            request_ms = 30000
+
+            [[routes]]
+            name = "reports"
+            hosts = ["example.com"]
+            path_prefix = "/reports"
+            timeouts = { response_header_ms = 120000, request_ms = 0 }
+            upstreams = []
+        "#;
+
+        let config: Config = toml::from_str(toml_str).unwrap();
+        assert_eq!(config.timeouts.header_read_ms, Some(5000));
+        assert_eq!(config.timeouts.connect_ms, None);
+        let route = &config.routes[0].timeouts;
+        assert_eq!(route.response_header_ms, Some(120000));
+        assert_eq!(route.request_ms, Some(0));
+    }
+
+    #[test]
+    fn test_parse_retry_policy() {
+        let toml_str = r#"
+            listen = "127.0.0.1:8080"
+
+            [control]
+            listen = "127.0.0.1:9000"
+
+            [[routes]]
+            name = "api"
+            hosts = ["example.com"]
+            path_prefix = "/"
+            upstreams = []
+
+            [routes.retry]
+            max_attempts = 2
+            retry_on = ["connect_failure"]
+            statuses = [503]
+        "#;
+
+        let config: Config = toml::from_str(toml_str).unwrap();
+        let retry = config.routes[0].retry.as_ref().unwrap();
+        assert_eq!(retry.max_attempts, 2);
+        assert_eq!(retry.retry_on, vec![RetryOn::ConnectFailure]);
+        assert_eq!(retry.statuses, vec![503]);
+        assert_eq!(retry.buffer_body_bytes, 64 * 1024);
+        assert_eq!(retry.budget_ratio, 0.2);
+    }
+
+    #[test]
+    fn test_parse_balancers() {
+        let toml_str = r#"
+            listen = "127.0.0.1:8080"
+
+            [control]
+            listen = "127.0.0.1:9000"
+
+            [[routes]]
+            name = "assets"
+            hosts = ["example.com"]
+            path_prefix = "/assets"
+            balancer = "consistent_hash"
+            hash_key = { cookie = "session" }
+            upstreams = []
+
+            [[routes]]
+            name = "api"
+            hosts = ["example.com"]
+            path_prefix = "/"
+            balancer = "least_conn"
+            upstreams = []
+        "#;
+
+        let config: Config = toml::from_str(toml_str).unwrap();
+        assert_eq!(config.routes[0].balancer, Balancer::ConsistentHash);
+        assert_eq!(
+            config.routes[0].hash_key,
+            HashKey::Cookie("session".to_string())
+        );
+        assert_eq!(config.routes[1].balancer, Balancer::LeastConn);
+        assert_eq!(config.routes[1].hash_key, HashKey::Path);
+    }
+
+    #[test]
+    fn test_parse_route_conditions() {
+        let toml_str = r#"
+            name = "users"
+            hosts = ["example.com"]
+            path_regex = '^/users/(?P<id>\d+)$'
+            rewrite_path = "/v2/users/${id}"
+            upstreams = []
+
+            [match]
+            methods = ["GET", "HEAD"]
+            headers = { "x-canary" = "1", authorization = "*" }
+            query = { version = "2" }
+            client_cidrs = ["10.0.0.0/8"]
+        "#;
+
+        let route: Route = toml::from_str(toml_str).unwrap();
+        assert_eq!(route.path_prefix, "");
+        assert_eq!(route.path_regex.as_deref(), Some(r"^/users/(?P<id>\d+)$"));
+        assert_eq!(route.rewrite_path.as_deref(), Some("/v2/users/${id}"));
+        assert_eq!(route.conditions.methods, vec!["GET", "HEAD"]);
+        assert_eq!(route.conditions.headers["authorization"], "*");
+        assert_eq!(route.conditions.query["version"], "2");
+        assert_eq!(
+            route.conditions.client_cidrs,
+            vec!["10.0.0.0/8".parse::<IpNet>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_parse_compression() {
+        let toml_str = r#"
+            name = "api"
+            hosts = ["example.com"]
+            upstreams = []
+
+            [compression]
+            encodings = ["br", "gzip"]
+            decompress = true
+        "#;
+
+        let route: Route = toml::from_str(toml_str).unwrap();
+        let compression = route.compression.unwrap();
+        assert_eq!(compression.encodings, [Encoding::Brotli, Encoding::Gzip]);
+        assert!(compression.content_types.contains(&"text/*".to_string()));
+        assert_eq!(compression.min_bytes, 1024);
+        assert!(compression.decompress);
+        assert_eq!(Encoding::from_token(" X-GZIP"), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn test_parse_cache() {
+        let toml_str = r#"
+            name = "api"
+            hosts = ["example.com"]
+            upstreams = []
+
+            [cache]
+            max_bytes = 1048576
+            default_ttl_secs = 30
+        "#;
+
+        let route: Route = toml::from_str(toml_str).unwrap();
+        let cache = route.cache.unwrap();
+        assert_eq!(cache.max_bytes, 1024 * 1024);
+        assert_eq!(cache.max_body_bytes, 1024 * 1024);
+        assert_eq!(cache.default_ttl_secs, 30);
+    }
+
+    #[test]
+    fn test_parse_mirror() {
+        let toml_str = r#"
+            name = "api"
+            hosts = ["example.com"]
+            upstreams = []
+
+            [mirror]
+            url = "http://shadow:8080"
+            percent = 10
+            compare = true
+        "#;
+
+        let route: Route = toml::from_str(toml_str).unwrap();
+        let mirror = route.mirror.unwrap();
+        assert_eq!(mirror.url, "http://shadow:8080");
+        assert_eq!(mirror.percent, 10.0);
+        assert_eq!(mirror.max_body_bytes, 64 * 1024);
+        assert!(mirror.compare);
+    }
+
+    #[test]
+    fn test_parse_canary() {
+        let toml_str = r#"
+            name = "api"
+            hosts = ["example.com"]
+            path_prefix = "/"
+
+            [[upstreams]]
+            url = "http://stable:8080"
+
+            [canary]
+            percent = 5
+            header = { name = "x-canary", value = "always" }
+
+            [[canary.upstreams]]
+            url = "http://canary:8080"
+            weight = 2
+        "#;
+
+        let route: Route = toml::from_str(toml_str).unwrap();
+        let canary = route.canary.as_ref().unwrap();
+        assert_eq!(canary.percent, 5.0);
+        assert_eq!(canary.header.as_ref().unwrap().value, "always");
+        assert!(canary.cookie.is_none());
+        let urls: Vec<_> = route.all_upstreams().map(|u| u.url.as_str()).collect();
+        assert_eq!(urls, vec!["http://stable:8080", "http://canary:8080"]);
+    }
+
+    #[test]
+    fn test_parse_sticky() {
+        let toml_str = r#"
+            name = "legacy"
+            hosts = ["example.com"]
+            path_prefix = "/app"
+            upstreams = []
+
+            [sticky]
+            secret = "s3cret"
+            max_age_secs = 3600
+        "#;
+
+        let route: Route = toml::from_str(toml_str).unwrap();
+        let sticky = route.sticky.unwrap();
+        assert_eq!(sticky.cookie, "charles_upstream");
+        assert_eq!(sticky.secret.as_deref(), Some("s3cret"));
+        assert_eq!(sticky.max_age_secs, Some(3600));
+        assert!(!sticky.secure);
+    }
+
+    #[test]
+    fn test_parse_map_local() {
+        let toml_str = r#"
+            listen = "127.0.0.1:8080"
+
+            [control]
+            listen = "127.0.0.1:9000"
+
+            [[map_local]]
+            host = "api.example.com"
+            path_prefix = "/v1/users"
+            path = "stubs/users.json"
+            status = 201
+            headers = { "X-Stub" = "1" }
+
+            [[routes]]
+            name = "static"
+            hosts = ["example.com"]
+            path_prefix = "/assets"
+            upstreams = []
+
+            [routes.map_local]
+            path = "public"
+        "#;
+
+        let config: Config = toml::from_str(toml_str).unwrap();
+        let rule = &config.map_local[0];
+        assert_eq!(rule.path_prefix.as_deref(), Some("/v1/users"));
+        assert_eq!(rule.local.path, "stubs/users.json");
+        assert_eq!(rule.local.status, Some(201));
+        assert_eq!(rule.local.headers["X-Stub"], "1");
+        assert_eq!(
+            config.routes[0].map_local.as_ref().map(|m| m.path.as_str()),
+            Some("public")
+        );
+    }
 }
@@ -1,4 +1,7 @@
+use crate::breakpoint::{BreakpointDecision, BreakpointEdits};
+use crate::config::TimeoutConfig;
 use crate::state::SharedState;
+use crate::timeout::{self, ActivityIo, Stage, TimeoutBody, Timeouts};
 use anyhow::Result;
 use bytes::Bytes;
 use http_body_util::{BodyExt, Full, combinators::BoxBody};
@@ -6,23 +9,31 @@ use hyper::body::Incoming;
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Method, Request, Response, StatusCode};
-use hyper_util::rt::TokioIo;
+use hyper_util::rt::{TokioIo, TokioTimer};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::net::TcpListener;
-use tracing::{error, info};
+use tracing::{debug, error, info};
 
 type GenericError = Box<dyn std::error::Error + Send + Sync>;
 type BoxedBody = BoxBody<Bytes, GenericError>;
 
 pub struct ControlServer {
     listen_addr: String,
+    /// The proxy's header-read and body-idle timeouts apply here too.
+    timeouts: TimeoutConfig,
     state: Arc<SharedState>,
 }
 
 impl ControlServer {
-    pub fn new(listen_addr: String, state: Arc<SharedState>) -> Self {
-        Self { listen_addr, state }
+    pub fn new(listen_addr: String, timeouts: TimeoutConfig, state: Arc<SharedState>) -> Self {
+        Self {
+            listen_addr,
+            timeouts,
+            state,
+        }
     }
 
     pub async fn run(self) -> Result<()> {
@@ -34,17 +45,32 @@ impl ControlServer {
 
         loop {
             let (stream, _remote_addr) = listener.accept().await?;
+            let (stream, activity) = ActivityIo::new(stream);
             let io = TokioIo::new(stream);
-            let server_clone = Arc::clone(&server);
+            let server = Arc::clone(&server);
 
             tokio::task::spawn(async move {
-                let service = service_fn(move |req| {
+                let body_idle = Timeouts::resolve(&server.timeouts, None).body_idle;
+                let server_clone = Arc::clone(&server);
+                let service = service_fn(move |req: Request<Incoming>| {
                     let server = Arc::clone(&server_clone);
+                    let req = req.map(|body| {
+                        TimeoutBody::new(body, body_idle, None, Stage::RequestBody, None).boxed()
+                    });
                     async move { server.handle_request(req).await }
                 });
 
-                if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
-                    error!("Error serving control connection: {}", err);
+                if let Err(err) = http1::Builder::new()
+                    .timer(TokioTimer::new())
+                    .header_read_timeout(timeout::header_read(&server.timeouts))
+                    .serve_connection(io, service)
+                    .await
+                {
+                    if err.is_timeout() && !activity.request_pending() {
+                        debug!("Closed idle control connection");
+                    } else {
+                        error!("Error serving control connection: {}", err);
+                    }
                 }
             });
         }
@@ -52,24 +78,193 @@ impl ControlServer {
 
     async fn handle_request(
         &self,
-        req: Request<Incoming>,
+        req: Request<BoxedBody>,
     ) -> Result<Response<BoxedBody>, std::convert::Infallible> {
-        let path = req.uri().path();
-        let method = req.method();
+        let path = req.uri().path().to_string();
+        let method = req.method().clone();
 
         info!("Control API request: {} {}", method, path);
 
-        match (method, path) {
-            (&Method::GET, "/health") => Ok(self.health_response()),
-            (&Method::GET, "/metrics") => Ok(self.metrics_response()),
-            _ => Ok(self.not_found_response()),
+        let response = match (&method, path.as_str()) {
+            (&Method::GET, "/health") => self.health_response(),
+            (&Method::GET, "/metrics") => self.metrics_response(),
+            (&Method::GET, "/requests") => self.requests_response(),
+            (&Method::GET, "/tunnels") => self.tunnels_response(),
+            (&Method::GET, "/breakpoints") => self.breakpoints_response(),
+            (&Method::POST, "/breakpoints/rules") => match read_json(req).await {
+                Ok(rule) => {
+                    self.state.breakpoints.add_rule(rule);
+                    self.breakpoints_response()
+                }
+                Err(message) => self.bad_request_response(&message),
+            },
+            (&Method::DELETE, "/breakpoints/rules") => {
+                self.state.breakpoints.set_rules(Vec::new());
+                self.breakpoints_response()
+            }
+            (&Method::DELETE, p) if p.starts_with("/breakpoints/rules/") => {
+                match p["/breakpoints/rules/".len()..].parse() {
+                    Ok(index) if self.state.breakpoints.remove_rule(index) => {
+                        self.breakpoints_response()
+                    }
+                    _ => self.not_found_response(),
+                }
+            }
+            (&Method::GET, "/map-remote") => self.map_remote_response(),
+            (&Method::GET, "/faults") => self.faults_response(),
+            (&Method::POST, p) if p.starts_with("/faults/") => self.toggle_fault(p),
+            (&Method::GET, "/throttle") => self.throttle_response(),
+            (&Method::POST, "/throttle") => match read_json::<ThrottleRequest>(req).await {
+                Ok(request) => match self
+                    .state
+                    .throttle
+                    .set_active(request.route.as_deref(), request.profile)
+                {
+                    Ok(()) => self.throttle_response(),
+                    Err(e) => self.bad_request_response(&e.to_string()),
+                },
+                Err(e) => self.bad_request_response(&e),
+            },
+            (&Method::GET, "/cache") => self.cache_response(),
+            (&Method::POST, "/cache/purge") => match read_json::<PurgeRequest>(req).await {
+                Ok(PurgeRequest {
+                    key: None,
+                    prefix: None,
+                }) => self.bad_request_response("Give a key or a prefix to purge"),
+                Ok(request) => {
+                    let purged = self
+                        .state
+                        .cache
+                        .purge(request.key.as_deref(), request.prefix.as_deref());
+                    let body = serde_json::json!({ "purged": purged });
+                    self.json_response(StatusCode::OK, body.to_string())
+                }
+                Err(e) => self.bad_request_response(&e),
+            },
+            (&Method::GET, "/canary") => self.canary_response(),
+            (&Method::POST, p) if p.starts_with("/canary/") => self.set_canary(p, req).await,
+            (&Method::POST, p) if p.starts_with("/map-remote/") => self.toggle_map_remote(p),
+            (&Method::POST, p) if p.starts_with("/breakpoints/") => {
+                self.resolve_breakpoint(p, req).await
+            }
+            _ => self.not_found_response(),
+        };
+
+        Ok(response)
+    }
+
+    /// Handles `POST /breakpoints/{id}/continue` and `POST /breakpoints/{id}/abort`.
+    async fn resolve_breakpoint(&self, path: &str, req: Request<BoxedBody>) -> Response<BoxedBody> {
+        let Some((id, action)) = path["/breakpoints/".len()..].split_once('/') else {
+            return self.not_found_response();
+        };
+        let Ok(id) = id.parse::<u64>() else {
+            return self.not_found_response();
+        };
+
+        let decision = match action {
+            "continue" => match read_json::<BreakpointEdits>(req).await {
+                Ok(edits) => BreakpointDecision::Continue(edits),
+                Err(message) => return self.bad_request_response(&message),
+            },
+            "abort" => match read_json::<AbortRequest>(req).await {
+                Ok(abort) => BreakpointDecision::Abort(abort.status),
+                Err(message) => return self.bad_request_response(&message),
+            },
+            _ => return self.not_found_response(),
+        };
+
+        if self.state.breakpoints.resolve(id, decision) {
+            self.json_response(StatusCode::OK, r#"{"status":"ok"}"#.to_string())
+        } else {
+            self.not_found_response()
         }
     }
 
-    fn health_response(&self) -> Response<BoxedBody> {
-        let body = r#"{"status":"ok"}"#;
+    /// Handles `POST /map-remote/{index}/enable` and `POST /map-remote/{index}/disable`.
+    fn toggle_map_remote(&self, path: &str) -> Response<BoxedBody> {
+        let enabled = match path["/map-remote/".len()..].split_once('/') {
+            Some((index, "enable")) => index.parse().map(|i| (i, true)),
+            Some((index, "disable")) => index.parse().map(|i| (i, false)),
+            _ => return self.not_found_response(),
+        };
+        match enabled {
+            Ok((index, enabled)) if self.state.map_remote.set_enabled(index, enabled) => {
+                self.map_remote_response()
+            }
+            _ => self.not_found_response(),
+        }
+    }
+
+    /// Handles `POST /faults/{route}/{index}/enable` and `POST /faults/{route}/{index}/disable`.
+    fn toggle_fault(&self, path: &str) -> Response<BoxedBody> {
+        let parts: Vec<&str> = path["/faults/".len()..].split('/').collect();
+        let (route, index, enabled) = match parts.as_slice() {
+            [route, index, "enable"] => (route, index, true),
+            [route, index, "disable"] => (route, index, false),
+            _ => return self.not_found_response(),
+        };
+        match index.parse() {
+            Ok(index) if self.state.faults.set_enabled(route, index, enabled) => {
+                self.faults_response()
+            }
+            _ => self.not_found_response(),
+        }
+    }
+
+    /// Handles `POST /canary/{route}`, changing the route's live split.
+    async fn set_canary(&self, path: &str, req: Request<BoxedBody>) -> Response<BoxedBody> {
+        let route = &path["/canary/".len()..];
+        let request = match read_json::<CanaryRequest>(req).await {
+            Ok(request) => request,
+            Err(message) => return self.bad_request_response(&message),
+        };
+        if !(0.0..=100.0).contains(&request.percent) {
+            return self.bad_request_response("percent must be between 0 and 100");
+        }
+        if self.state.canary.set_percent(route, request.percent) {
+            self.canary_response()
+        } else {
+            self.not_found_response()
+        }
+    }
+
+    fn cache_response(&self) -> Response<BoxedBody> {
+        let body = serde_json::to_string(&self.state.cache.stats()).unwrap_or_default();
+        self.json_response(StatusCode::OK, body)
+    }
+
+    fn canary_response(&self) -> Response<BoxedBody> {
+        let body = serde_json::to_string(&self.state.canary.stats()).unwrap_or_default();
+        self.json_response(StatusCode::OK, body)
+    }
+
+    fn faults_response(&self) -> Response<BoxedBody> {
+        let body = serde_json::to_string(&self.state.faults.get_rules()).unwrap_or_default();
+        self.json_response(StatusCode::OK, body)
+    }
+
+    fn map_remote_response(&self) -> Response<BoxedBody> {
+        let body = serde_json::to_string(&self.state.map_remote.get_rules()).unwrap_or_default();
+        self.json_response(StatusCode::OK, body)
+    }
+
+    fn throttle_response(&self) -> Response<BoxedBody> {
+        let body = serde_json::to_string(&self.state.throttle.get_status()).unwrap_or_default();
+        self.json_response(StatusCode::OK, body)
+    }
+
+    fn breakpoints_response(&self) -> Response<BoxedBody> {
+        let body = serde_json::json!({
+            "rules": self.state.breakpoints.get_rules(),
+            "pending": self.state.breakpoints.get_pending(),
+        });
+        self.json_response(StatusCode::OK, body.to_string())
+    }
+
+    fn json_response(&self, status: StatusCode, body: String) -> Response<BoxedBody> {
         Response::builder()
-            .status(StatusCode::OK)
+            .status(status)
             .header("Content-Type", "application/json")
             .body(
                 Full::new(Bytes::from(body))
@@ -79,12 +274,13 @@ impl ControlServer {
             .unwrap()
     }
 
-    fn metrics_response(&self) -> Response<BoxedBody> {
-        let metrics = self.state.get_metrics();
-        let body = format!(
-            r#"{{"total_requests":{},"active_requests":{},"total_errors":{},"upstreams":[]}}"#,
-            metrics.total_requests, metrics.active_requests, metrics.total_errors
-        );
+    fn bad_request_response(&self, message: &str) -> Response<BoxedBody> {
+        let body = serde_json::json!({ "error": message });
+        self.json_response(StatusCode::BAD_REQUEST, body.to_string())
+    }
+
+    fn health_response(&self) -> Response<BoxedBody> {
+        let body = r#"{"status":"ok"}"#;
         Response::builder()
             .status(StatusCode::OK)
             .header("Content-Type", "application/json")
@@ -96,6 +292,34 @@ impl ControlServer {
             .unwrap()
     }
 
+    fn metrics_response(&self) -> Response<BoxedBody> {
+        let metrics = self.state.get_metrics();
+        let body = serde_json::json!({
+            "total_requests": metrics.total_requests,
+            "active_requests": metrics.active_requests,
+            "total_errors": metrics.total_errors,
+            "timeouts": metrics.timeouts,
+            "mirror_errors": metrics.mirror_errors,
+            "upstreams": metrics.upstreams_status,
+            "rate_limits": self.state.rate_limits.stats(),
+            "retry_budgets": self.state.retries.stats(),
+            "concurrency": self.state.concurrency.stats(),
+            "connections": self.state.concurrency.connection_stats(),
+            "cache": self.state.cache.stats(),
+        });
+        self.json_response(StatusCode::OK, body.to_string())
+    }
+
+    fn requests_response(&self) -> Response<BoxedBody> {
+        let body = serde_json::to_string(&self.state.get_request_logs()).unwrap_or_default();
+        self.json_response(StatusCode::OK, body)
+    }
+
+    fn tunnels_response(&self) -> Response<BoxedBody> {
+        let body = serde_json::to_string(&self.state.get_tunnel_logs()).unwrap_or_default();
+        self.json_response(StatusCode::OK, body)
+    }
+
     fn not_found_response(&self) -> Response<BoxedBody> {
         Response::builder()
             .status(StatusCode::NOT_FOUND)
@@ -107,3 +331,48 @@ impl ControlServer {
             .unwrap()
     }
 }
+
+/// Body of `POST /throttle`: a null `profile` turns throttling off.
+#[derive(Deserialize)]
+struct ThrottleRequest {
+    #[serde(default)]
+    route: Option<String>,
+    profile: Option<String>,
+}
+
+/// Body of `POST /cache/purge`: one exact key, or every key with a prefix.
+#[derive(Deserialize)]
+struct PurgeRequest {
+    #[serde(default)]
+    key: Option<String>,
+    #[serde(default)]
+    prefix: Option<String>,
+}
+
+/// Body of `POST /canary/{route}`.
+#[derive(Deserialize)]
+struct CanaryRequest {
+    percent: f64,
+}
+
+#[derive(Deserialize)]
+struct AbortRequest {
+    #[serde(default = "default_abort_status")]
+    status: u16,
+}
+
+fn default_abort_status() -> u16 {
+    502
+}
+
+/// Reads a JSON request body, treating an empty body as `{}`.
+async fn read_json<T: DeserializeOwned>(req: Request<BoxedBody>) -> Result<T, String> {
+    let body = req
+        .into_body()
+        .collect()
+        .await
+        .map_err(|e| format!("Failed to read body: {}", e))?
+        .to_bytes();
+    let body: &[u8] = if body.is_empty() { b"{}" } else { &body };
+    serde_json::from_slice(body).map_err(|e| format!("Invalid JSON: {}", e))
+}
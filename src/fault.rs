@@ -0,0 +1,213 @@
+use crate::config::{Fault, FaultRule, Route};
+use crate::url_path;
+use bytes::Bytes;
+use hyper::body::{Body, Frame, SizeHint};
+use parking_lot::RwLock;
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Per-route fault rules with their runtime enabled state.
+pub struct FaultManager {
+    rules: RwLock<BTreeMap<String, Vec<FaultRule>>>,
+}
+
+impl FaultManager {
+    pub fn new() -> Self {
+        Self {
+            rules: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn set_rules(&self, routes: &[Route]) {
+        *self.rules.write() = routes
+            .iter()
+            .filter(|route| !route.faults.is_empty())
+            .map(|route| (route.name.clone(), route.faults.clone()))
+            .collect();
+    }
+
+    pub fn get_rules(&self) -> BTreeMap<String, Vec<FaultRule>> {
+        self.rules.read().clone()
+    }
+
+    pub fn set_enabled(&self, route: &str, index: usize, enabled: bool) -> bool {
+        match self
+            .rules
+            .write()
+            .get_mut(route)
+            .and_then(|rules| rules.get_mut(index))
+        {
+            Some(rule) => {
+                rule.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Rolls each enabled matching rule in order and returns the first fault
+    /// that fires.
+    pub fn pick(&self, route: &str, method: &str, path: &str) -> Option<Fault> {
+        let rules = self.rules.read();
+        rules
+            .get(route)?
+            .iter()
+            .filter(|rule| rule.enabled)
+            .filter(|rule| {
+                rule.method
+                    .as_deref()
+                    .is_none_or(|m| m.eq_ignore_ascii_case(method))
+            })
+            .filter(|rule| {
+                rule.path_prefix
+                    .as_deref()
+                    .is_none_or(|prefix| url_path::has_prefix(path, prefix))
+            })
+            .find(|rule| rand::random::<f64>() * 100.0 < rule.percentage)
+            .map(|rule| rule.fault.clone())
+    }
+}
+
+impl Default for FaultManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Body that fails once more than `limit` bytes would be sent, so the
+/// client sees the response cut off mid-stream.
+pub struct TruncatedBody<B> {
+    inner: B,
+    remaining: u64,
+    cut: bool,
+    flushed: bool,
+}
+
+impl<B> TruncatedBody<B> {
+    pub fn new(inner: B, limit: u64) -> Self {
+        Self {
+            inner,
+            remaining: limit,
+            cut: false,
+            flushed: false,
+        }
+    }
+}
+
+impl<B> Body for TruncatedBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: Into<GenericError>,
+{
+    type Data = Bytes;
+    type Error = GenericError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, GenericError>>> {
+        let this = self.get_mut();
+        loop {
+            if this.cut {
+                // Yield once so the connection flushes what was already
+                // written; failing straight away would discard it.
+                if !this.flushed {
+                    this.flushed = true;
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                return Poll::Ready(Some(Err(truncated())));
+            }
+            let frame = match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => frame,
+                Some(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                None => return Poll::Ready(None),
+            };
+            match frame.into_data() {
+                Ok(mut data) => {
+                    if data.len() as u64 > this.remaining {
+                        data.truncate(this.remaining as usize);
+                        this.cut = true;
+                        if data.is_empty() {
+                            continue;
+                        }
+                    }
+                    this.remaining -= data.len() as u64;
+                    return Poll::Ready(Some(Ok(Frame::data(data))));
+                }
+                Err(frame) => return Poll::Ready(Some(Ok(frame))),
+            }
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+fn truncated() -> GenericError {
+    "response truncated by fault injection".into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{BodyExt, Full};
+
+    fn rule(percentage: f64, method: Option<&str>, fault: Fault) -> FaultRule {
+        FaultRule {
+            enabled: true,
+            percentage,
+            method: method.map(str::to_string),
+            path_prefix: Some("/api".to_string()),
+            fault,
+        }
+    }
+
+    #[test]
+    fn test_pick_respects_matchers_and_toggles() {
+        let manager = FaultManager::new();
+        manager.rules.write().insert(
+            "api".to_string(),
+            vec![
+                rule(0.0, None, Fault::Reset),
+                rule(100.0, Some("POST"), Fault::Delay { delay_ms: 10 }),
+                rule(100.0, None, Fault::Truncate { after_bytes: 1 }),
+            ],
+        );
+
+        assert_eq!(
+            manager.pick("api", "post", "/api/users"),
+            Some(Fault::Delay { delay_ms: 10 })
+        );
+        assert_eq!(
+            manager.pick("api", "GET", "/api/users"),
+            Some(Fault::Truncate { after_bytes: 1 })
+        );
+        assert_eq!(manager.pick("api", "GET", "/health"), None);
+        assert_eq!(manager.pick("api", "GET", "/apiv2"), None);
+        assert_eq!(manager.pick("web", "GET", "/api"), None);
+
+        assert!(manager.set_enabled("api", 2, false));
+        assert_eq!(manager.pick("api", "GET", "/api/users"), None);
+        assert!(!manager.set_enabled("api", 3, false));
+    }
+
+    #[tokio::test]
+    async fn test_truncated_body_errors_after_limit() {
+        let mut body = TruncatedBody::new(Full::new(Bytes::from_static(b"hello world")), 8);
+        let data = body.frame().await.unwrap().unwrap().into_data().unwrap();
+        assert_eq!(data, "hello wo");
+        assert!(body.frame().await.unwrap().is_err());
+
+        let mut body = TruncatedBody::new(Full::new(Bytes::from_static(b"abc")), 0);
+        assert!(body.frame().await.unwrap().is_err());
+
+        // Bodies within the limit pass through untouched.
+        let body = TruncatedBody::new(Full::new(Bytes::from_static(b"abc")), 8);
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "abc");
+    }
+}
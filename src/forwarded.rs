@@ -0,0 +1,211 @@
+use hyper::header::{FORWARDED, HeaderName, HeaderValue, VIA};
+use hyper::{HeaderMap, Version};
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+pub const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+pub const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+pub const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+pub fn is_trusted(trusted_proxies: &[IpNet], addr: IpAddr) -> bool {
+    trusted_proxies.iter().any(|net| net.contains(&addr))
+}
+
+/// Adds `X-Forwarded-*`, `Forwarded` and `Via` for a request received from
+/// `client`. Forwarding headers sent by an untrusted client are discarded
+/// rather than extended, so upstreams can rely on the left-most entry.
+pub fn apply(
+    headers: &mut HeaderMap,
+    client: IpAddr,
+    trusted: bool,
+    proto: &str,
+    host: &str,
+    version: Version,
+    via_name: &str,
+) {
+    if !trusted {
+        headers.remove(&X_FORWARDED_FOR);
+        headers.remove(&X_FORWARDED_PROTO);
+        headers.remove(&X_FORWARDED_HOST);
+        headers.remove(FORWARDED);
+    }
+
+    append_list(headers, X_FORWARDED_FOR, &client.to_string());
+    if !headers.contains_key(&X_FORWARDED_PROTO) {
+        insert(headers, X_FORWARDED_PROTO, proto);
+    }
+    if !headers.contains_key(&X_FORWARDED_HOST) {
+        insert(headers, X_FORWARDED_HOST, host);
+    }
+
+    let element = format!(
+        "for={};host={};proto={}",
+        forwarded_node(client),
+        quote_if_needed(host),
+        proto
+    );
+    append_list(headers, FORWARDED, &element);
+
+    append_via(headers, version, via_name);
+}
+
+pub fn append_via(headers: &mut HeaderMap, version: Version, via_name: &str) {
+    let via = format!("{} {}", via_version(version), via_name);
+    append_list(headers, VIA, &via);
+}
+
+/// True if a `Via` entry shows the message already passed through `via_name`.
+pub fn is_loop(headers: &HeaderMap, via_name: &str) -> bool {
+    headers
+        .get_all(VIA)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|entry| entry.split_whitespace().nth(1))
+        .any(|received_by| received_by.eq_ignore_ascii_case(via_name))
+}
+
+/// Protocol version as written in `Via` (`1.1`, `2`, ...).
+pub fn via_version(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    }
+}
+
+/// Joins all existing values of `name` and `value` into one comma-separated
+/// header, which is how list-valued headers are extended by proxies.
+fn append_list(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    let mut values: Vec<String> = headers
+        .get_all(&name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .map(str::to_string)
+        .collect();
+    values.push(value.to_string());
+    insert(headers, name, &values.join(", "));
+}
+
+fn insert(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
+
+/// RFC 7239 node: IPv6 addresses are bracketed and must be quoted.
+fn forwarded_node(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(v4) => v4.to_string(),
+        IpAddr::V6(v6) => format!("\"[{}]\"", v6),
+    }
+}
+
+fn quote_if_needed(value: &str) -> String {
+    let is_token = !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    if is_token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spoofed() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(&X_FORWARDED_FOR, HeaderValue::from_static("1.2.3.4"));
+        headers.insert(&X_FORWARDED_PROTO, HeaderValue::from_static("https"));
+        headers.insert(FORWARDED, HeaderValue::from_static("for=1.2.3.4"));
+        headers.insert(VIA, HeaderValue::from_static("1.0 edge"));
+        headers
+    }
+
+    #[test]
+    fn test_untrusted_client_headers_replaced() {
+        let mut headers = spoofed();
+        let client: IpAddr = "192.0.2.7".parse().unwrap();
+        apply(
+            &mut headers,
+            client,
+            false,
+            "http",
+            "example.com:8080",
+            Version::HTTP_11,
+            "charles",
+        );
+
+        assert_eq!(headers[&X_FORWARDED_FOR], "192.0.2.7");
+        assert_eq!(headers[&X_FORWARDED_PROTO], "http");
+        assert_eq!(headers[&X_FORWARDED_HOST], "example.com:8080");
+        assert_eq!(
+            headers[FORWARDED],
+            "for=192.0.2.7;host=\"example.com:8080\";proto=http"
+        );
+        assert_eq!(headers[VIA], "1.0 edge, 1.1 charles");
+    }
+
+    #[test]
+    fn test_trusted_proxy_headers_extended() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        let client: IpAddr = "10.1.2.3".parse().unwrap();
+        assert!(is_trusted(&trusted, client));
+        assert!(!is_trusted(&trusted, "11.0.0.1".parse().unwrap()));
+
+        let mut headers = spoofed();
+        apply(
+            &mut headers,
+            client,
+            true,
+            "http",
+            "example.com",
+            Version::HTTP_11,
+            "charles",
+        );
+        assert_eq!(headers[&X_FORWARDED_FOR], "1.2.3.4, 10.1.2.3");
+        assert_eq!(headers[&X_FORWARDED_PROTO], "https");
+        assert_eq!(
+            headers[FORWARDED],
+            "for=1.2.3.4, for=10.1.2.3;host=example.com;proto=http"
+        );
+    }
+
+    #[test]
+    fn test_ipv6_node_quoted() {
+        let mut headers = HeaderMap::new();
+        let client: IpAddr = "2001:db8::1".parse().unwrap();
+        apply(
+            &mut headers,
+            client,
+            false,
+            "http",
+            "example.com",
+            Version::HTTP_10,
+            "charles",
+        );
+        assert_eq!(
+            headers[FORWARDED],
+            "for=\"[2001:db8::1]\";host=example.com;proto=http"
+        );
+        assert_eq!(headers[VIA], "1.0 charles");
+    }
+
+    #[test]
+    fn test_loop_detection() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            VIA,
+            HeaderValue::from_static("1.0 edge (cache), 1.1 Charles"),
+        );
+        assert!(is_loop(&headers, "charles"));
+        assert!(!is_loop(&headers, "charles-2"));
+        assert!(!is_loop(&HeaderMap::new(), "charles"));
+    }
+}
@@ -0,0 +1,107 @@
+use crate::config::HeaderRules;
+use hyper::HeaderMap;
+use hyper::header::{HeaderName, HeaderValue};
+use tracing::warn;
+
+/// Per-request values available to header templates.
+pub struct TemplateContext<'a> {
+    pub client_ip: String,
+    pub route: &'a str,
+    pub request_id: &'a str,
+}
+
+impl TemplateContext<'_> {
+    pub fn render(&self, template: &str) -> String {
+        template
+            .replace("{client_ip}", &self.client_ip)
+            .replace("{route}", self.route)
+            .replace("{request_id}", self.request_id)
+    }
+}
+
+pub fn apply(rules: &HeaderRules, headers: &mut HeaderMap, ctx: &TemplateContext) {
+    for name in &rules.remove {
+        headers.remove(name.as_str());
+    }
+
+    for (from, to) in &rules.rename {
+        let Ok(to) = HeaderName::from_bytes(to.as_bytes()) else {
+            warn!("Ignoring invalid header name in rename: {}", to);
+            continue;
+        };
+        let values: Vec<HeaderValue> = headers.get_all(from.as_str()).iter().cloned().collect();
+        if values.is_empty() {
+            continue;
+        }
+        headers.remove(from.as_str());
+        for value in values {
+            headers.append(to.clone(), value);
+        }
+    }
+
+    for (name, template) in &rules.set {
+        if let Some((name, value)) = header(name, &ctx.render(template)) {
+            headers.insert(name, value);
+        }
+    }
+
+    for (name, template) in &rules.add {
+        if let Some((name, value)) = header(name, &ctx.render(template)) {
+            headers.append(name, value);
+        }
+    }
+}
+
+fn header(name: &str, value: &str) -> Option<(HeaderName, HeaderValue)> {
+    match (
+        HeaderName::from_bytes(name.as_bytes()),
+        HeaderValue::from_str(value),
+    ) {
+        (Ok(name), Ok(value)) => Some((name, value)),
+        _ => {
+            warn!("Ignoring invalid header rule: {}: {}", name, value);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_rules() {
+        let mut rules = HeaderRules::default();
+        rules.remove.push("cookie".to_string());
+        rules
+            .rename
+            .insert("x-old".to_string(), "x-new".to_string());
+        rules
+            .set
+            .insert("x-client".to_string(), "{client_ip}".to_string());
+        rules
+            .add
+            .insert("x-trace".to_string(), "{route}/{request_id}".to_string());
+
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", HeaderValue::from_static("a=1"));
+        headers.append("x-old", HeaderValue::from_static("1"));
+        headers.append("x-old", HeaderValue::from_static("2"));
+        headers.insert("x-client", HeaderValue::from_static("spoofed"));
+        headers.insert("x-trace", HeaderValue::from_static("upstream"));
+
+        let ctx = TemplateContext {
+            client_ip: "10.0.0.1".to_string(),
+            route: "api",
+            request_id: "abc",
+        };
+        apply(&rules, &mut headers, &ctx);
+
+        assert!(headers.get("cookie").is_none());
+        assert!(headers.get("x-old").is_none());
+        assert_eq!(headers.get_all("x-new").iter().count(), 2);
+        assert_eq!(headers["x-client"], "10.0.0.1");
+        let traces: Vec<_> = headers.get_all("x-trace").iter().collect();
+        assert_eq!(traces, ["upstream", "api/abc"]);
+    }
+}
@@ -0,0 +1,113 @@
+use hyper::HeaderMap;
+use hyper::header::{
+    CONNECTION, HeaderName, MAX_FORWARDS, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE, TRAILER,
+    TRANSFER_ENCODING, UPGRADE,
+};
+
+const KEEP_ALIVE: HeaderName = HeaderName::from_static("keep-alive");
+const PROXY_CONNECTION: HeaderName = HeaderName::from_static("proxy-connection");
+
+/// Connection-specific fields that must not be forwarded (RFC 9110 §7.6.1),
+/// plus the legacy `Keep-Alive`/`Proxy-Connection` fields.
+const HOP_BY_HOP: [HeaderName; 9] = [
+    CONNECTION,
+    KEEP_ALIVE,
+    PROXY_CONNECTION,
+    PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION,
+    TE,
+    TRAILER,
+    TRANSFER_ENCODING,
+    UPGRADE,
+];
+
+/// Removes hop-by-hop headers, including any named in `Connection`.
+pub fn strip(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+
+    for name in listed.iter().chain(HOP_BY_HOP.iter()) {
+        headers.remove(name);
+    }
+}
+
+/// Outcome of `Max-Forwards` processing for TRACE and OPTIONS.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MaxForwards {
+    /// Not limited, or another method: forward as-is.
+    Forward,
+    /// The limit is exhausted: this proxy must answer as the final recipient.
+    Respond,
+}
+
+/// Applies RFC 9110 §7.6.2: a TRACE or OPTIONS request with `Max-Forwards: 0`
+/// is answered here, otherwise the value is decremented before forwarding.
+pub fn max_forwards(method: &hyper::Method, headers: &mut HeaderMap) -> MaxForwards {
+    if method != hyper::Method::TRACE && method != hyper::Method::OPTIONS {
+        return MaxForwards::Forward;
+    }
+    let Some(remaining) = headers
+        .get(MAX_FORWARDS)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+    else {
+        return MaxForwards::Forward;
+    };
+
+    if remaining == 0 {
+        MaxForwards::Respond
+    } else {
+        headers.insert(MAX_FORWARDS, (remaining - 1).into());
+        MaxForwards::Forward
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Method;
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn test_strip_listed_and_standard_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONNECTION, HeaderValue::from_static("close, X-Session"));
+        headers.insert("x-session", HeaderValue::from_static("1"));
+        headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        headers.insert(TE, HeaderValue::from_static("trailers"));
+        headers.insert(PROXY_AUTHORIZATION, HeaderValue::from_static("Basic x"));
+        headers.insert("x-end-to-end", HeaderValue::from_static("kept"));
+
+        strip(&mut headers);
+
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["x-end-to-end"], "kept");
+    }
+
+    #[test]
+    fn test_max_forwards() {
+        let mut headers = HeaderMap::new();
+        headers.insert(MAX_FORWARDS, HeaderValue::from_static("2"));
+        assert_eq!(
+            max_forwards(&Method::TRACE, &mut headers),
+            MaxForwards::Forward
+        );
+        assert_eq!(headers[MAX_FORWARDS], "1");
+
+        headers.insert(MAX_FORWARDS, HeaderValue::from_static("0"));
+        assert_eq!(
+            max_forwards(&Method::OPTIONS, &mut headers),
+            MaxForwards::Respond
+        );
+        assert_eq!(
+            max_forwards(&Method::GET, &mut headers),
+            MaxForwards::Forward
+        );
+        assert_eq!(headers[MAX_FORWARDS], "0");
+    }
+}
@@ -1,10 +1,34 @@
+mod balancer;
+mod breakpoint;
+mod cache;
+mod canary;
+mod circuit;
+mod compression;
+mod concurrency;
 mod config;
 mod control;
+mod fault;
+mod forwarded;
+mod headers;
+mod hop_by_hop;
+mod map_local;
+mod map_remote;
+mod mirror;
+mod mitm;
 mod proxy;
+mod rate_limit;
+mod retry;
+mod router;
 mod state;
+mod sticky;
+mod tee;
+mod throttle;
+mod timeout;
 mod tui;
+mod upgrade;
+mod url_path;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use std::sync::Arc;
 use tracing::info;
@@ -32,6 +56,53 @@ enum Commands {
         #[arg(short, long, default_value = "http://127.0.0.1:9000")]
         control: String,
     },
+    /// Manage the CA used for SSL proxying
+    Ca {
+        #[command(subcommand)]
+        command: CaCommands,
+    },
+    /// Inspect route selection
+    Routes {
+        #[command(subcommand)]
+        command: RoutesCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum RoutesCommands {
+    /// Show which route a request would match and why
+    Explain {
+        /// Path to configuration file
+        #[arg(short, long, default_value = "config/charles.toml")]
+        config: String,
+        /// Host header of the request
+        #[arg(long)]
+        host: String,
+        /// Request path, optionally with a query string
+        #[arg(long)]
+        path: String,
+        #[arg(long, default_value = "GET")]
+        method: String,
+        /// Request header as `Name: value`; may be repeated
+        #[arg(long = "header")]
+        headers: Vec<String>,
+        /// Address the request comes from
+        #[arg(long, default_value = "127.0.0.1")]
+        client_ip: std::net::IpAddr,
+    },
+}
+
+#[derive(Subcommand)]
+enum CaCommands {
+    /// Write the CA certificate (PEM) for installing in trust stores
+    Export {
+        /// Path to configuration file
+        #[arg(short, long, default_value = "config/charles.toml")]
+        config: String,
+        /// Output file; prints to stdout if omitted
+        #[arg(short, long)]
+        out: Option<String>,
+    },
 }
 
 #[tokio::main]
@@ -41,7 +112,7 @@ async fn main() -> Result<()> {
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()),
         )
-        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
         .init();
 
     let cli = Cli::parse();
@@ -55,6 +126,24 @@ async fn main() -> Result<()> {
             info!("Starting Charles TUI");
             run_tui(control).await?;
         }
+        Commands::Ca {
+            command: CaCommands::Export { config, out },
+        } => {
+            export_ca(config, out)?;
+        }
+        Commands::Routes {
+            command:
+                RoutesCommands::Explain {
+                    config,
+                    host,
+                    path,
+                    method,
+                    headers,
+                    client_ip,
+                },
+        } => {
+            explain_route(config, &host, &path, &method, &headers, client_ip)?;
+        }
     }
 
     Ok(())
@@ -67,8 +156,11 @@ async fn run_server(config_path: String) -> Result<()> {
     let state = state::SharedState::new();
 
     // Start control server
-    let control_server =
-        control::ControlServer::new(config.control.listen.clone(), Arc::clone(&state));
+    let control_server = control::ControlServer::new(
+        config.control.listen.clone(),
+        config.timeouts.clone(),
+        Arc::clone(&state),
+    );
     let control_handle = tokio::spawn(async move {
         if let Err(e) = control_server.run().await {
             tracing::error!("Control server error: {}", e);
@@ -76,7 +168,7 @@ async fn run_server(config_path: String) -> Result<()> {
     });
 
     // Start proxy server
-    let proxy_server = proxy::ProxyServer::new(config, Arc::clone(&state));
+    let proxy_server = proxy::ProxyServer::new(config, Arc::clone(&state))?;
     let proxy_handle = tokio::spawn(async move {
         if let Err(e) = proxy_server.run().await {
             tracing::error!("Proxy server error: {}", e);
@@ -89,6 +181,94 @@ async fn run_server(config_path: String) -> Result<()> {
     Ok(())
 }
 
+fn export_ca(config_path: String, out: Option<String>) -> Result<()> {
+    let config = config::Config::from_file(&config_path)?;
+    let ca = mitm::CertificateAuthority::load_or_create(std::path::Path::new(&config.ca_dir))?;
+    match out {
+        Some(path) => {
+            std::fs::write(&path, ca.cert_pem())?;
+            info!("Wrote CA certificate to {}", path);
+        }
+        None => print!("{}", ca.cert_pem()),
+    }
+    Ok(())
+}
+
+fn explain_route(
+    config_path: String,
+    host: &str,
+    path: &str,
+    method: &str,
+    headers: &[String],
+    client_ip: std::net::IpAddr,
+) -> Result<()> {
+    let config = config::Config::from_file(&config_path)?;
+    // Routes are chosen by the normalized path, as the proxy does.
+    let (raw_path, query) = match path.split_once('?') {
+        Some((raw_path, query)) => (raw_path, Some(query)),
+        None => (path, None),
+    };
+    let normalized = match url_path::normalize(raw_path) {
+        Ok(normalized) => normalized,
+        Err(e) => {
+            println!("{} {}{} from {}", method, host, path, client_ip);
+            println!();
+            println!("rejected: {}; the proxy answers 400.", e);
+            return Ok(());
+        }
+    };
+    let path = match query {
+        Some(query) => format!("{}?{}", normalized, query),
+        None => normalized,
+    };
+    let mut req = hyper::Request::builder().method(method).uri(&path);
+    for header in headers {
+        let (name, value) = header
+            .split_once(':')
+            .with_context(|| format!("Header {:?} is not `Name: value`", header))?;
+        req = req.header(name.trim(), value.trim());
+    }
+    let req = req.body(())?;
+
+    let matcher = router::RouteMatcher::new(config.routes);
+    let explanation = matcher.explain(&req, host, client_ip);
+    println!("{} {}{} from {}", method, host, path, client_ip);
+    println!();
+    if explanation.candidates.is_empty() {
+        println!("No route has a matching host and path prefix.");
+    }
+    let mut chosen = false;
+    for (rank, candidate) in explanation.candidates.iter().enumerate() {
+        let route = candidate.route;
+        let verdict = match (&candidate.rejected, chosen) {
+            (Some(rejection), _) => format!("skipped: {}", rejection),
+            (None, false) => {
+                chosen = true;
+                "MATCH".to_string()
+            }
+            (None, true) => "shadowed".to_string(),
+        };
+        println!(
+            "{:>2}. {:<20} priority {}, {}, prefix {:?}  {}",
+            rank + 1,
+            route.name,
+            route.priority,
+            candidate.host,
+            route.path_prefix,
+            verdict
+        );
+    }
+    for (route, reason) in &explanation.unmatched {
+        println!("  - {:<20} {}", route.name, reason);
+    }
+    println!();
+    match explanation.selected() {
+        Some(route) => println!("Selected route: {}", route.name),
+        None => println!("No route matches; the proxy answers 404."),
+    }
+    Ok(())
+}
+
 async fn run_tui(control_url: String) -> Result<()> {
     let mut app = tui::TuiApp::new(control_url);
     app.run().await?;
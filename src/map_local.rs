@@ -0,0 +1,138 @@
+use crate::config::{MapLocal, MapLocalRule};
+use crate::proxy::host_matches;
+use crate::url_path;
+use bytes::Bytes;
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE, HeaderName, HeaderValue};
+use hyper::{Response, StatusCode};
+use std::io;
+use std::path::PathBuf;
+use tracing::warn;
+
+pub fn find_rule<'a>(
+    rules: &'a [MapLocalRule],
+    host: &str,
+    path: &str,
+) -> Option<&'a MapLocalRule> {
+    rules.iter().find(|rule| {
+        rule.host.as_ref().is_none_or(|h| host_matches(h, host))
+            && rule
+                .path_prefix
+                .as_ref()
+                .is_none_or(|p| url_path::has_prefix(path, p))
+    })
+}
+
+/// Resolves the file to serve. `rest` is the request path after the matched
+/// prefix and is only used when the mapping points at a directory. Each
+/// segment is percent-decoded before it is checked, so `%2e%2e` or `%2f`
+/// cannot climb out of the directory.
+pub async fn resolve(map: &MapLocal, rest: &str) -> io::Result<PathBuf> {
+    let mut file = PathBuf::from(&map.path);
+    if tokio::fs::metadata(&file).await?.is_dir() {
+        for segment in rest.split('/') {
+            let denied = || io::Error::from(io::ErrorKind::PermissionDenied);
+            let segment = url_path::decode(segment).ok_or_else(denied)?;
+            match segment.as_str() {
+                "" | "." => {}
+                s if s == ".." || s.contains(['/', '\\', ':', '\0']) => return Err(denied()),
+                s => file.push(s),
+            }
+        }
+        if tokio::fs::metadata(&file).await?.is_dir() {
+            file.push("index.html");
+        }
+    }
+    Ok(file)
+}
+
+pub async fn load(map: &MapLocal, rest: &str) -> io::Result<Response<Bytes>> {
+    let file = resolve(map, rest).await?;
+    let body = Bytes::from(tokio::fs::read(&file).await?);
+
+    let content_type = map.content_type.clone().unwrap_or_else(|| {
+        mime_guess::from_path(&file)
+            .first_or_octet_stream()
+            .to_string()
+    });
+    let status = map
+        .status
+        .and_then(|s| StatusCode::from_u16(s).ok())
+        .unwrap_or(StatusCode::OK);
+
+    let mut response = Response::new(Bytes::new());
+    *response.status_mut() = status;
+    let headers = response.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&content_type) {
+        headers.insert(CONTENT_TYPE, value);
+    }
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    for (name, value) in &map.headers {
+        match (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            (Ok(name), Ok(value)) => {
+                headers.insert(name, value);
+            }
+            _ => warn!("Ignoring invalid map_local header: {}: {}", name, value),
+        }
+    }
+    *response.body_mut() = body;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn mapping(path: &std::path::Path) -> MapLocal {
+        MapLocal {
+            path: path.to_string_lossy().into_owned(),
+            status: None,
+            content_type: None,
+            headers: BTreeMap::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_directory_mapping() {
+        let dir = std::env::temp_dir().join(format!("charles-map-local-{}", std::process::id()));
+        tokio::fs::create_dir_all(dir.join("docs")).await.unwrap();
+        tokio::fs::write(dir.join("app.json"), "{}").await.unwrap();
+        tokio::fs::write(dir.join("docs/index.html"), "<h1>")
+            .await
+            .unwrap();
+
+        let mut map = mapping(&dir);
+        map.headers.insert("X-Stub".to_string(), "1".to_string());
+
+        let response = load(&map, "/app.json").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(response.headers()["x-stub"], "1");
+        assert_eq!(response.body().as_ref(), b"{}");
+
+        let response = load(&map, "/docs/").await.unwrap();
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/html");
+
+        let err = load(&map, "/../etc/passwd").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        for escape in [
+            "/%2e%2e/secret",
+            "/docs%2f..%2f..%2fsecret",
+            "/a%5c..",
+            "/c%3a",
+            "/a%00",
+        ] {
+            let err = load(&map, escape).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied, "{escape}");
+        }
+        let response = load(&map, "/d%6fcs/index.html").await.unwrap();
+        assert_eq!(response.body().as_ref(), b"<h1>");
+        let err = load(&map, "/missing.txt").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,242 @@
+use crate::config::MapRemoteRule;
+use crate::proxy::host_matches;
+use crate::url_path;
+use anyhow::{Context, Result};
+use parking_lot::RwLock;
+use regex::Regex;
+
+struct CompiledRule {
+    rule: MapRemoteRule,
+    path: Option<Regex>,
+    query: Option<Regex>,
+}
+
+/// Where a matched request should go instead of its route's upstreams.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapRemoteTarget {
+    pub uri: String,
+    pub preserve_host: bool,
+}
+
+pub struct MapRemoteManager {
+    rules: RwLock<Vec<CompiledRule>>,
+}
+
+impl MapRemoteManager {
+    pub fn new() -> Self {
+        Self {
+            rules: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Replaces all rules, failing without changes if a pattern is invalid.
+    pub fn set_rules(&self, rules: Vec<MapRemoteRule>) -> Result<()> {
+        let compiled = rules.into_iter().map(compile).collect::<Result<Vec<_>>>()?;
+        *self.rules.write() = compiled;
+        Ok(())
+    }
+
+    pub fn get_rules(&self) -> Vec<MapRemoteRule> {
+        self.rules.read().iter().map(|c| c.rule.clone()).collect()
+    }
+
+    pub fn set_enabled(&self, index: usize, enabled: bool) -> bool {
+        match self.rules.write().get_mut(index) {
+            Some(compiled) => {
+                compiled.rule.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the rewritten destination for the first enabled matching rule.
+    pub fn rewrite(&self, host: &str, path: &str, query: Option<&str>) -> Option<MapRemoteTarget> {
+        self.rules
+            .read()
+            .iter()
+            .filter(|c| c.rule.enabled)
+            .find_map(|c| apply(c, host, path, query))
+    }
+}
+
+impl Default for MapRemoteManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn compile(rule: MapRemoteRule) -> Result<CompiledRule> {
+    let path = rule
+        .path
+        .as_deref()
+        .map(Regex::new)
+        .transpose()
+        .with_context(|| format!("Invalid map_remote path pattern for {}", rule.target))?;
+    let query = rule
+        .query
+        .as_deref()
+        .map(Regex::new)
+        .transpose()
+        .with_context(|| format!("Invalid map_remote query pattern for {}", rule.target))?;
+    Ok(CompiledRule { rule, path, query })
+}
+
+fn apply(
+    compiled: &CompiledRule,
+    host: &str,
+    path: &str,
+    query: Option<&str>,
+) -> Option<MapRemoteTarget> {
+    if !compiled
+        .rule
+        .host
+        .as_ref()
+        .is_none_or(|h| host_matches(h, host))
+    {
+        return None;
+    }
+    if let Some(pattern) = &compiled.query
+        && !pattern.is_match(query.unwrap_or(""))
+    {
+        return None;
+    }
+
+    let mut uri = match &compiled.path {
+        Some(pattern) => {
+            let captures = pattern.captures(path)?;
+            let mut expanded = String::new();
+            captures.expand(&compiled.rule.target, &mut expanded);
+            expanded
+        }
+        None => append_path(&compiled.rule.target, path),
+    };
+
+    // Keep the original query unless the target sets its own.
+    if let Some(query) = query
+        && !uri.contains('?')
+    {
+        uri.push('?');
+        uri.push_str(query);
+    }
+
+    Some(MapRemoteTarget {
+        uri,
+        preserve_host: compiled.rule.preserve_host,
+    })
+}
+
+/// Without a `path` pattern the target is a base that the request path
+/// is appended to, ahead of any query the target carries.
+fn append_path(target: &str, path: &str) -> String {
+    let (base, query) = match target.split_once('?') {
+        Some((base, query)) => (base, Some(query)),
+        None => (target, None),
+    };
+    let authority_start = base.find("://").map_or(0, |scheme| scheme + 3);
+    let path_start = base[authority_start..]
+        .find('/')
+        .map_or(base.len(), |i| authority_start + i);
+    let mut uri = format!(
+        "{}{}",
+        &base[..path_start],
+        url_path::join(&base[path_start..], path.trim_start_matches('/'))
+    );
+    if let Some(query) = query {
+        uri.push('?');
+        uri.push_str(query);
+    }
+    uri
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(host: Option<&str>, path: Option<&str>, target: &str) -> MapRemoteRule {
+        MapRemoteRule {
+            name: None,
+            enabled: true,
+            host: host.map(str::to_string),
+            path: path.map(str::to_string),
+            query: None,
+            target: target.to_string(),
+            preserve_host: false,
+        }
+    }
+
+    #[test]
+    fn test_rewrite_with_captures() {
+        let manager = MapRemoteManager::new();
+        manager
+            .set_rules(vec![rule(
+                Some("api.example.com"),
+                Some("^/api/v2/(?P<rest>.*)$"),
+                "http://127.0.0.1:3000/${rest}",
+            )])
+            .unwrap();
+
+        let target = manager
+            .rewrite("api.example.com", "/api/v2/users/7", Some("x=1"))
+            .unwrap();
+        assert_eq!(target.uri, "http://127.0.0.1:3000/users/7?x=1");
+        assert!(
+            manager
+                .rewrite("api.example.com", "/api/v1/users", None)
+                .is_none()
+        );
+        assert!(
+            manager
+                .rewrite("other.com", "/api/v2/users", None)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_host_only_rule_keeps_path() {
+        let manager = MapRemoteManager::new();
+        manager
+            .set_rules(vec![
+                rule(Some("api.example.com"), None, "http://127.0.0.1:3000"),
+                rule(Some("cdn.example.com"), None, "https://mirror.test/cdn/"),
+            ])
+            .unwrap();
+
+        let target = manager
+            .rewrite("api.example.com", "/users/7", Some("x=1"))
+            .unwrap();
+        assert_eq!(target.uri, "http://127.0.0.1:3000/users/7?x=1");
+        let target = manager.rewrite("api.example.com", "/", None).unwrap();
+        assert_eq!(target.uri, "http://127.0.0.1:3000/");
+        let target = manager
+            .rewrite("cdn.example.com", "/img/a.png", None)
+            .unwrap();
+        assert_eq!(target.uri, "https://mirror.test/cdn/img/a.png");
+    }
+
+    #[test]
+    fn test_query_condition_and_toggle() {
+        let manager = MapRemoteManager::new();
+        let mut with_query = rule(None, None, "http://127.0.0.1:3000/debug?on=1");
+        with_query.query = Some("(^|&)debug=1(&|$)".to_string());
+        manager.set_rules(vec![with_query]).unwrap();
+
+        let target = manager.rewrite("a", "/", Some("debug=1")).unwrap();
+        assert_eq!(target.uri, "http://127.0.0.1:3000/debug?on=1");
+        assert!(manager.rewrite("a", "/", Some("debug=0")).is_none());
+
+        assert!(manager.set_enabled(0, false));
+        assert!(manager.rewrite("a", "/", Some("debug=1")).is_none());
+        assert!(!manager.set_enabled(1, true));
+    }
+
+    #[test]
+    fn test_invalid_pattern_rejected() {
+        let manager = MapRemoteManager::new();
+        assert!(
+            manager
+                .set_rules(vec![rule(None, Some("("), "http://x")])
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,41 @@
+use crate::config::Mirror;
+use bytes::{Bytes, BytesMut};
+use http_body_util::BodyExt;
+use hyper::body::Body;
+
+/// Whether this request gets a shadow copy.
+pub fn sampled(mirror: &Mirror) -> bool {
+    rand::random_range(0.0..100.0) < mirror.percent
+}
+
+/// Reads a response body of at most `limit` bytes; `None` when it is
+/// longer.
+pub async fn read_capped<B>(mut body: B, limit: u64) -> Result<Option<Bytes>, B::Error>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    let mut read = BytesMut::new();
+    while let Some(frame) = body.frame().await {
+        if let Ok(data) = frame?.into_data() {
+            if (read.len() + data.len()) as u64 > limit {
+                return Ok(None);
+            }
+            read.extend_from_slice(&data);
+        }
+    }
+    Ok(Some(read.freeze()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Full;
+
+    #[tokio::test]
+    async fn test_read_capped() {
+        let body = || Full::new(Bytes::from("hello"));
+        let read = read_capped(body(), 5).await.unwrap();
+        assert_eq!(read.as_deref(), Some(&b"hello"[..]));
+        assert_eq!(read_capped(body(), 4).await.unwrap(), None);
+    }
+}
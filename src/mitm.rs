@@ -0,0 +1,225 @@
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair,
+    KeyUsagePurpose,
+};
+use rustls::ServerConfig;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use time::{Duration, OffsetDateTime};
+use tracing::info;
+
+const CERT_FILE: &str = "charles-ca.pem";
+const KEY_FILE: &str = "charles-ca.key";
+/// Leaf configurations kept before the least recently used are dropped.
+const MAX_LEAVES: usize = 1_000;
+
+/// Minted leaf configurations by host, with least recently used eviction.
+#[derive(Default)]
+struct Leaves {
+    configs: HashMap<String, (Arc<ServerConfig>, u64)>,
+    /// Hosts by last use, oldest first.
+    recency: BTreeMap<u64, String>,
+    next_use: u64,
+}
+
+impl Leaves {
+    fn get(&mut self, host: &str) -> Option<Arc<ServerConfig>> {
+        let (config, used) = self.configs.get_mut(host)?;
+        self.recency.remove(used);
+        *used = self.next_use;
+        self.recency.insert(self.next_use, host.to_string());
+        self.next_use += 1;
+        Some(Arc::clone(config))
+    }
+
+    fn insert(&mut self, host: &str, config: Arc<ServerConfig>) {
+        if let Some((_, used)) = self.configs.remove(host) {
+            self.recency.remove(&used);
+        }
+        while self.configs.len() >= MAX_LEAVES
+            && let Some((_, oldest)) = self.recency.pop_first()
+        {
+            self.configs.remove(&oldest);
+        }
+        self.configs
+            .insert(host.to_string(), (config, self.next_use));
+        self.recency.insert(self.next_use, host.to_string());
+        self.next_use += 1;
+    }
+}
+
+/// Root CA used to mint leaf certificates for intercepted hosts.
+pub struct CertificateAuthority {
+    issuer: Issuer<'static, KeyPair>,
+    cert_pem: String,
+    cert_der: CertificateDer<'static>,
+    leaves: Mutex<Leaves>,
+}
+
+impl CertificateAuthority {
+    /// Loads the CA from `dir`, generating and saving a new one on first use.
+    pub fn load_or_create(dir: &Path) -> Result<Self> {
+        let cert_path = dir.join(CERT_FILE);
+        let key_path = dir.join(KEY_FILE);
+
+        if cert_path.exists() && key_path.exists() {
+            let cert_pem = fs::read_to_string(&cert_path)
+                .with_context(|| format!("reading {}", cert_path.display()))?;
+            let key = KeyPair::from_pem(&fs::read_to_string(&key_path)?)
+                .with_context(|| format!("parsing {}", key_path.display()))?;
+            let issuer = Issuer::from_ca_cert_pem(&cert_pem, key)
+                .with_context(|| format!("parsing {}", cert_path.display()))?;
+            return Self::new(issuer, cert_pem);
+        }
+
+        let key = KeyPair::generate()?;
+        let mut params = CertificateParams::default();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "Charles Proxy CA");
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, "Charles");
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::CrlSign,
+            KeyUsagePurpose::DigitalSignature,
+        ];
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - Duration::days(1);
+        params.not_after = now + Duration::days(3650);
+        let cert = params.self_signed(&key)?;
+
+        create_private_dir(dir).with_context(|| format!("creating {}", dir.display()))?;
+        fs::write(&cert_path, cert.pem())?;
+        write_private(&key_path, key.serialize_pem().as_bytes())
+            .with_context(|| format!("writing {}", key_path.display()))?;
+        info!("Generated CA certificate at {}", cert_path.display());
+
+        Self::new(Issuer::new(params, key), cert.pem())
+    }
+
+    fn new(issuer: Issuer<'static, KeyPair>, cert_pem: String) -> Result<Self> {
+        let cert_der = CertificateDer::from_pem_slice(cert_pem.as_bytes())?;
+        Ok(Self {
+            issuer,
+            cert_pem,
+            cert_der,
+            leaves: Mutex::new(Leaves::default()),
+        })
+    }
+
+    /// The CA certificate, PEM encoded, for installing in trust stores.
+    pub fn cert_pem(&self) -> &str {
+        &self.cert_pem
+    }
+
+    /// TLS settings presenting a certificate for `host`, minted on first use.
+    pub fn server_config(&self, host: &str) -> Result<Arc<ServerConfig>> {
+        if let Some(config) = self.leaves.lock().get(host) {
+            return Ok(config);
+        }
+
+        let key = KeyPair::generate()?;
+        let mut params = CertificateParams::new(vec![host.to_string()])?;
+        params.distinguished_name.push(DnType::CommonName, host);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params.use_authority_key_identifier_extension = true;
+        // Some clients reject leaf certificates valid for more than 398 days.
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - Duration::days(1);
+        params.not_after = now + Duration::days(365);
+        let cert = params.signed_by(&key, &self.issuer)?;
+
+        let chain = vec![cert.der().clone(), self.cert_der.clone()];
+        let key = PrivateKeyDer::Pkcs8(key.serialize_der().into());
+        let mut config =
+            ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()?
+                .with_no_client_auth()
+                .with_single_cert(chain, key)?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let config = Arc::new(config);
+        self.leaves.lock().insert(host, Arc::clone(&config));
+        Ok(config)
+    }
+}
+
+/// Creates `dir` readable only by its owner, as it holds the CA key.
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir)
+}
+
+/// Writes a file only its owner can read, so no other local user can
+/// sign certificates with the CA key.
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ca_persisted_and_leaves_cached() {
+        let dir = std::env::temp_dir().join(format!("charles-ca-{}", uuid::Uuid::new_v4()));
+
+        let ca = CertificateAuthority::load_or_create(&dir).unwrap();
+        assert!(ca.cert_pem().starts_with("-----BEGIN CERTIFICATE-----"));
+        let first = ca.server_config("example.com").unwrap();
+        let again = ca.server_config("example.com").unwrap();
+        assert!(Arc::ptr_eq(&first, &again));
+        assert!(ca.server_config("127.0.0.1").is_ok());
+
+        let reloaded = CertificateAuthority::load_or_create(&dir).unwrap();
+        assert_eq!(reloaded.cert_pem(), ca.cert_pem());
+        assert!(reloaded.server_config("example.com").is_ok());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&dir), 0o700);
+            assert_eq!(mode(&dir.join(KEY_FILE)), 0o600);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_leaves_evict_least_recently_used() {
+        let dir = std::env::temp_dir().join(format!("charles-ca-{}", uuid::Uuid::new_v4()));
+        let ca = CertificateAuthority::load_or_create(&dir).unwrap();
+        let config = ca.server_config("example.com").unwrap();
+
+        let mut leaves = Leaves::default();
+        for i in 0..MAX_LEAVES {
+            leaves.insert(&format!("{i}.example.com"), Arc::clone(&config));
+        }
+        assert!(leaves.get("0.example.com").is_some());
+        leaves.insert("new.example.com", config);
+
+        assert_eq!(leaves.configs.len(), MAX_LEAVES);
+        assert!(leaves.get("0.example.com").is_some());
+        assert!(leaves.get("1.example.com").is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
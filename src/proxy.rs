@@ -1,70 +1,201 @@
-use crate::config::{Config, Route, Upstream};
-use crate::state::{RequestLog, SharedState};
+use crate::balancer;
+use crate::breakpoint::{
+    BreakpointDecision, BreakpointPhase, PendingBreakpoint, header_map, header_pairs,
+};
+use crate::cache::{self, CacheStatus, Lookup};
+use crate::canary::Group;
+use crate::circuit::{self, Admission, CircuitState};
+use crate::compression;
+use crate::concurrency::{self, PermitBody};
+use crate::config::{Config, Fault, ForwardProxyConfig, MapLocal, Mirror, Route};
+use crate::fault::TruncatedBody;
+use crate::forwarded;
+use crate::headers::{self, TemplateContext};
+use crate::hop_by_hop::{self, MaxForwards};
+use crate::map_local;
+use crate::mirror;
+use crate::mitm::CertificateAuthority;
+use crate::rate_limit;
+use crate::retry::{self, Failure, Replay};
+use crate::router::{RouteMatch, RouteMatcher};
+use crate::state::{MirrorDiff, RequestLog, SharedState, TunnelLog, UpstreamStatus};
+use crate::sticky;
+use crate::tee::{Captured, TeeBody};
+use crate::throttle::{self, ThrottledBody};
+use crate::timeout::{self, Activity, ActivityIo, Stage, TimeoutBody, Timeouts};
+use crate::upgrade;
+use crate::url_path;
 use anyhow::Result;
 use bytes::Bytes;
 use chrono::Utc;
 use http_body_util::{BodyExt, Full, combinators::BoxBody};
 use hyper::body::Incoming;
+use hyper::header::HeaderValue;
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper::{Method, Request, Response, StatusCode};
-use hyper_util::rt::TokioIo;
-use std::net::SocketAddr;
+use hyper::upgrade::OnUpgrade;
+use hyper::{HeaderMap, Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
 use std::sync::Arc;
-use std::time::Instant;
-use tokio::net::TcpListener;
-use tracing::{error, info, warn};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tracing::{debug, error, info, warn};
 
 type GenericError = Box<dyn std::error::Error + Send + Sync>;
 type BoxedBody = BoxBody<Bytes, GenericError>;
 
-pub struct ProxyServer {
-    config: Config,
-    state: Arc<SharedState>,
-    route_matcher: Arc<RouteMatcher>,
+/// Payload bytes kept per captured WebSocket frame.
+const WEBSOCKET_PREVIEW_BYTES: usize = 1024;
+
+/// Response extension asking `handle_request` to drop the connection; also
+/// the service error that makes hyper do so.
+#[derive(Debug, Clone, Copy)]
+struct ResetConnection;
+
+impl std::fmt::Display for ResetConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "connection reset by fault injection")
+    }
 }
 
-struct RouteMatcher {
-    routes: Vec<Route>,
+impl std::error::Error for ResetConnection {}
+
+/// Which listener a request arrived on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Listener {
+    /// Origin-form requests routed by `Host`.
+    Reverse,
+    /// Absolute-form and CONNECT requests from clients configured to use a proxy.
+    Forward,
 }
 
-impl RouteMatcher {
-    fn new(routes: Vec<Route>) -> Self {
-        Self { routes }
-    }
+/// Why an upstream exchange produced no response.
+#[derive(Debug)]
+enum UpstreamError {
+    InvalidUri(hyper::http::uri::InvalidUri),
+    TimedOut(Stage),
+    Failed(hyper_util::client::legacy::Error),
+}
 
-    fn find_route(&self, host: &str, path: &str) -> Option<&Route> {
-        for route in &self.routes {
-            if self.matches_host(&route.hosts, host) && path.starts_with(&route.path_prefix) {
-                return Some(route);
-            }
+impl UpstreamError {
+    /// The status a client would have been answered with.
+    fn status(&self) -> StatusCode {
+        match self {
+            UpstreamError::TimedOut(stage) => stage.status(),
+            _ => StatusCode::BAD_GATEWAY,
         }
-        None
     }
+}
 
-    fn matches_host(&self, patterns: &[String], host: &str) -> bool {
-        for pattern in patterns {
-            if pattern.starts_with("*.") {
-                let suffix = &pattern[2..];
-                if host.ends_with(suffix) || (suffix.len() > 1 && host == &suffix[1..]) {
-                    return true;
-                }
-            } else if pattern == host {
-                return true;
-            }
+impl std::fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpstreamError::InvalidUri(e) => write!(f, "invalid URI: {}", e),
+            UpstreamError::TimedOut(stage) => write!(f, "{} timeout", stage),
+            UpstreamError::Failed(e) => write!(f, "{}", e),
         }
-        false
+    }
+}
+
+type HttpClient = hyper_util::client::legacy::Client<
+    hyper_rustls::HttpsConnector<hyper_util::client::legacy::connect::HttpConnector>,
+    BoxedBody,
+>;
+
+pub struct ProxyServer {
+    config: Config,
+    state: Arc<SharedState>,
+    route_matcher: Arc<RouteMatcher>,
+    /// One client per distinct connect timeout in use.
+    clients: HashMap<Option<Duration>, HttpClient>,
+    /// Loaded by `serve` when the forward proxy intercepts any hosts.
+    ca: Option<Arc<CertificateAuthority>>,
+}
+
+fn build_client(connect_timeout: Option<Duration>) -> HttpClient {
+    let mut http = hyper_util::client::legacy::connect::HttpConnector::new();
+    http.enforce_http(false);
+    http.set_connect_timeout(connect_timeout);
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_provider_and_webpki_roots(rustls::crypto::ring::default_provider())
+        .expect("ring supports the default TLS versions")
+        .https_or_http()
+        .enable_http1()
+        .wrap_connector(http);
+    hyper_util::client::legacy::Client::builder(hyper_util::rt::TokioExecutor::new())
+        .build(connector)
+}
+
+/// Lowercases `host` and drops a trailing dot, the form patterns are
+/// written in.
+pub fn normalize_host(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Matches a host against an exact name or a `*.suffix` wildcard.
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    if let Some(suffix) = pattern.strip_prefix("*.") {
+        host.strip_suffix(suffix)
+            .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+    } else {
+        pattern == host
     }
 }
 
 impl ProxyServer {
-    pub fn new(config: Config, state: Arc<SharedState>) -> Self {
+    /// Fails if the Map Remote patterns or throttle profiles are invalid.
+    pub fn new(config: Config, state: Arc<SharedState>) -> Result<Self> {
         let route_matcher = Arc::new(RouteMatcher::new(config.routes.clone()));
-        Self {
+        // Routes sharing an upstream URL share its status, as they do its
+        // circuit breaker.
+        let mut seen = HashSet::new();
+        state.update_upstream_status(
+            config
+                .routes
+                .iter()
+                .flat_map(Route::all_upstreams)
+                .filter(|upstream| seen.insert(upstream.url.as_str()))
+                .map(|upstream| UpstreamStatus {
+                    url: upstream.url.clone(),
+                    healthy: true,
+                    failures: 0,
+                    requests: 0,
+                    circuit: CircuitState::Closed,
+                    in_flight: 0,
+                    latency_ewma_ms: None,
+                })
+                .collect(),
+        );
+        state.faults.set_rules(&config.routes);
+        state.circuits.configure(&config.routes);
+        state.balancers.configure(&config.routes);
+        state.canary.configure(&config.routes);
+        state.cache.configure(&config.routes);
+        state
+            .concurrency
+            .configure(&config.routes, config.max_connections);
+        state
+            .breakpoints
+            .set_rules(config.breakpoints.rules.clone());
+        state.map_remote.set_rules(config.map_remote.clone())?;
+        state.throttle.configure(&config.throttle, &config.routes)?;
+        let clients = std::iter::once(None)
+            .chain(config.routes.iter().map(|route| Some(&route.timeouts)))
+            .map(|route| Timeouts::resolve(&config.timeouts, route).connect)
+            .map(|connect| (connect, build_client(connect)))
+            .collect();
+
+        Ok(Self {
             config,
             state,
             route_matcher,
-        }
+            clients,
+            ca: None,
+        })
     }
 
     pub async fn run(self) -> Result<()> {
@@ -72,126 +203,1110 @@ impl ProxyServer {
         let listener = TcpListener::bind(addr).await?;
         info!("Proxy server listening on {}", addr);
 
+        let forward_listener = match &self.config.forward_proxy {
+            Some(forward) => {
+                let addr: SocketAddr = forward.listen.parse()?;
+                let listener = TcpListener::bind(addr).await?;
+                info!("Forward proxy listening on {}", addr);
+                Some(listener)
+            }
+            None => None,
+        };
+
+        self.serve(listener, forward_listener).await
+    }
+
+    /// Accepts connections on already bound listeners.
+    pub async fn serve(
+        mut self,
+        listener: TcpListener,
+        forward_listener: Option<TcpListener>,
+    ) -> Result<()> {
+        if self
+            .config
+            .forward_proxy
+            .as_ref()
+            .is_some_and(|forward| !forward.ssl_proxying.is_empty())
+        {
+            let ca = CertificateAuthority::load_or_create(Path::new(&self.config.ca_dir))?;
+            self.ca = Some(Arc::new(ca));
+        }
         let server = Arc::new(self);
 
+        if let Some(forward_listener) = forward_listener {
+            let server = Arc::clone(&server);
+            tokio::spawn(async move {
+                if let Err(e) = server.accept(forward_listener, Listener::Forward).await {
+                    error!("Forward proxy error: {}", e);
+                }
+            });
+        }
+
+        server.accept(listener, Listener::Reverse).await
+    }
+
+    async fn accept(self: Arc<Self>, listener: TcpListener, kind: Listener) -> Result<()> {
+        let server = self;
+
         loop {
+            // At the connection cap stop accepting, leaving new clients in
+            // the listen backlog until a connection closes.
+            let connection = server.state.concurrency.acquire_connection().await;
             let (stream, remote_addr) = listener.accept().await?;
+            let (stream, activity) = ActivityIo::new(stream);
             let io = TokioIo::new(stream);
+            let server = Arc::clone(&server);
             let server_clone = Arc::clone(&server);
 
             tokio::task::spawn(async move {
+                let _connection = connection;
                 let service = service_fn(move |req| {
                     let server = Arc::clone(&server_clone);
-                    async move { server.handle_request(req, remote_addr).await }
+                    async move { server.handle_request(req, remote_addr, kind).await }
                 });
 
-                if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+                if let Err(err) = server
+                    .http1_builder()
+                    .serve_connection(io, service)
+                    .with_upgrades()
+                    .await
+                    && server.count_connection_error(&err, &activity)
+                {
                     error!("Error serving connection: {}", err);
                 }
             });
         }
     }
 
+    /// Connection settings shared by every client-facing listener. Clients
+    /// too slow to send their headers are disconnected without a response.
+    fn http1_builder(&self) -> http1::Builder {
+        let mut builder = http1::Builder::new();
+        builder
+            .timer(TokioTimer::new())
+            .header_read_timeout(timeout::header_read(&self.config.timeouts));
+        builder
+    }
+
+    /// Counts a header-read timeout against the client only when part of a
+    /// request had arrived; otherwise an idle keep-alive connection just
+    /// closed. Returns whether `err` was a real error.
+    fn count_connection_error(&self, err: &hyper::Error, activity: &Activity) -> bool {
+        if !err.is_timeout() {
+            return true;
+        }
+        if !activity.request_pending() {
+            debug!("Closed idle keep-alive connection");
+            return false;
+        }
+        self.state.record_timeout(Stage::HeaderRead);
+        true
+    }
+
     async fn handle_request(
-        &self,
+        self: &Arc<Self>,
         req: Request<Incoming>,
-        _remote_addr: SocketAddr,
-    ) -> Result<Response<BoxedBody>, std::convert::Infallible> {
+        remote_addr: SocketAddr,
+        listener: Listener,
+    ) -> Result<Response<BoxedBody>, ResetConnection> {
         let start = Instant::now();
         self.state.increment_total_requests();
         self.state.increment_active_requests();
 
-        let host = req
-            .headers()
-            .get("host")
-            .and_then(|h| h.to_str().ok())
-            .unwrap_or("unknown")
-            .to_string();
-        let path = req.uri().path().to_string();
+        let host = match (listener, req.uri().authority()) {
+            (Listener::Forward, Some(authority)) => authority.to_string(),
+            _ => req
+                .headers()
+                .get("host")
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or("unknown")
+                .to_string(),
+        };
+        let mut path = req.uri().path().to_string();
         let method = req.method().clone();
 
         info!("Received request: {} {} Host: {}", method, path, host);
 
-        let result = match self.route_matcher.find_route(&host, &path) {
-            Some(route) => {
-                let upstream = self.select_upstream(&route.upstreams);
-                match upstream {
-                    Some(upstream_url) => {
-                        let response = self.proxy_request(req, route, &upstream_url).await;
-                        let status = response.status().as_u16();
+        let request_id = uuid::Uuid::new_v4().to_string();
+        let mut req = req.map(|body| body.map_err(|e| Box::new(e) as GenericError).boxed());
 
-                        self.log_request(
-                            method,
-                            path,
-                            host,
-                            status,
-                            start.elapsed().as_millis() as u64,
-                            upstream_url,
-                        );
+        let client_ip = remote_addr.ip();
 
+        // Everything downstream, the upstream included, sees the normalized
+        // path, so `/api/../admin` cannot slip past the `/admin` route.
+        let mut path_escapes = false;
+        if listener == Listener::Reverse {
+            match url_path::normalize(&path) {
+                Ok(normalized) if normalized != path => {
+                    set_path(&mut req, &normalized);
+                    path = normalized;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Rejecting path {}: {}", path, e);
+                    path_escapes = true;
+                }
+            }
+        }
+
+        let matched = match listener {
+            Listener::Reverse if !path_escapes => {
+                self.route_matcher.find_route(&req, &host, client_ip)
+            }
+            _ => None,
+        };
+        let route = matched.map(|matched| matched.route);
+        let route_name = route.map(|route| route.name.as_str());
+        let mut timeouts =
+            Timeouts::resolve(&self.config.timeouts, route.map(|route| &route.timeouts));
+        if let Some(idle) = timeouts.body_idle {
+            let state = Arc::clone(&self.state);
+            req = req.map(|body| {
+                TimeoutBody::new(body, Some(idle), None, Stage::RequestBody, Some(state)).boxed()
+            });
+        }
+        let throttle = self.state.throttle.profile_for(route_name);
+        if let Some(profile) = &throttle {
+            tokio::time::sleep(throttle::latency(profile)).await;
+            req = req.map(|body| ThrottledBody::new(body, profile.upstream_kbps, profile).boxed());
+        }
+
+        // Filled in as the request is handled and logged once it is answered.
+        let mut upstream = "none".to_string();
+        let mut injected = None;
+        let mut attempts = 0;
+        let mut cache_status = None;
+        let result = 'dispatch: {
+            if path_escapes {
+                self.state.increment_errors();
+                break 'dispatch self.error_response(StatusCode::BAD_REQUEST, "Invalid path");
+            }
+
+            if let Some(forward) = &self.config.forward_proxy
+                && listener == Listener::Forward
+            {
+                let refused = match req.uri().host() {
+                    None => Some(self.error_response(
+                        StatusCode::BAD_REQUEST,
+                        "Forward proxy requires absolute-form or CONNECT requests",
+                    )),
+                    Some(target) if !forward_allowed(forward, target) => {
+                        warn!("Forward proxy refused host: {}", target);
+                        Some(self.error_response(StatusCode::FORBIDDEN, "Host not allowed"))
+                    }
+                    Some(_) => None,
+                };
+                if let Some(response) = refused {
+                    break 'dispatch response;
+                }
+
+                if let Some(ca) = &self.ca
+                    && method == Method::CONNECT
+                    && let Some(target) = req.uri().host().map(normalize_host)
+                    && forward
+                        .ssl_proxying
+                        .iter()
+                        .any(|pattern| host_matches(pattern, &target))
+                {
+                    let response = self.intercept_tunnel(req, ca, &host, remote_addr);
+                    upstream = format!("mitm:{}", host);
+                    break 'dispatch response;
+                }
+
+                if method == Method::CONNECT {
+                    let response = self.open_tunnel(req, &host, remote_addr, &timeouts).await;
+                    upstream = format!("tunnel:{}", host);
+                    break 'dispatch response;
+                }
+            }
+
+            if forwarded::is_loop(req.headers(), &self.config.via_name) {
+                warn!("Request loop detected: {} {} Host: {}", method, path, host);
+                self.state.increment_errors();
+                break 'dispatch self.error_response(StatusCode::LOOP_DETECTED, "Loop detected");
+            }
+
+            if hop_by_hop::max_forwards(&method, req.headers_mut()) == MaxForwards::Respond {
+                let response = self.final_recipient_response(&req);
+                break 'dispatch response;
+            }
+
+            let upgrade_protocol = upgrade::requested_protocol(req.headers());
+            hop_by_hop::strip(req.headers_mut());
+            if let Some(protocol) = &upgrade_protocol {
+                upgrade::restore_headers(req.headers_mut(), protocol.clone());
+            }
+            let client_upgrade = upgrade_protocol
+                .is_some()
+                .then(|| hyper::upgrade::on(&mut req));
+            let trusted = forwarded::is_trusted(&self.config.trusted_proxies, client_ip);
+            let version = req.version();
+            let proto = req.uri().scheme_str().unwrap_or("http").to_string();
+            forwarded::apply(
+                req.headers_mut(),
+                client_ip,
+                trusted,
+                &proto,
+                &host,
+                version,
+                &self.config.via_name,
+            );
+
+            if self.state.breakpoints.matches(
+                BreakpointPhase::Request,
+                method.as_str(),
+                &host,
+                &path,
+            ) {
+                match self.break_on_request(req, &host).await {
+                    Ok(edited) => req = edited,
+                    Err(response) => {
+                        upstream = "breakpoint".to_string();
+                        break 'dispatch response;
+                    }
+                }
+            }
+
+            if let Some(rule) = map_local::find_rule(&self.config.map_local, &host, &path) {
+                let rest = rule
+                    .path_prefix
+                    .as_deref()
+                    .and_then(|prefix| path.strip_prefix(prefix))
+                    .unwrap_or(&path);
+                let response = self.serve_local(&rule.local, rest).await;
+                upstream = format!("local:{}", rule.local.path);
+                break 'dispatch response;
+            }
+
+            if let Some(target) = self
+                .state
+                .map_remote
+                .rewrite(&host, &path, req.uri().query())
+            {
+                if !target.preserve_host {
+                    set_host_from_uri(req.headers_mut(), &target.uri);
+                }
+                attempts = 1;
+                timeouts.start();
+                let response = self.forward(req, &target.uri, &timeouts).await;
+                let response = self
+                    .break_on_response(response, method.as_str(), &host, &path)
+                    .await;
+                let response =
+                    self.finish_upgrade(response, client_upgrade, upgrade_protocol, &request_id);
+                upstream = target.uri;
+                break 'dispatch response;
+            }
+
+            if listener == Listener::Forward {
+                let target = req.uri().to_string();
+                attempts = 1;
+                timeouts.start();
+                let response = self.forward(req, &target, &timeouts).await;
+                let response = self
+                    .break_on_response(response, method.as_str(), &host, &path)
+                    .await;
+                let response =
+                    self.finish_upgrade(response, client_upgrade, upgrade_protocol, &request_id);
+                upstream = target;
+                break 'dispatch response;
+            }
+
+            match matched {
+                Some(RouteMatch {
+                    route:
+                        Route {
+                            map_local: Some(local),
+                            path_prefix,
+                            ..
+                        },
+                    ..
+                }) => {
+                    let rest = path.strip_prefix(path_prefix.as_str()).unwrap_or(&path);
+                    let response = self.serve_local(local, rest).await;
+                    upstream = format!("local:{}", local.path);
+                    response
+                }
+                Some(matched) => {
+                    let route = matched.route;
+                    if let Some(limit) = &route.rate_limit {
+                        let key = rate_limit::client_key(&limit.key, &req, client_ip);
+                        if let Err(wait) = self.state.rate_limits.check(&route.name, limit, &key) {
+                            warn!("Rate limit exceeded on route {} by {}", route.name, key);
+                            let mut response = self
+                                .error_response(StatusCode::TOO_MANY_REQUESTS, "Too many requests");
+                            response.headers_mut().insert(
+                                hyper::header::RETRY_AFTER,
+                                rate_limit::retry_after_secs(wait).into(),
+                            );
+                            upstream = "rate_limited".to_string();
+                            break 'dispatch response;
+                        }
+                    }
+
+                    let mut permits = Vec::new();
+                    if let Some(limiter) = self.state.concurrency.route(&route.name) {
+                        match limiter.acquire().await {
+                            Ok(permit) => permits.push(permit),
+                            Err(rejected) => {
+                                warn!("Shedding request on route {}: {}", route.name, rejected);
+                                let response = self.shed_response(rejected);
+                                upstream = "shed".to_string();
+                                break 'dispatch response;
+                            }
+                        }
+                    }
+
+                    let fault = self.state.faults.pick(&route.name, method.as_str(), &path);
+                    if let Some(fault) = &fault {
+                        info!("Injecting fault on route {}: {}", route.name, fault);
+                        injected = Some(fault.to_string());
+                    }
+                    match &fault {
+                        Some(Fault::Delay { delay_ms }) => {
+                            tokio::time::sleep(Duration::from_millis(*delay_ms)).await;
+                        }
+                        Some(Fault::Status { status, body }) => {
+                            let status =
+                                StatusCode::from_u16(*status).unwrap_or(StatusCode::BAD_GATEWAY);
+                            let response = self.error_response(status, body);
+                            upstream = "fault".to_string();
+                            break 'dispatch response;
+                        }
+                        Some(Fault::Reset) => {
+                            upstream = "fault".to_string();
+                            let mut response = Response::new(full_body(Bytes::new()));
+                            response.extensions_mut().insert(ResetConnection);
+                            break 'dispatch response;
+                        }
+                        Some(Fault::Truncate { .. }) | None => {}
+                    }
+
+                    let accept_encoding =
+                        req.headers().get(hyper::header::ACCEPT_ENCODING).cloned();
+                    let ctx = TemplateContext {
+                        client_ip: client_ip.to_string(),
+                        route: &route.name,
+                        request_id: &request_id,
+                    };
+                    let cache_key = route
+                        .cache
+                        .as_ref()
+                        .filter(|_| upgrade_protocol.is_none())
+                        .map(|_| cache::key(&req, &host));
+                    let mut stale = None;
+                    if let Some(key) = &cache_key {
+                        match self.state.cache.lookup(&route.name, key, &req) {
+                            Lookup::Hit(response) => {
+                                cache_status = Some(CacheStatus::Hit);
+                                let mut response = response.map(full_body);
+                                headers::apply(
+                                    &route.response_headers,
+                                    response.headers_mut(),
+                                    &ctx,
+                                );
+                                if let Some(config) = &route.compression {
+                                    response = compression::compress(
+                                        config,
+                                        &method,
+                                        accept_encoding.as_ref(),
+                                        response,
+                                    );
+                                }
+                                upstream = "cache".to_string();
+                                break 'dispatch response;
+                            }
+                            Lookup::Stale(entry) => {
+                                cache::add_validators(&mut req, &entry);
+                                stale = Some(entry);
+                                cache_status = Some(CacheStatus::Miss);
+                            }
+                            Lookup::Miss => cache_status = Some(CacheStatus::Miss),
+                            Lookup::Bypass => cache_status = Some(CacheStatus::Bypass),
+                        }
+                    }
+                    // Kept for matching `Vary` when the response is stored.
+                    let request_headers =
+                        (cache_status == Some(CacheStatus::Miss)).then(|| req.headers().clone());
+
+                    // Retries and mirroring resend a copy of the request kept
+                    // in memory, so they need a body small enough to buffer.
+                    let policy = route.retry.as_ref().filter(|_| upgrade_protocol.is_none());
+                    if let Some(policy) = policy {
+                        self.state.retries.deposit(&route.name, policy.budget_ratio);
+                    }
+                    let retryable = policy
+                        .is_some_and(|policy| retry::can_buffer(&req, policy.buffer_body_bytes));
+                    let mirror = route.mirror.as_ref().filter(|mirror| {
+                        upgrade_protocol.is_none()
+                            && retry::can_buffer(&req, mirror.max_body_bytes)
+                            && mirror::sampled(mirror)
+                    });
+                    let group = self.state.canary.choose(route, &req);
+                    let hash_key = balancer::hash_key(route, &req, client_ip);
+                    let pinned = route
+                        .sticky
+                        .as_ref()
+                        .and_then(|sticky| sticky::pinned(sticky, route, &req));
+                    let (mut pending, replay) = if retryable || mirror.is_some() {
+                        match Replay::capture(req).await {
+                            Ok(replay) => (None, Some(replay)),
+                            Err(e) => {
+                                warn!("Failed to read request body: {}", e);
+                                let status = timeout::stage_of(&*e)
+                                    .map_or(StatusCode::BAD_REQUEST, Stage::status);
+                                break 'dispatch self
+                                    .error_response(status, "Invalid request body");
+                            }
+                        }
+                    } else {
+                        (Some(req), None)
+                    };
+                    let primary = match (mirror, &replay) {
+                        (Some(mirror), Some(replay)) => self.spawn_mirror(
+                            mirror,
+                            matched,
+                            replay.request(),
+                            &request_id,
+                            client_ip,
+                        ),
+                        _ => None,
+                    };
+
+                    let mut tried = Vec::new();
+                    let (mut response, upstream_url, load) = loop {
+                        let Some(admission) =
+                            self.select_upstream(route, group, &tried, pinned, hash_key.as_deref())
+                        else {
+                            warn!("No healthy upstream available for route: {}", route.name);
+                            self.state.increment_errors();
+                            break 'dispatch self.error_response(
+                                StatusCode::SERVICE_UNAVAILABLE,
+                                "No upstream available",
+                            );
+                        };
+                        let upstream_url = admission.url().to_string();
+
+                        let mut upstream_permit = None;
+                        if let Some(limiter) = self.state.concurrency.upstream(&upstream_url) {
+                            match limiter.acquire().await {
+                                Ok(permit) => upstream_permit = Some(permit),
+                                Err(rejected) => {
+                                    warn!("Shedding request to {}: {}", upstream_url, rejected);
+                                    drop(admission);
+                                    let response = self.shed_response(rejected);
+                                    upstream = format!("shed:{}", upstream_url);
+                                    break 'dispatch response;
+                                }
+                            }
+                        }
+
+                        attempts += 1;
+                        let attempt = match &replay {
+                            Some(replay) => replay.request(),
+                            // Without a copy there is only ever one attempt.
+                            None => pending.take().expect("request sent once"),
+                        };
+                        let load = self.state.balancers.start(&upstream_url);
+                        timeouts.start();
+                        let response = self
+                            .proxy_request(attempt, matched, &upstream_url, &ctx, &timeouts)
+                            .await;
+                        match circuit::outcome(&response) {
+                            Some(success) => {
+                                admission.record(success);
+                                load.observe(success);
+                            }
+                            None => drop(admission),
+                        }
+
+                        if let Some(policy) = policy
+                            && retryable
+                            && attempts < policy.max_attempts
+                            && retry::should_retry(policy, &method, &response)
+                            && self.state.retries.withdraw(&route.name)
+                        {
+                            warn!(
+                                "Attempt {} to {} failed with {}, retrying",
+                                attempts,
+                                upstream_url,
+                                response.status()
+                            );
+                            tried.push(upstream_url);
+                            tokio::time::sleep(retry::backoff(policy, attempts)).await;
+                            continue;
+                        }
+                        permits.extend(upstream_permit);
+                        break (response, upstream_url, load);
+                    };
+                    if route.compression.as_ref().is_some_and(|c| c.decompress) {
+                        response = compression::decompress(response);
+                    }
+                    if let Some(key) = &cache_key {
+                        if let Some(entry) = &stale
+                            && response.status() == StatusCode::NOT_MODIFIED
+                        {
+                            let headers = response.headers();
+                            response = self
+                                .state
+                                .cache
+                                .revalidated(&route.name, key, entry, headers)
+                                .map(full_body);
+                            cache_status = Some(CacheStatus::Revalidated);
+                        } else if let Some(request_headers) = &request_headers
+                            && let Some(storable) = self.state.cache.storable(
+                                &route.name,
+                                key,
+                                request_headers,
+                                &response,
+                            )
+                        {
+                            let limit = self.state.cache.max_body_bytes(&route.name);
+                            let status = response.status().as_u16();
+                            let state = Arc::clone(&self.state);
+                            let route = route.name.clone();
+                            let store = move |captured: Captured| {
+                                if let Some(body) = captured.body {
+                                    state.cache.insert(&route, storable, body);
+                                }
+                            };
+                            response = response
+                                .map(|body| TeeBody::new(body, status, limit, store).boxed());
+                        } else if !method.is_safe()
+                            && (response.status().is_success()
+                                || response.status().is_redirection())
+                        {
+                            self.state.cache.invalidate(&route.name, key);
+                        }
+                    }
+                    // Applied after the cache took its copy, so the rules
+                    // render for each client instead of replaying the first.
+                    headers::apply(&route.response_headers, response.headers_mut(), &ctx);
+                    if let (Some(mirror), Some(primary)) = (mirror, primary) {
+                        let status = response.status().as_u16();
+                        response = response.map(|body| {
+                            TeeBody::new(body, status, mirror.max_body_bytes, move |captured| {
+                                let _ = primary.send(captured);
+                            })
+                            .boxed()
+                        });
+                    }
+
+                    if let Some(sticky) = &route.sticky
+                        && pinned != Some(upstream_url.as_str())
+                        && response.extensions().get::<Failure>().is_none()
+                        && let Some(cookie) = sticky::set_cookie(sticky, route, &upstream_url)
+                    {
                         response
+                            .headers_mut()
+                            .append(hyper::header::SET_COOKIE, cookie);
                     }
-                    None => {
-                        warn!("No healthy upstream available for route: {}", route.name);
-                        self.state.increment_errors();
-                        self.log_request(
-                            method,
-                            path,
-                            host,
-                            503,
-                            start.elapsed().as_millis() as u64,
-                            "none".to_string(),
+
+                    let mut response = self
+                        .break_on_response(response, method.as_str(), &host, &path)
+                        .await;
+                    if let Some(config) = &route.compression {
+                        response = compression::compress(
+                            config,
+                            &method,
+                            accept_encoding.as_ref(),
+                            response,
                         );
-                        self.error_response(
-                            StatusCode::SERVICE_UNAVAILABLE,
-                            "No upstream available",
-                        )
                     }
+                    let response = self.finish_upgrade(
+                        response,
+                        client_upgrade,
+                        upgrade_protocol,
+                        &request_id,
+                    );
+                    let response = match fault {
+                        Some(Fault::Truncate { after_bytes }) => {
+                            response.map(|body| TruncatedBody::new(body, after_bytes).boxed())
+                        }
+                        _ => response,
+                    };
+                    upstream = upstream_url;
+                    response.map(|body| PermitBody::new(body, (permits, load)).boxed())
                 }
+                None => {
+                    warn!("No route found for: {} {}", host, path);
+                    self.state.increment_errors();
+                    self.error_response(StatusCode::NOT_FOUND, "No route configured")
+                }
+            }
+        };
+
+        let reset = result.extensions().get::<ResetConnection>().is_some();
+        self.state.add_request_log(RequestLog {
+            id: request_id,
+            timestamp: Utc::now(),
+            method: method.to_string(),
+            path,
+            host,
+            status: if reset { 0 } else { result.status().as_u16() },
+            duration_ms: start.elapsed().as_millis() as u64,
+            upstream,
+            fault: injected,
+            attempts,
+            websocket_messages: Vec::new(),
+            cache: cache_status,
+            mirror: None,
+        });
+        if let Some(status) = cache_status {
+            self.state
+                .cache
+                .record(route_name.unwrap_or_default(), status);
+        }
+        if reset {
+            self.state.decrement_active_requests();
+            return Err(ResetConnection);
+        }
+
+        let result = match &throttle {
+            Some(profile) => result
+                .map(|body| ThrottledBody::new(body, profile.downstream_kbps, profile).boxed()),
+            None => result,
+        };
+
+        self.state.decrement_active_requests();
+        Ok(result)
+    }
+
+    /// Holds a request at a breakpoint. `Err` carries the response to send
+    /// instead when the user aborts or the body cannot be read.
+    async fn break_on_request(
+        &self,
+        req: Request<BoxedBody>,
+        host: &str,
+    ) -> Result<Request<BoxedBody>, Response<BoxedBody>> {
+        let (mut parts, body) = req.into_parts();
+        let body = match body.collect().await {
+            Ok(collected) => collected.to_bytes(),
+            Err(e) => {
+                error!("Failed to read request body at breakpoint: {}", e);
+                return Err(self.error_response(StatusCode::BAD_REQUEST, "Invalid request body"));
             }
-            None => {
-                warn!("No route found for: {} {}", host, path);
+        };
+
+        let entry = PendingBreakpoint {
+            id: 0,
+            phase: BreakpointPhase::Request,
+            created_at: Utc::now(),
+            method: parts.method.to_string(),
+            host: host.to_string(),
+            path: parts.uri.path().to_string(),
+            status: None,
+            headers: header_pairs(&parts.headers),
+            body: String::from_utf8_lossy(&body).into_owned(),
+        };
+        info!(
+            "Request paused at breakpoint: {} {}",
+            entry.method, entry.path
+        );
+
+        let timeout = Duration::from_secs(self.config.breakpoints.timeout_secs);
+        let body = match self.state.breakpoints.pause(entry, timeout).await {
+            Some(BreakpointDecision::Abort(status)) => {
+                let status = StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_GATEWAY);
+                return Err(self.error_response(status, "Aborted at breakpoint"));
+            }
+            Some(BreakpointDecision::Continue(edits)) => {
+                apply_edits(&mut parts.headers, edits.headers.as_deref());
+                set_edited_body(&mut parts.headers, body, edits.body, false)
+            }
+            None => body,
+        };
+
+        Ok(Request::from_parts(parts, full_body(body)))
+    }
+
+    /// Holds an upstream response at a breakpoint if a rule matches,
+    /// returning what the client should receive.
+    async fn break_on_response(
+        &self,
+        response: Response<BoxedBody>,
+        method: &str,
+        host: &str,
+        path: &str,
+    ) -> Response<BoxedBody> {
+        if !self
+            .state
+            .breakpoints
+            .matches(BreakpointPhase::Response, method, host, path)
+        {
+            return response;
+        }
+
+        let (mut parts, body) = response.into_parts();
+        let body = match body.collect().await {
+            Ok(collected) => collected.to_bytes(),
+            Err(e) => {
+                error!("Failed to read response body at breakpoint: {}", e);
+                return self.error_response(StatusCode::BAD_GATEWAY, "Upstream error");
+            }
+        };
+
+        let entry = PendingBreakpoint {
+            id: 0,
+            phase: BreakpointPhase::Response,
+            created_at: Utc::now(),
+            method: method.to_string(),
+            host: host.to_string(),
+            path: path.to_string(),
+            status: Some(parts.status.as_u16()),
+            headers: header_pairs(&parts.headers),
+            body: String::from_utf8_lossy(&body).into_owned(),
+        };
+        info!("Response paused at breakpoint: {} {}", method, path);
+
+        let timeout = Duration::from_secs(self.config.breakpoints.timeout_secs);
+        let body = match self.state.breakpoints.pause(entry, timeout).await {
+            Some(BreakpointDecision::Abort(status)) => {
+                let status = StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_GATEWAY);
+                return self.error_response(status, "Aborted at breakpoint");
+            }
+            Some(BreakpointDecision::Continue(edits)) => {
+                if let Some(status) = edits.status.and_then(|s| StatusCode::from_u16(s).ok()) {
+                    parts.status = status;
+                }
+                apply_edits(&mut parts.headers, edits.headers.as_deref());
+                // HEAD answers and 1xx, 204 and 304 responses never carry a
+                // body, whatever their headers say.
+                let bodiless = method.eq_ignore_ascii_case("HEAD")
+                    || parts.status.is_informational()
+                    || parts.status == StatusCode::NO_CONTENT
+                    || parts.status == StatusCode::NOT_MODIFIED;
+                set_edited_body(&mut parts.headers, body, edits.body, bodiless)
+            }
+            None => body,
+        };
+
+        Response::from_parts(parts, full_body(body))
+    }
+
+    /// Splices the client and upstream connections once the upstream has
+    /// agreed to switch protocols.
+    fn finish_upgrade(
+        &self,
+        mut response: Response<BoxedBody>,
+        client: Option<OnUpgrade>,
+        protocol: Option<HeaderValue>,
+        request_id: &str,
+    ) -> Response<BoxedBody> {
+        if let (Some(client), Some(protocol)) = (client, protocol)
+            && response.status() == StatusCode::SWITCHING_PROTOCOLS
+        {
+            let upstream = hyper::upgrade::on(&mut response);
+            let capture =
+                (self.config.capture_websocket && upgrade::is_websocket(&protocol)).then(|| {
+                    upgrade::Capture {
+                        state: Arc::clone(&self.state),
+                        request_id: request_id.to_string(),
+                        max_preview: WEBSOCKET_PREVIEW_BYTES,
+                    }
+                });
+            upgrade::splice(client, upstream, capture);
+        }
+        response
+    }
+
+    /// Connects to `target` and, once the client has received `200`, relays
+    /// bytes blindly in both directions.
+    async fn open_tunnel(
+        &self,
+        req: Request<BoxedBody>,
+        target: &str,
+        client: SocketAddr,
+        timeouts: &Timeouts,
+    ) -> Response<BoxedBody> {
+        let connect = TcpStream::connect(target);
+        let connected = match timeouts.connect {
+            Some(limit) => tokio::time::timeout(limit, connect).await,
+            None => Ok(connect.await),
+        };
+        let upstream = match connected {
+            Ok(Ok(stream)) => stream,
+            Err(_) => {
+                self.state.record_timeout(Stage::Connect);
+                return self.timeout_response(target, Stage::Connect);
+            }
+            Ok(Err(e)) => {
+                error!("Error connecting tunnel to {}: {}", target, e);
                 self.state.increment_errors();
-                self.log_request(
-                    method,
-                    path,
-                    host,
-                    404,
-                    start.elapsed().as_millis() as u64,
-                    "none".to_string(),
+                return self.error_response(StatusCode::BAD_GATEWAY, "Tunnel connect failed");
+            }
+        };
+
+        let state = Arc::clone(&self.state);
+        let target = target.to_string();
+        let on_upgrade = hyper::upgrade::on(req);
+        tokio::spawn(async move {
+            let opened_at = Utc::now();
+            let start = Instant::now();
+            let upgraded = match on_upgrade.await {
+                Ok(upgraded) => upgraded,
+                Err(e) => {
+                    warn!("CONNECT upgrade failed: {}", e);
+                    return;
+                }
+            };
+            let (bytes_up, bytes_down) =
+                upgrade::relay(TokioIo::new(upgraded), upstream, None).await;
+            debug!(
+                "Tunnel to {} closed: {} bytes up, {} bytes down",
+                target, bytes_up, bytes_down
+            );
+            state.add_tunnel_log(TunnelLog {
+                id: uuid::Uuid::new_v4().to_string(),
+                opened_at,
+                client: client.to_string(),
+                target,
+                bytes_up,
+                bytes_down,
+                duration_ms: start.elapsed().as_millis() as u64,
+            });
+        });
+
+        Response::new(full_body(Bytes::new()))
+    }
+
+    /// Terminates the client's TLS with a certificate for the target host and
+    /// feeds the decrypted requests back through the forward-proxy pipeline.
+    fn intercept_tunnel(
+        self: &Arc<Self>,
+        req: Request<BoxedBody>,
+        ca: &CertificateAuthority,
+        authority: &str,
+        client: SocketAddr,
+    ) -> Response<BoxedBody> {
+        let host = req.uri().host().unwrap_or(authority);
+        let tls_config = match ca.server_config(host) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Error issuing certificate for {}: {}", host, e);
+                self.state.increment_errors();
+                return self.error_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Certificate generation failed",
                 );
-                self.error_response(StatusCode::NOT_FOUND, "No route configured")
             }
         };
 
-        self.state.decrement_active_requests();
-        Ok(result)
+        let server = Arc::clone(self);
+        let authority = authority.to_string();
+        let on_upgrade = hyper::upgrade::on(req);
+        tokio::spawn(async move {
+            let upgraded = match on_upgrade.await {
+                Ok(upgraded) => upgraded,
+                Err(e) => {
+                    warn!("CONNECT upgrade failed: {}", e);
+                    return;
+                }
+            };
+            let tls = match tokio_rustls::TlsAcceptor::from(tls_config)
+                .accept(TokioIo::new(upgraded))
+                .await
+            {
+                Ok(tls) => tls,
+                Err(e) => {
+                    warn!("TLS handshake with client for {} failed: {}", authority, e);
+                    return;
+                }
+            };
+
+            let server_clone = Arc::clone(&server);
+            let service = service_fn(move |mut req: Request<Incoming>| {
+                let server = Arc::clone(&server_clone);
+                // Requests inside the tunnel are origin-form; restore the target.
+                let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+                if let Ok(uri) = format!("https://{}{}", authority, path).parse() {
+                    *req.uri_mut() = uri;
+                }
+                async move { server.handle_request(req, client, Listener::Forward).await }
+            });
+            let (tls, activity) = ActivityIo::new(tls);
+            if let Err(err) = server
+                .http1_builder()
+                .serve_connection(TokioIo::new(tls), service)
+                .with_upgrades()
+                .await
+                && server.count_connection_error(&err, &activity)
+            {
+                debug!("Error serving intercepted connection: {}", err);
+            }
+        });
+
+        Response::new(full_body(Bytes::new()))
     }
 
-    fn select_upstream(&self, upstreams: &[Upstream]) -> Option<String> {
-        // Simple round-robin selection of first available upstream
-        // In production, this would implement weighted selection and health checking
-        upstreams.first().map(|u| u.url.clone())
+    async fn serve_local(&self, local: &MapLocal, rest: &str) -> Response<BoxedBody> {
+        match map_local::load(local, rest).await {
+            Ok(response) => response.map(full_body),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.error_response(StatusCode::NOT_FOUND, "Local file not found")
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                self.error_response(StatusCode::FORBIDDEN, "Forbidden")
+            }
+            Err(e) => {
+                error!("Failed to serve local mapping {}: {}", local.path, e);
+                self.state.increment_errors();
+                self.error_response(StatusCode::INTERNAL_SERVER_ERROR, "Local mapping error")
+            }
+        }
     }
 
-    async fn proxy_request(
+    /// Prefers the `pinned` upstream from a sticky cookie, then lets the
+    /// route's balancer pick among the `group`'s upstreams not `tried` yet,
+    /// falling back to ones already tried. Upstreams whose circuit refuses
+    /// the request are passed over, and a canary group with none left falls
+    /// back to the primary one.
+    fn select_upstream(
         &self,
-        mut req: Request<Incoming>,
         route: &Route,
+        group: Group,
+        tried: &[String],
+        pinned: Option<&str>,
+        hash_key: Option<&str>,
+    ) -> Option<Admission<'_>> {
+        if let Some(url) = pinned
+            && !tried.iter().any(|t| t == url)
+            && let Some(admission) = self.state.circuits.allow(url)
+        {
+            return Some(admission);
+        }
+        let groups: &[Group] = match group {
+            Group::Primary => &[Group::Primary],
+            Group::Canary => &[Group::Canary, Group::Primary],
+        };
+        for &group in groups {
+            let upstreams = group.upstreams(route);
+            for fresh in [true, false] {
+                let mut candidates: Vec<usize> = (0..upstreams.len())
+                    .filter(|&i| tried.contains(&upstreams[i].url) != fresh)
+                    .collect();
+                while let Some(i) =
+                    self.state
+                        .balancers
+                        .pick(&route.name, group, &candidates, hash_key)
+                {
+                    if let Some(admission) = self.state.circuits.allow(&upstreams[i].url) {
+                        return Some(admission);
+                    }
+                    candidates.retain(|&c| c != i);
+                }
+            }
+        }
+        None
+    }
+
+    /// Sends a copy of the request to the route's shadow upstream in the
+    /// background and throws the answer away. With `compare` on, returns
+    /// where to hand over the primary response for the diff.
+    fn spawn_mirror(
+        self: &Arc<Self>,
+        mirror: &Mirror,
+        matched: RouteMatch<'_>,
+        req: Request<BoxedBody>,
+        request_id: &str,
+        client_ip: IpAddr,
+    ) -> Option<oneshot::Sender<Captured>> {
+        let compare = mirror.compare;
+        let (tx, rx) = oneshot::channel::<Captured>();
+        let server = Arc::clone(self);
+        let mirror = mirror.clone();
+        let index = matched.index;
+        let route = matched.route.clone();
+        let request_id = request_id.to_string();
+        tokio::spawn(async move {
+            let ctx = TemplateContext {
+                client_ip: client_ip.to_string(),
+                route: &route.name,
+                request_id: &request_id,
+            };
+            // The shadow runs on its own deadline, and its failures go to
+            // `mirror_errors` rather than the metrics clients' requests feed.
+            let mut timeouts = Timeouts::resolve(&server.config.timeouts, Some(&route.timeouts));
+            timeouts.start();
+            let mut req = req;
+            let matched = RouteMatch {
+                route: &route,
+                index,
+            };
+            let target = server.upstream_request(&mut req, matched, &mirror.url, &ctx);
+            let (shadow_status, shadow_body) =
+                match server.send_upstream(req, &target, &timeouts).await {
+                    Ok(response) => {
+                        let status = response.status().as_u16();
+                        let body = TimeoutBody::new(
+                            response.into_body(),
+                            timeouts.body_idle,
+                            timeouts.deadline,
+                            Stage::Request,
+                            None,
+                        )
+                        .boxed();
+                        match mirror::read_capped(body, mirror.max_body_bytes).await {
+                            Ok(body) => (status, body),
+                            Err(e) => {
+                                warn!("Mirror response from {} failed: {}", mirror.url, e);
+                                server.state.record_mirror_error();
+                                (status, None)
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Mirror request to {} failed: {}", mirror.url, e);
+                        server.state.record_mirror_error();
+                        (e.status().as_u16(), None)
+                    }
+                };
+            if !compare {
+                return;
+            }
+            let Ok(primary) = rx.await else {
+                return;
+            };
+            let body_matches = match (primary.body, shadow_body) {
+                (Some(primary), Some(shadow)) => Some(primary == shadow),
+                _ => None,
+            };
+            server.state.set_mirror_diff(
+                &request_id,
+                MirrorDiff {
+                    upstream: mirror.url,
+                    primary_status: primary.status,
+                    shadow_status,
+                    body_matches,
+                },
+            );
+        });
+        compare.then_some(tx)
+    }
+
+    async fn proxy_request(
+        &self,
+        mut req: Request<BoxedBody>,
+        matched: RouteMatch<'_>,
         upstream_url: &str,
+        ctx: &TemplateContext<'_>,
+        timeouts: &Timeouts,
     ) -> Response<BoxedBody> {
-        // Rewrite path if needed
-        let path = req.uri().path();
-        let new_path = if route.strip_prefix {
-            let stripped = path.strip_prefix(&route.path_prefix).unwrap_or(path);
-            if let Some(rewrite) = &route.rewrite_prefix {
-                format!("{}{}", rewrite, stripped)
-            } else {
-                stripped.to_string()
-            }
-        } else {
-            path.to_string()
-        };
+        let target = self.upstream_request(&mut req, matched, upstream_url, ctx);
+        self.forward(req, &target, timeouts).await
+    }
+
+    /// Rewrites `req` for `upstream_url` under the matched route's path and
+    /// header rules, returning the absolute URI to send it to.
+    fn upstream_request(
+        &self,
+        req: &mut Request<BoxedBody>,
+        matched: RouteMatch<'_>,
+        upstream_url: &str,
+        ctx: &TemplateContext<'_>,
+    ) -> String {
+        let route = matched.route;
+        let new_path = self.route_matcher.upstream_path(matched, req.uri().path());
 
         // Build new URI
         let query = req
@@ -201,31 +1316,57 @@ impl ProxyServer {
             .unwrap_or_default();
         let new_uri = format!("{}{}{}", upstream_url, new_path, query);
 
-        info!("Proxying to: {}", new_uri);
-
-        // Create a new request to the upstream
-        let client =
-            hyper_util::client::legacy::Client::builder(hyper_util::rt::TokioExecutor::new())
-                .build_http();
+        if !route.preserve_host {
+            set_host_from_uri(req.headers_mut(), &new_uri);
+        }
+        headers::apply(&route.request_headers, req.headers_mut(), ctx);
+        new_uri
+    }
 
-        match new_uri.parse() {
-            Ok(uri) => {
-                *req.uri_mut() = uri;
+    /// Sends `req` to the absolute `target` URI and relays the response,
+    /// counting failures in the proxy's metrics.
+    async fn forward(
+        &self,
+        req: Request<BoxedBody>,
+        target: &str,
+        timeouts: &Timeouts,
+    ) -> Response<BoxedBody> {
+        info!("Proxying to: {}", target);
 
-                match client.request(req).await {
-                    Ok(response) => {
-                        let (parts, body) = response.into_parts();
-                        let boxed_body = body.map_err(|e| Box::new(e) as GenericError).boxed();
-                        Response::from_parts(parts, boxed_body)
-                    }
-                    Err(e) => {
-                        error!("Error proxying request: {}", e);
-                        self.state.increment_errors();
-                        self.error_response(StatusCode::BAD_GATEWAY, "Upstream error")
-                    }
-                }
+        match self.send_upstream(req, target, timeouts).await {
+            Ok(response) => match timeouts.deadline {
+                Some(deadline) => response.map(|body| {
+                    TimeoutBody::new(
+                        body,
+                        None,
+                        Some(deadline),
+                        Stage::Request,
+                        Some(Arc::clone(&self.state)),
+                    )
+                    .boxed()
+                }),
+                None => response,
+            },
+            // The request body counted its own timeout.
+            Err(UpstreamError::TimedOut(Stage::RequestBody)) => {
+                self.timeout_response(target, Stage::RequestBody)
             }
-            Err(e) => {
+            Err(UpstreamError::TimedOut(stage)) => {
+                self.state.record_timeout(stage);
+                self.timeout_response(target, stage)
+            }
+            Err(UpstreamError::Failed(e)) => {
+                error!("Error proxying request: {}", e);
+                self.state.increment_errors();
+                let mut response = self.error_response(StatusCode::BAD_GATEWAY, "Upstream error");
+                response.extensions_mut().insert(if e.is_connect() {
+                    Failure::Connect
+                } else {
+                    Failure::Reset
+                });
+                response
+            }
+            Err(UpstreamError::InvalidUri(e)) => {
                 error!("Invalid URI: {}", e);
                 self.state.increment_errors();
                 self.error_response(StatusCode::BAD_GATEWAY, "Invalid upstream URI")
@@ -233,6 +1374,79 @@ impl ProxyServer {
         }
     }
 
+    /// Sends `req` to the absolute `target` URI, leaving failures, and the
+    /// response body's deadline, to the caller.
+    async fn send_upstream(
+        &self,
+        mut req: Request<BoxedBody>,
+        target: &str,
+        timeouts: &Timeouts,
+    ) -> Result<Response<BoxedBody>, UpstreamError> {
+        *req.uri_mut() = target.parse().map_err(UpstreamError::InvalidUri)?;
+
+        let client = self
+            .clients
+            .get(&timeouts.connect)
+            .unwrap_or_else(|| &self.clients[&None]);
+        let sent = client.request(req);
+        let response = match timeouts.response_header_limit() {
+            Some((at, stage)) => tokio::time::timeout_at(at, sent)
+                .await
+                .map_err(|_| UpstreamError::TimedOut(stage))?,
+            None => sent.await,
+        }
+        .map_err(|e| match timeout::stage_of(&e) {
+            Some(stage) => UpstreamError::TimedOut(stage),
+            None => UpstreamError::Failed(e),
+        })?;
+
+        let (mut parts, body) = response.into_parts();
+        let upgrade = (parts.status == StatusCode::SWITCHING_PROTOCOLS)
+            .then(|| upgrade::requested_protocol(&parts.headers))
+            .flatten();
+        hop_by_hop::strip(&mut parts.headers);
+        if let Some(protocol) = upgrade {
+            upgrade::restore_headers(&mut parts.headers, protocol);
+        }
+        forwarded::append_via(&mut parts.headers, parts.version, &self.config.via_name);
+        let body = body.map_err(|e| Box::new(e) as GenericError).boxed();
+        Ok(Response::from_parts(parts, body))
+    }
+
+    /// Answers an OPTIONS or TRACE whose `Max-Forwards` reached zero.
+    fn final_recipient_response(&self, req: &Request<BoxedBody>) -> Response<BoxedBody> {
+        if req.method() == Method::OPTIONS {
+            return Response::builder()
+                .status(StatusCode::OK)
+                .header(
+                    hyper::header::ALLOW,
+                    "GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS, TRACE",
+                )
+                .header(hyper::header::CONTENT_LENGTH, 0)
+                .body(full_body(Bytes::new()))
+                .unwrap();
+        }
+
+        // TRACE echoes the request as received, minus credentials.
+        let mut echo = format!("{} {} {:?}\r\n", req.method(), req.uri(), req.version());
+        for (name, value) in req.headers() {
+            if name == hyper::header::AUTHORIZATION || name == hyper::header::COOKIE {
+                continue;
+            }
+            echo.push_str(&format!(
+                "{}: {}\r\n",
+                name,
+                String::from_utf8_lossy(value.as_bytes())
+            ));
+        }
+        echo.push_str("\r\n");
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(hyper::header::CONTENT_TYPE, "message/http")
+            .body(full_body(Bytes::from(echo)))
+            .unwrap()
+    }
+
     fn error_response(&self, status: StatusCode, message: &str) -> Response<BoxedBody> {
         Response::builder()
             .status(status)
@@ -244,23 +1458,98 @@ impl ProxyServer {
             .unwrap()
     }
 
-    fn log_request(
-        &self,
-        method: Method,
-        path: String,
-        host: String,
-        status: u16,
-        duration_ms: u64,
-        upstream: String,
-    ) {
-        self.state.add_request_log(RequestLog {
-            timestamp: Utc::now(),
-            method: method.to_string(),
-            path,
-            host,
-            status,
-            duration_ms,
-            upstream,
+    fn timeout_response(&self, target: &str, stage: Stage) -> Response<BoxedBody> {
+        warn!("{} timeout proxying to {}", stage, target);
+        let message = match stage.status() {
+            StatusCode::REQUEST_TIMEOUT => "Request timeout",
+            _ => "Upstream timeout",
+        };
+        let mut response = self.error_response(stage.status(), message);
+        response.extensions_mut().insert(match stage {
+            Stage::Connect => Failure::Connect,
+            Stage::ResponseHeader => Failure::Timeout,
+            Stage::HeaderRead | Stage::RequestBody | Stage::Request => Failure::Final,
         });
+        response
+    }
+
+    fn shed_response(&self, rejected: concurrency::Rejected) -> Response<BoxedBody> {
+        let message = match rejected {
+            concurrency::Rejected::QueueFull => "Server busy",
+            concurrency::Rejected::Timeout => "Timed out waiting for capacity",
+        };
+        self.error_response(StatusCode::SERVICE_UNAVAILABLE, message)
+    }
+}
+
+/// Deny patterns win; an empty allow list permits every other host.
+fn forward_allowed(config: &ForwardProxyConfig, host: &str) -> bool {
+    let host = &normalize_host(host);
+    !config
+        .deny
+        .iter()
+        .any(|pattern| host_matches(pattern, host))
+        && (config.allow.is_empty()
+            || config
+                .allow
+                .iter()
+                .any(|pattern| host_matches(pattern, host)))
+}
+
+fn full_body(body: Bytes) -> BoxedBody {
+    Full::new(body).map_err(|never| match never {}).boxed()
+}
+
+fn apply_edits(headers: &mut HeaderMap, edited: Option<&[(String, String)]>) {
+    if let Some(pairs) = edited {
+        *headers = header_map(pairs);
+    }
+}
+
+/// Swaps in an edited body, keeping `Content-Length` in step with it. An
+/// unedited body, or a message that cannot carry one (`bodiless`), keeps
+/// its framing headers as they were.
+fn set_edited_body(
+    headers: &mut HeaderMap,
+    original: Bytes,
+    edited: Option<String>,
+    bodiless: bool,
+) -> Bytes {
+    match edited {
+        Some(edited) if !bodiless => {
+            let body = Bytes::from(edited);
+            headers.remove(hyper::header::TRANSFER_ENCODING);
+            headers.insert(hyper::header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+            body
+        }
+        _ => original,
+    }
+}
+
+/// Replaces the path of `req`'s URI, keeping its query.
+fn set_path<B>(req: &mut Request<B>, path: &str) {
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let mut parts = req.uri().clone().into_parts();
+    if let Ok(path_and_query) = path_and_query.parse() {
+        parts.path_and_query = Some(path_and_query);
+        if let Ok(uri) = hyper::Uri::from_parts(parts) {
+            *req.uri_mut() = uri;
+        }
     }
 }
+
+/// Points the `Host` header at the authority of `uri`.
+fn set_host_from_uri(headers: &mut HeaderMap, uri: &str) {
+    if let Ok(uri) = uri.parse::<hyper::Uri>()
+        && let Some(authority) = uri.authority()
+        && let Ok(value) = HeaderValue::from_str(authority.as_str())
+    {
+        headers.insert(hyper::header::HOST, value);
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,1553 @@
+//! Conformance tests running the proxy against a local hyper upstream.
+
+use super::*;
+use crate::breakpoint::BreakpointEdits;
+use hyper::header::{HeaderName, MAX_FORWARDS, VIA};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Upstream that echoes the request head as `name: value` lines and answers
+/// with a mix of hop-by-hop and end-to-end headers.
+async fn start_upstream() -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&hits);
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let counter = Arc::clone(&counter);
+            tokio::spawn(async move {
+                let service = service_fn(move |req: Request<Incoming>| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let mut echo = format!("{} {}\n", req.method(), req.uri());
+                    for (name, value) in req.headers() {
+                        echo.push_str(&format!("{}: {}\n", name, value.to_str().unwrap()));
+                    }
+                    let response = Response::builder()
+                        .header("connection", "x-hop")
+                        .header("x-hop", "1")
+                        .header("keep-alive", "timeout=5")
+                        .header("proxy-authenticate", "Basic")
+                        .header("x-end", "1")
+                        .body(Full::new(Bytes::from(echo)))
+                        .unwrap();
+                    async move { Ok::<_, std::convert::Infallible>(response) }
+                });
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+
+    (addr, hits)
+}
+
+async fn start_proxy(upstream: SocketAddr, extra: &str) -> SocketAddr {
+    start_proxy_with(upstream, extra, "").await.0
+}
+
+/// Like `start_proxy`, with `route_tables` appended to the test route.
+async fn start_proxy_with(
+    upstream: SocketAddr,
+    extra: &str,
+    route_tables: &str,
+) -> (SocketAddr, Arc<SharedState>) {
+    let toml_str = format!(
+        r#"
+            listen = "127.0.0.1:0"
+            {extra}
+
+            [control]
+            listen = "127.0.0.1:0"
+
+            [[routes]]
+            name = "test"
+            hosts = ["example.com"]
+            path_prefix = "/"
+
+            [[routes.upstreams]]
+            url = "http://{upstream}"
+
+            {route_tables}
+        "#
+    );
+    let config: Config = toml::from_str(&toml_str).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let state = SharedState::new();
+    let server = ProxyServer::new(config, Arc::clone(&state)).unwrap();
+    tokio::spawn(server.serve(listener, None));
+    (addr, state)
+}
+
+async fn send(proxy: SocketAddr, req: Request<Full<Bytes>>) -> Response<String> {
+    send_raw(proxy, req)
+        .await
+        .map(|body| String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Like `send`, keeping the body as the bytes on the wire.
+async fn send_raw(proxy: SocketAddr, req: Request<Full<Bytes>>) -> Response<Bytes> {
+    let stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    exchange(stream, req).await
+}
+
+/// Like `send`, from the loopback address `client`.
+async fn send_from(
+    client: IpAddr,
+    proxy: SocketAddr,
+    req: Request<Full<Bytes>>,
+) -> Response<String> {
+    let socket = tokio::net::TcpSocket::new_v4().unwrap();
+    socket.bind(SocketAddr::new(client, 0)).unwrap();
+    let stream = socket.connect(proxy).await.unwrap();
+    exchange(stream, req)
+        .await
+        .map(|body| String::from_utf8_lossy(&body).into_owned())
+}
+
+async fn exchange(stream: TcpStream, req: Request<Full<Bytes>>) -> Response<Bytes> {
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .unwrap();
+    tokio::spawn(conn);
+
+    let response = sender.send_request(req).await.unwrap();
+    let (parts, body) = response.into_parts();
+    let body = body.collect().await.unwrap().to_bytes();
+    Response::from_parts(parts, body)
+}
+
+fn request(method: Method) -> hyper::http::request::Builder {
+    Request::builder()
+        .method(method)
+        .uri("/echo")
+        .header("host", "example.com")
+}
+
+fn echoed_header<'a>(echo: &'a str, name: &str) -> Option<&'a str> {
+    echo.lines()
+        .filter_map(|line| line.split_once(": "))
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value)
+}
+
+#[tokio::test]
+async fn test_request_hop_by_hop_headers_stripped() {
+    let (upstream, _) = start_upstream().await;
+    let proxy = start_proxy(upstream, "").await;
+
+    let req = request(Method::GET)
+        .header("connection", "keep-alive, x-session")
+        .header("x-session", "secret")
+        .header("keep-alive", "timeout=5")
+        .header("proxy-authorization", "Basic Zm9vOmJhcg==")
+        .header("te", "trailers")
+        .header("x-end", "kept")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let response = send(proxy, req).await;
+    let echo = response.body();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    for name in ["x-session", "keep-alive", "proxy-authorization", "te"] {
+        assert_eq!(echoed_header(echo, name), None, "{} leaked upstream", name);
+    }
+    assert_eq!(echoed_header(echo, "x-end"), Some("kept"));
+    assert_eq!(echoed_header(echo, "via"), Some("1.1 charles"));
+    assert_eq!(echoed_header(echo, "x-forwarded-for"), Some("127.0.0.1"));
+}
+
+#[tokio::test]
+async fn test_response_hop_by_hop_headers_stripped() {
+    let (upstream, _) = start_upstream().await;
+    let proxy = start_proxy(upstream, "").await;
+
+    let req = request(Method::GET).body(Full::new(Bytes::new())).unwrap();
+    let response = send(proxy, req).await;
+    let headers = response.headers();
+
+    for name in ["x-hop", "keep-alive", "proxy-authenticate"] {
+        assert!(
+            !headers.contains_key(HeaderName::from_static(name)),
+            "{} leaked downstream",
+            name
+        );
+    }
+    assert_eq!(headers["x-end"], "1");
+    assert_eq!(headers[VIA], "1.1 charles");
+}
+
+#[tokio::test]
+async fn test_upstream_host_header() {
+    let (upstream, _) = start_upstream().await;
+    let get = |path: &str| {
+        request(Method::GET)
+            .uri(path)
+            .body(Full::new(Bytes::new()))
+            .unwrap()
+    };
+    let upstream_host = upstream.to_string();
+
+    // Routes keep the client's Host unless preserve_host is off.
+    for (route_keys, expected) in [
+        ("", "example.com"),
+        ("preserve_host = false", upstream_host.as_str()),
+    ] {
+        let proxy = start_balanced_proxy(route_keys, &[upstream]).await;
+        let response = send(proxy, get("/echo")).await;
+        assert_eq!(echoed_header(response.body(), "host"), Some(expected));
+    }
+
+    // Map Remote points Host at its target unless preserve_host is on.
+    let map_remote = format!(
+        r#"
+            [[map_remote]]
+            path = "^/moved(/.*)$"
+            target = "http://{upstream}$1"
+
+            [[map_remote]]
+            path = "^/kept(/.*)$"
+            target = "http://{upstream}$1"
+            preserve_host = true
+        "#
+    );
+    let proxy = start_proxy(upstream, &map_remote).await;
+    for (path, expected) in [
+        ("/moved/a", upstream_host.as_str()),
+        ("/kept/a", "example.com"),
+    ] {
+        let response = send(proxy, get(path)).await;
+        assert!(response.body().starts_with("GET /a\n"), "{path}");
+        assert_eq!(
+            echoed_header(response.body(), "host"),
+            Some(expected),
+            "{path}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_request_body_forwarded() {
+    let (upstream, _) = start_upstream().await;
+    let proxy = start_proxy(upstream, "").await;
+
+    let req = request(Method::POST)
+        .header("content-type", "text/plain")
+        .body(Full::new(Bytes::from("payload")))
+        .unwrap();
+    let response = send(proxy, req).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(echoed_header(response.body(), "content-length"), Some("7"));
+}
+
+#[tokio::test]
+async fn test_max_forwards_zero_answered_by_proxy() {
+    let (upstream, hits) = start_upstream().await;
+    let proxy = start_proxy(upstream, "").await;
+
+    let req = request(Method::OPTIONS)
+        .header(MAX_FORWARDS, "0")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let response = send(proxy, req).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().contains_key(hyper::header::ALLOW));
+
+    let req = request(Method::TRACE)
+        .header(MAX_FORWARDS, "0")
+        .header("cookie", "session=1")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let response = send(proxy, req).await;
+    assert_eq!(
+        response.headers()[hyper::header::CONTENT_TYPE],
+        "message/http"
+    );
+    assert!(response.body().starts_with("TRACE /echo HTTP/1.1"));
+    assert!(!response.body().contains("session=1"));
+
+    assert_eq!(hits.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn test_max_forwards_decremented() {
+    let (upstream, hits) = start_upstream().await;
+    let proxy = start_proxy(upstream, "").await;
+
+    let req = request(Method::OPTIONS)
+        .header(MAX_FORWARDS, "3")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let response = send(proxy, req).await;
+
+    assert_eq!(echoed_header(response.body(), "max-forwards"), Some("2"));
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_via_loop_rejected() {
+    let (upstream, hits) = start_upstream().await;
+    let proxy = start_proxy(upstream, r#"via_name = "charles-a""#).await;
+
+    let req = request(Method::GET)
+        .header(VIA, "1.1 edge, 1.1 charles-a")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let response = send(proxy, req).await;
+    assert_eq!(response.status(), StatusCode::LOOP_DETECTED);
+    assert_eq!(hits.load(Ordering::SeqCst), 0);
+
+    let req = request(Method::GET)
+        .header(VIA, "1.1 charles-b")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let response = send(proxy, req).await;
+    assert_eq!(
+        echoed_header(response.body(), "via"),
+        Some("1.1 charles-b, 1.1 charles-a")
+    );
+}
+
+/// Upstream that accepts any upgrade and echoes bytes on the new connection.
+async fn start_upgrade_upstream() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let service = service_fn(|mut req: Request<Incoming>| async move {
+                    let protocol = req.headers()[hyper::header::UPGRADE].clone();
+                    tokio::spawn(async move {
+                        let upgraded = hyper::upgrade::on(&mut req).await.unwrap();
+                        let (mut read, mut write) = tokio::io::split(TokioIo::new(upgraded));
+                        let _ = tokio::io::copy(&mut read, &mut write).await;
+                    });
+                    let response = Response::builder()
+                        .status(StatusCode::SWITCHING_PROTOCOLS)
+                        .header(hyper::header::CONNECTION, "upgrade")
+                        .header(hyper::header::UPGRADE, protocol)
+                        .body(Full::new(Bytes::new()))
+                        .unwrap();
+                    Ok::<_, std::convert::Infallible>(response)
+                });
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .with_upgrades()
+                    .await;
+            });
+        }
+    });
+
+    addr
+}
+
+#[tokio::test]
+async fn test_upgrade_tunnelled() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let upstream = start_upgrade_upstream().await;
+    let proxy = start_proxy(upstream, "").await;
+
+    let stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .unwrap();
+    tokio::spawn(conn.with_upgrades());
+
+    let req = request(Method::GET)
+        .header(hyper::header::CONNECTION, "Upgrade")
+        .header(hyper::header::UPGRADE, "echo")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let mut response = sender.send_request(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+    assert_eq!(response.headers()[hyper::header::UPGRADE], "echo");
+
+    let upgraded = hyper::upgrade::on(&mut response).await.unwrap();
+    let mut io = TokioIo::new(upgraded);
+    io.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    io.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+}
+
+async fn start_forward_proxy(forward: &str) -> (SocketAddr, Arc<SharedState>) {
+    start_forward_proxy_with(forward, "").await
+}
+
+async fn start_forward_proxy_with(forward: &str, extra: &str) -> (SocketAddr, Arc<SharedState>) {
+    let toml_str = format!(
+        r#"
+            listen = "127.0.0.1:0"
+            routes = []
+            {extra}
+
+            [control]
+            listen = "127.0.0.1:0"
+
+            [forward_proxy]
+            listen = "127.0.0.1:0"
+            {forward}
+        "#
+    );
+    let config: Config = toml::from_str(&toml_str).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let forward_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = forward_listener.local_addr().unwrap();
+    let state = SharedState::new();
+    let server = ProxyServer::new(config, Arc::clone(&state)).unwrap();
+    tokio::spawn(server.serve(listener, Some(forward_listener)));
+    (addr, state)
+}
+
+#[tokio::test]
+async fn test_forward_proxy_absolute_form() {
+    let (upstream, hits) = start_upstream().await;
+    let (proxy, _) = start_forward_proxy("").await;
+
+    let req = Request::builder()
+        .uri(format!("http://{upstream}/echo?q=1"))
+        .header("host", upstream.to_string())
+        .header("proxy-connection", "keep-alive")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let response = send(proxy, req).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.body().starts_with("GET /echo?q=1\n"));
+    assert_eq!(echoed_header(response.body(), "proxy-connection"), None);
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_forward_proxy_refuses_requests() {
+    let (upstream, hits) = start_upstream().await;
+    let (proxy, _) = start_forward_proxy(r#"deny = ["127.0.0.1"]"#).await;
+
+    let req = Request::builder()
+        .uri(format!("http://{upstream}/echo"))
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    assert_eq!(send(proxy, req).await.status(), StatusCode::FORBIDDEN);
+
+    let req = request(Method::GET).body(Full::new(Bytes::new())).unwrap();
+    assert_eq!(send(proxy, req).await.status(), StatusCode::BAD_REQUEST);
+
+    let (proxy, _) = start_forward_proxy(r#"allow = ["*.example.com"]"#).await;
+    let req = Request::builder()
+        .uri(format!("http://{upstream}/echo"))
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    assert_eq!(send(proxy, req).await.status(), StatusCode::FORBIDDEN);
+    assert_eq!(hits.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn test_forward_proxy_deny_ignores_host_case() {
+    let (proxy, _) = start_forward_proxy(r#"deny = ["evil.com"]"#).await;
+
+    for uri in ["http://Evil.COM/", "http://evil.com./"] {
+        let req = Request::builder()
+            .uri(uri)
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        assert_eq!(
+            send(proxy, req).await.status(),
+            StatusCode::FORBIDDEN,
+            "{uri}"
+        );
+    }
+    let (_stream, head) = connect(proxy, "EVIL.com:443").await;
+    assert!(head.starts_with(b"HTTP/1.1 403"));
+}
+
+#[tokio::test]
+async fn test_connect_tunnel() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Raw TCP echo target standing in for a TLS server
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = target.accept().await.unwrap();
+        let (mut read, mut write) = stream.split();
+        let _ = tokio::io::copy(&mut read, &mut write).await;
+    });
+
+    let (proxy, state) = start_forward_proxy("").await;
+    let (mut stream, head) = connect(proxy, &target_addr.to_string()).await;
+    assert!(head.starts_with(b"HTTP/1.1 200"));
+
+    stream.write_all(b"tls bytes").await.unwrap();
+    let mut buf = [0u8; 9];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"tls bytes");
+    drop(stream);
+
+    for _ in 0..50 {
+        if let Some(tunnel) = state.get_tunnel_logs().first() {
+            assert_eq!(tunnel.target, target_addr.to_string());
+            assert_eq!((tunnel.bytes_up, tunnel.bytes_down), (9, 9));
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("tunnel was not recorded");
+}
+
+/// Sends `CONNECT target` and reads the proxy's response head.
+async fn connect(proxy: SocketAddr, target: &str) -> (tokio::net::TcpStream, Vec<u8>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    stream
+        .write_all(format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n\r\n").as_bytes())
+        .await
+        .unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await.unwrap());
+    }
+    (stream, head)
+}
+
+#[tokio::test]
+async fn test_ssl_proxying_decrypts_requests() {
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, ServerName};
+
+    let ca_dir = std::env::temp_dir().join(format!("charles-ca-{}", uuid::Uuid::new_v4()));
+    let (proxy, state) = start_forward_proxy_with(
+        r#"ssl_proxying = ["localhost"]"#,
+        &format!("ca_dir = {:?}", ca_dir.to_str().unwrap()),
+    )
+    .await;
+
+    let (stream, head) = connect(proxy, "localhost:1").await;
+    assert!(head.starts_with(b"HTTP/1.1 200"));
+
+    // The client trusts only the generated CA.
+    let ca_pem = std::fs::read(ca_dir.join("charles-ca.pem")).unwrap();
+    let mut roots = rustls::RootCertStore::empty();
+    roots
+        .add(CertificateDer::from_pem_slice(&ca_pem).unwrap())
+        .unwrap();
+    let tls_config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    let tls = tokio_rustls::TlsConnector::from(Arc::new(tls_config))
+        .connect(ServerName::try_from("localhost").unwrap(), stream)
+        .await
+        .unwrap();
+
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(tls))
+        .await
+        .unwrap();
+    tokio::spawn(conn);
+    let req = Request::builder()
+        .uri("/secret?x=1")
+        .header("host", "localhost")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    // Nothing listens on port 1, so the upstream leg fails after decryption.
+    let response = sender.send_request(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+    let logs = state.get_request_logs();
+    let decrypted = logs.iter().find(|log| log.method == "GET").unwrap();
+    assert_eq!(decrypted.path, "/secret");
+    assert_eq!(decrypted.upstream, "https://localhost:1/secret?x=1");
+    assert!(state.get_tunnel_logs().is_empty());
+
+    std::fs::remove_dir_all(&ca_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_fault_status_and_flag() {
+    let (upstream, hits) = start_upstream().await;
+    let (proxy, state) = start_proxy_with(
+        upstream,
+        "",
+        r#"
+            [[routes.faults]]
+            type = "status"
+            path_prefix = "/echo"
+            status = 503
+            body = "injected"
+        "#,
+    )
+    .await;
+
+    let req = request(Method::GET).body(Full::new(Bytes::new())).unwrap();
+    let response = send(proxy, req).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.body(), "injected");
+    assert_eq!(hits.load(Ordering::SeqCst), 0);
+
+    let logs = state.get_request_logs();
+    assert_eq!(logs[0].fault.as_deref(), Some("status 503"));
+
+    // Disabled rules stop firing.
+    assert!(state.faults.set_enabled("test", 0, false));
+    let req = request(Method::GET).body(Full::new(Bytes::new())).unwrap();
+    assert_eq!(send(proxy, req).await.status(), StatusCode::OK);
+    assert_eq!(state.get_request_logs()[1].fault, None);
+}
+
+#[tokio::test]
+async fn test_fault_reset_and_truncate() {
+    let (upstream, _) = start_upstream().await;
+    let (proxy, state) = start_proxy_with(
+        upstream,
+        "",
+        r#"
+            [[routes.faults]]
+            type = "reset"
+            method = "DELETE"
+
+            [[routes.faults]]
+            type = "truncate"
+            after_bytes = 4
+        "#,
+    )
+    .await;
+
+    let stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .unwrap();
+    tokio::spawn(conn);
+    let req = request(Method::DELETE)
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    assert!(sender.send_request(req).await.is_err());
+
+    let stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .unwrap();
+    tokio::spawn(conn);
+    let req = request(Method::GET).body(Full::new(Bytes::new())).unwrap();
+    let response = sender.send_request(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.into_body().collect().await.is_err());
+
+    let faults: Vec<_> = state
+        .get_request_logs()
+        .into_iter()
+        .map(|log| log.fault)
+        .collect();
+    assert_eq!(
+        faults,
+        [
+            Some("reset".to_string()),
+            Some("truncate after 4 bytes".to_string())
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_rate_limit_per_client_key() {
+    let (upstream, hits) = start_upstream().await;
+    let (proxy, state) = start_proxy_with(
+        upstream,
+        "",
+        r#"
+            [routes.rate_limit]
+            requests_per_second = 0.01
+            burst = 1
+            key = { header = "x-api-key" }
+        "#,
+    )
+    .await;
+
+    let keyed = |key: &'static str| {
+        request(Method::GET)
+            .header("x-api-key", key)
+            .body(Full::new(Bytes::new()))
+            .unwrap()
+    };
+    assert_eq!(send(proxy, keyed("a")).await.status(), StatusCode::OK);
+
+    let response = send(proxy, keyed("a")).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()[hyper::header::RETRY_AFTER], "100");
+
+    assert_eq!(send(proxy, keyed("b")).await.status(), StatusCode::OK);
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+    let stats = state.rate_limits.stats();
+    assert_eq!(stats.len(), 2);
+    assert_eq!((stats[0].allowed, stats[0].limited), (1, 1));
+}
+
+/// Upstream that accepts connections but never answers, keeping requests in
+/// flight for as long as the test needs.
+async fn start_stalled_upstream() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            held.push(stream);
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_concurrency_limit_queues_and_sheds() {
+    for (table, scope) in [
+        ("[routes.concurrency]", "route"),
+        ("[routes.upstreams.concurrency]", "upstream"),
+    ] {
+        let upstream = start_stalled_upstream().await;
+        let (proxy, state) = start_proxy_with(
+            upstream,
+            "",
+            &format!("{table}\nmax_in_flight = 1\nqueue_size = 1\nqueue_timeout_ms = 100"),
+        )
+        .await;
+        let get = || request(Method::GET).body(Full::new(Bytes::new())).unwrap();
+
+        let stuck = tokio::spawn(send(proxy, get()));
+        while state.concurrency.stats()[0].in_flight == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let queued = tokio::spawn(send(proxy, get()));
+        while state.concurrency.stats()[0].queued == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let shed = send(proxy, get()).await;
+        assert_eq!(shed.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(shed.body(), "Server busy");
+        let timed_out = queued.await.unwrap();
+        assert_eq!(timed_out.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(timed_out.body(), "Timed out waiting for capacity");
+
+        let stats = &state.concurrency.stats()[0];
+        assert_eq!(stats.scope, scope);
+        assert_eq!((stats.in_flight, stats.queued), (1, 0));
+        assert_eq!((stats.shed, stats.timed_out), (1, 1));
+
+        stuck.abort();
+    }
+}
+
+#[tokio::test]
+async fn test_max_connections_holds_back_new_clients() {
+    let (upstream, _) = start_upstream().await;
+    let (proxy, state) = start_proxy_with(upstream, "max_connections = 1", "").await;
+
+    let stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .unwrap();
+    let first = tokio::spawn(conn);
+    let req = request(Method::GET).body(Full::new(Bytes::new())).unwrap();
+    assert_eq!(
+        sender.send_request(req).await.unwrap().status(),
+        StatusCode::OK
+    );
+
+    let second = tokio::spawn(send(
+        proxy,
+        request(Method::GET).body(Full::new(Bytes::new())).unwrap(),
+    ));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!second.is_finished());
+    assert_eq!(state.concurrency.connection_stats().open, 1);
+
+    drop(sender);
+    first.await.unwrap().unwrap();
+    assert_eq!(second.await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_upstream_timeouts_answer_504() {
+    for (extra, route_tables) in [
+        ("", "[routes.timeouts]\nresponse_header_ms = 100"),
+        ("[timeouts]\nrequest_ms = 100", ""),
+    ] {
+        let upstream = start_stalled_upstream().await;
+        let (proxy, state) = start_proxy_with(upstream, extra, route_tables).await;
+
+        let req = request(Method::GET).body(Full::new(Bytes::new())).unwrap();
+        let response = send(proxy, req).await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        let timeouts = state.get_metrics().timeouts;
+        let counted = if route_tables.is_empty() {
+            timeouts.request
+        } else {
+            timeouts.response_header
+        };
+        assert_eq!(counted, 1);
+        assert_eq!(state.get_metrics().total_errors, 1);
+    }
+}
+
+#[tokio::test]
+async fn test_request_deadline_starts_at_dispatch() {
+    let (upstream, hits) = start_upstream().await;
+    // The 3g profile holds each request for 100-200ms before it goes out.
+    let extra = "[timeouts]\nrequest_ms = 80\n\n[throttle]\nactive = \"3g\"";
+    let (proxy, state) = start_proxy_with(upstream, extra, "").await;
+
+    let req = request(Method::GET).body(Full::new(Bytes::new())).unwrap();
+    let response = send(proxy, req).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    response.into_body().collect().await.unwrap();
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+    assert_eq!(state.get_metrics().timeouts.request, 0);
+}
+
+#[tokio::test]
+async fn test_client_timeouts() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let upstream = start_stalled_upstream().await;
+    let (proxy, state) = start_proxy_with(
+        upstream,
+        "[timeouts]\nheader_read_ms = 100",
+        "[routes.timeouts]\nbody_idle_ms = 100",
+    )
+    .await;
+
+    // A body that stops short of its Content-Length gets a 408.
+    let mut stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    stream
+        .write_all(b"POST /echo HTTP/1.1\r\nhost: example.com\r\ncontent-length: 10\r\n\r\nab")
+        .await
+        .unwrap();
+    let mut head = [0; 12];
+    stream.read_exact(&mut head).await.unwrap();
+    assert_eq!(&head, b"HTTP/1.1 408");
+    assert_eq!(state.get_metrics().timeouts.request_body, 1);
+
+    // A client that never finishes its headers is disconnected.
+    let mut stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    stream
+        .write_all(b"GET /echo HTTP/1.1\r\nhost: exa")
+        .await
+        .unwrap();
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+    while state.get_metrics().timeouts.header_read == 0 {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_idle_keep_alive_is_not_a_timeout() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (upstream, _) = start_upstream().await;
+    let (proxy, state) = start_proxy_with(upstream, "[timeouts]\nheader_read_ms = 100", "").await;
+
+    // One full exchange, then the client keeps the connection open and
+    // idle until the proxy closes it.
+    let mut stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    stream
+        .write_all(b"GET /echo HTTP/1.1\r\nhost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).await.unwrap();
+    assert!(received.starts_with(b"HTTP/1.1 200"));
+    // The connection task counts errors just after the socket closes.
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let metrics = state.get_metrics();
+    assert_eq!(metrics.timeouts.header_read, 0);
+    assert_eq!(metrics.total_errors, 0);
+}
+
+/// Upstream answering every request with `status`.
+async fn start_status_upstream(status: StatusCode) -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&hits);
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let counter = Arc::clone(&counter);
+            tokio::spawn(async move {
+                let service = service_fn(move |_req: Request<Incoming>| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let mut response = Response::new(Full::new(Bytes::from_static(b"failed")));
+                    *response.status_mut() = status;
+                    async move { Ok::<_, std::convert::Infallible>(response) }
+                });
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+    (addr, hits)
+}
+
+#[tokio::test]
+async fn test_retry_moves_to_next_upstream() {
+    let (healthy, hits) = start_upstream().await;
+    let refused = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let (proxy, state) = start_proxy_with(
+        refused,
+        "",
+        &format!(
+            "[[routes.upstreams]]\nurl = \"http://{healthy}\"\n\n[routes.retry]\nbackoff_ms = 1"
+        ),
+    )
+    .await;
+
+    // Nothing reached the refused upstream, so even a POST is resent.
+    let req = request(Method::POST)
+        .body(Full::new(Bytes::from_static(b"payload")))
+        .unwrap();
+    let response = send(proxy, req).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+    let log = &state.get_request_logs()[0];
+    assert_eq!(log.attempts, 2);
+    assert_eq!(log.upstream, format!("http://{healthy}"));
+}
+
+#[tokio::test]
+async fn test_retry_status_only_for_idempotent_methods() {
+    let (failing, failures) = start_status_upstream(StatusCode::SERVICE_UNAVAILABLE).await;
+    // With one upstream, retries go back to it; keep its circuit closed.
+    let (proxy, state) = start_proxy_with(
+        failing,
+        "",
+        "fail_threshold = 100\n\n[routes.retry]\nbackoff_ms = 1",
+    )
+    .await;
+
+    let req = request(Method::PUT).body(Full::new(Bytes::new())).unwrap();
+    assert_eq!(
+        send(proxy, req).await.status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+    assert_eq!(failures.load(Ordering::SeqCst), 3);
+
+    let req = request(Method::POST).body(Full::new(Bytes::new())).unwrap();
+    assert_eq!(
+        send(proxy, req).await.status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+
+    assert_eq!(failures.load(Ordering::SeqCst), 4);
+    let attempts: Vec<u32> = state
+        .get_request_logs()
+        .iter()
+        .map(|log| log.attempts)
+        .collect();
+    assert_eq!(attempts, vec![3, 1]);
+}
+
+async fn start_balanced_proxy(route_keys: &str, upstreams: &[SocketAddr]) -> SocketAddr {
+    let upstream_tables: String = upstreams
+        .iter()
+        .map(|addr| format!("[[routes.upstreams]]\nurl = \"http://{addr}\"\n"))
+        .collect();
+    let toml_str = format!(
+        r#"
+            listen = "127.0.0.1:0"
+
+            [control]
+            listen = "127.0.0.1:0"
+
+            [[routes]]
+            name = "test"
+            hosts = ["example.com"]
+            {route_keys}
+
+            {upstream_tables}
+        "#
+    );
+    let config: Config = toml::from_str(&toml_str).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = ProxyServer::new(config, SharedState::new()).unwrap();
+    tokio::spawn(server.serve(listener, None));
+    addr
+}
+
+#[tokio::test]
+async fn test_balancers_spread_and_stick() {
+    let (a, a_hits) = start_upstream().await;
+    let (b, b_hits) = start_upstream().await;
+    let get = |user: &str| {
+        request(Method::GET)
+            .header("x-user", user)
+            .body(Full::new(Bytes::new()))
+            .unwrap()
+    };
+
+    let proxy = start_balanced_proxy("", &[a, b]).await;
+    for _ in 0..4 {
+        assert_eq!(send(proxy, get("alice")).await.status(), StatusCode::OK);
+    }
+    assert_eq!(a_hits.load(Ordering::SeqCst), 2);
+    assert_eq!(b_hits.load(Ordering::SeqCst), 2);
+
+    let proxy = start_balanced_proxy(
+        "balancer = \"consistent_hash\"\nhash_key = { header = \"X-User\" }",
+        &[a, b],
+    )
+    .await;
+    for user in ["alice", "bob", "carol", "dave"] {
+        let before = (a_hits.load(Ordering::SeqCst), b_hits.load(Ordering::SeqCst));
+        for _ in 0..3 {
+            send(proxy, get(user)).await;
+        }
+        let after = (a_hits.load(Ordering::SeqCst), b_hits.load(Ordering::SeqCst));
+        assert!(
+            after == (before.0 + 3, before.1) || after == (before.0, before.1 + 3),
+            "{user} moved between upstreams"
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_circuit_opens_after_failures() {
+    let (failing, hits) = start_status_upstream(StatusCode::INTERNAL_SERVER_ERROR).await;
+    let (proxy, state) = start_proxy_with(failing, "", "fail_threshold = 2").await;
+    let get = || request(Method::GET).body(Full::new(Bytes::new())).unwrap();
+
+    for _ in 0..2 {
+        assert_eq!(
+            send(proxy, get()).await.status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+    let response = send(proxy, get()).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.body(), "No upstream available");
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+    let status = &state.get_metrics().upstreams_status[0];
+    assert_eq!(status.circuit, CircuitState::Open);
+    assert!(!status.healthy);
+}
+
+#[tokio::test]
+async fn test_shared_upstream_listed_once() {
+    let (upstream, _) = start_upstream().await;
+    let other_route = format!(
+        "[[routes]]\nname = \"other\"\nhosts = [\"other.com\"]\nupstreams = [{{ url = \"http://{upstream}\" }}]"
+    );
+    let (_, state) = start_proxy_with(upstream, &other_route, "").await;
+
+    let statuses = state.get_metrics().upstreams_status;
+    assert_eq!(statuses.len(), 1);
+    assert_eq!(statuses[0].url, format!("http://{upstream}"));
+}
+
+#[tokio::test]
+async fn test_sticky_cookie_pins_upstream() {
+    let (a, a_hits) = start_upstream().await;
+    let (b, b_hits) = start_upstream().await;
+    let proxy = start_balanced_proxy("sticky = { cookie = \"sid\" }", &[a, b]).await;
+    let get = |cookie: Option<&str>| {
+        let mut req = request(Method::GET);
+        if let Some(cookie) = cookie {
+            req = req.header("cookie", cookie);
+        }
+        req.body(Full::new(Bytes::new())).unwrap()
+    };
+
+    let first = send(proxy, get(None)).await;
+    let set_cookie = first.headers()["set-cookie"].to_str().unwrap();
+    assert!(set_cookie.starts_with("sid="), "{set_cookie}");
+    let cookie = set_cookie.split(';').next().unwrap().to_string();
+    let pinned_hits = if a_hits.load(Ordering::SeqCst) == 1 {
+        &a_hits
+    } else {
+        &b_hits
+    };
+
+    // Round-robin alone would alternate; the cookie keeps one upstream.
+    for _ in 0..4 {
+        let response = send(proxy, get(Some(&cookie))).await;
+        assert!(response.headers().get("set-cookie").is_none());
+    }
+    assert_eq!(pinned_hits.load(Ordering::SeqCst), 5);
+
+    let response = send(proxy, get(Some("sid=stale"))).await;
+    assert!(response.headers().contains_key("set-cookie"));
+}
+
+#[tokio::test]
+async fn test_canary_split_and_override() {
+    let (stable, stable_hits) = start_upstream().await;
+    let (canary, canary_hits) = start_upstream().await;
+    let canary_keys = |percent: u32| {
+        format!(
+            "[routes.canary]\npercent = {percent}\nheader = {{ name = \"x-canary\", value = \"1\" }}\n\
+             [[routes.canary.upstreams]]\nurl = \"http://{canary}\""
+        )
+    };
+    let get = |forced: bool| {
+        let mut req = request(Method::GET);
+        if forced {
+            req = req.header("x-canary", "1");
+        }
+        req.body(Full::new(Bytes::new())).unwrap()
+    };
+
+    let proxy = start_balanced_proxy(&canary_keys(0), &[stable]).await;
+    for _ in 0..3 {
+        send(proxy, get(false)).await;
+    }
+    send(proxy, get(true)).await;
+    assert_eq!(stable_hits.load(Ordering::SeqCst), 3);
+    assert_eq!(canary_hits.load(Ordering::SeqCst), 1);
+
+    let proxy = start_balanced_proxy(&canary_keys(100), &[stable]).await;
+    for _ in 0..3 {
+        assert_eq!(send(proxy, get(false)).await.status(), StatusCode::OK);
+    }
+    assert_eq!(stable_hits.load(Ordering::SeqCst), 3);
+    assert_eq!(canary_hits.load(Ordering::SeqCst), 4);
+}
+
+#[tokio::test]
+async fn test_cache_serves_hits_and_invalidates() {
+    let (upstream, hits) = start_upstream().await;
+    let (proxy, state) =
+        start_proxy_with(upstream, "", "[routes.cache]\ndefault_ttl_secs = 60").await;
+    let call = |method: Method| request(method).body(Full::new(Bytes::new())).unwrap();
+
+    let first = send(proxy, call(Method::GET)).await;
+    let second = send(proxy, call(Method::GET)).await;
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+    assert_eq!(second.body(), first.body());
+    assert_eq!(second.headers()["age"], "0");
+
+    let uncached = request(Method::GET)
+        .header("cache-control", "no-store")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    send(proxy, uncached).await;
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+    // A successful POST to the same resource drops the stored copy.
+    send(proxy, call(Method::POST)).await;
+    send(proxy, call(Method::GET)).await;
+    assert_eq!(hits.load(Ordering::SeqCst), 4);
+
+    let statuses: Vec<_> = state
+        .get_request_logs()
+        .iter()
+        .map(|log| log.cache)
+        .collect();
+    use crate::cache::CacheStatus::*;
+    assert_eq!(
+        statuses,
+        [
+            Some(Miss),
+            Some(Hit),
+            Some(Bypass),
+            Some(Bypass),
+            Some(Miss)
+        ]
+    );
+    let stats = &state.cache.stats()["test"];
+    assert_eq!((stats.hits, stats.misses, stats.bypassed), (1, 2, 2));
+    assert_eq!(state.cache.purge(None, Some("example.com/")), 1);
+}
+
+#[tokio::test]
+async fn test_mirror_copies_request_and_records_diff() {
+    let (upstream, hits) = start_upstream().await;
+    let (shadow, shadow_hits) = start_status_upstream(StatusCode::INTERNAL_SERVER_ERROR).await;
+    let (proxy, state) = start_proxy_with(
+        upstream,
+        "",
+        &format!("[routes.mirror]\nurl = \"http://{shadow}\"\ncompare = true"),
+    )
+    .await;
+
+    let req = request(Method::POST)
+        .body(Full::new(Bytes::from("payload")))
+        .unwrap();
+    let response = send(proxy, req).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.body().starts_with("POST /echo\n"));
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+    let mut diff = None;
+    for _ in 0..50 {
+        diff = state.get_request_logs()[0].mirror.clone();
+        if diff.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let diff = diff.expect("mirror diff recorded");
+    assert_eq!(shadow_hits.load(Ordering::SeqCst), 1);
+    assert_eq!((diff.primary_status, diff.shadow_status), (200, 500));
+    assert_eq!(diff.body_matches, Some(false));
+    assert!(diff.differs());
+}
+
+#[tokio::test]
+async fn test_failing_mirror_stays_out_of_proxy_metrics() {
+    let (upstream, _) = start_upstream().await;
+    let refused = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let (proxy, state) = start_proxy_with(
+        upstream,
+        "",
+        &format!("[routes.mirror]\nurl = \"http://{refused}\"\ncompare = true"),
+    )
+    .await;
+
+    let req = request(Method::GET).body(Full::new(Bytes::new())).unwrap();
+    assert_eq!(send(proxy, req).await.status(), StatusCode::OK);
+
+    let mut diff = None;
+    for _ in 0..50 {
+        diff = state.get_request_logs()[0].mirror.clone();
+        if diff.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(diff.expect("mirror diff recorded").shadow_status, 502);
+    let metrics = state.get_metrics();
+    assert_eq!((metrics.total_errors, metrics.mirror_errors), (0, 1));
+    assert_eq!(metrics.timeouts.connect, 0);
+}
+
+#[tokio::test]
+async fn test_regex_route_rewrites_with_captures() {
+    let (upstream, hits) = start_upstream().await;
+    let proxy = start_balanced_proxy(
+        r#"path_regex = '^/(?P<name>\w+)$'
+rewrite_path = "/v2/${name}/items"
+match = { methods = ["GET"], query = { tenant = "*" } }"#,
+        &[upstream],
+    )
+    .await;
+
+    let req = Request::builder()
+        .uri("/echo?tenant=a")
+        .header("host", "example.com")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let response = send(proxy, req).await;
+    assert!(
+        response.body().starts_with("GET /v2/echo/items?tenant=a\n"),
+        "{}",
+        response.body()
+    );
+
+    let req = request(Method::GET).body(Full::new(Bytes::new())).unwrap();
+    assert_eq!(send(proxy, req).await.status(), StatusCode::NOT_FOUND);
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_paths_normalized_before_matching() {
+    let (upstream, hits) = start_upstream().await;
+    let proxy = start_balanced_proxy(
+        "path_prefix = \"/api\"\nstrip_prefix = true\nrewrite_prefix = \"/v1/\"",
+        &[upstream],
+    )
+    .await;
+    let get = |path: &str| {
+        Request::builder()
+            .uri(path)
+            .header("host", "example.com")
+            .body(Full::new(Bytes::new()))
+            .unwrap()
+    };
+
+    let cases = [
+        ("/api/users?q=1", "GET /v1/users?q=1\n"),
+        ("/api", "GET /v1/\n"),
+        ("/api//a%2Fb/./c", "GET /v1/a%2Fb/c\n"),
+        ("/x/%2e%2e/api/users", "GET /v1/users\n"),
+    ];
+    for (path, echoed) in cases {
+        let response = send(proxy, get(path)).await;
+        assert!(
+            response.body().starts_with(echoed),
+            "{path}: {}",
+            response.body()
+        );
+    }
+
+    for (path, status) in [
+        ("/apiv2/secret", StatusCode::NOT_FOUND),
+        ("/api/../../etc/passwd", StatusCode::BAD_REQUEST),
+    ] {
+        assert_eq!(send(proxy, get(path)).await.status(), status, "{path}");
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), 4);
+}
+
+#[tokio::test]
+async fn test_map_local_serves_directory_under_prefix() {
+    let dir = std::env::temp_dir().join(format!("charles-map-local-e2e-{}", std::process::id()));
+    tokio::fs::create_dir_all(&dir).await.unwrap();
+    tokio::fs::write(dir.join("app.json"), "{}").await.unwrap();
+    tokio::fs::write(dir.join("a b.txt"), "spaced")
+        .await
+        .unwrap();
+
+    let (upstream, hits) = start_upstream().await;
+    let extra = format!(
+        "[[map_local]]\nhost = \"example.com\"\npath_prefix = \"/static\"\npath = {:?}",
+        dir.to_string_lossy()
+    );
+    let proxy = start_proxy(upstream, &extra).await;
+    let get = |path: &str| {
+        Request::builder()
+            .uri(path)
+            .header("host", "example.com")
+            .body(Full::new(Bytes::new()))
+            .unwrap()
+    };
+
+    let response = send(proxy, get("/static/app.json")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.body(), "{}");
+    assert_eq!(send(proxy, get("/static/a%20b.txt")).await.body(), "spaced");
+    for path in ["/static/..%2f..%2fsecret", "/static/%5c..%5csecret"] {
+        let response = send(proxy, get(path)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN, "{path}");
+    }
+    // Only whole segments match the prefix.
+    let response = send(proxy, get("/staticfiles/app.json")).await;
+    assert!(response.body().starts_with("GET /staticfiles/app.json\n"));
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+    tokio::fs::remove_dir_all(&dir).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_breakpoint_edits_request_or_times_out() {
+    let (upstream, hits) = start_upstream().await;
+    let breakpoints = r#"
+        [breakpoints]
+        timeout_secs = 1
+
+        [[breakpoints.rules]]
+        host = "example.com"
+        path_prefix = "/echo"
+        method = "POST"
+    "#;
+    let (proxy, state) = start_proxy_with(upstream, breakpoints, "").await;
+    let post = || {
+        request(Method::POST)
+            .body(Full::new(Bytes::from("original")))
+            .unwrap()
+    };
+    let wait_for_pause = || async {
+        loop {
+            if let Some(paused) = state.breakpoints.get_pending().pop() {
+                return paused;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    };
+
+    // Edits made while paused reach the upstream.
+    let sent = tokio::spawn(send(proxy, post()));
+    let paused = wait_for_pause().await;
+    assert_eq!(paused.body, "original");
+    assert_eq!(hits.load(Ordering::SeqCst), 0);
+    let mut headers = paused.headers.clone();
+    headers.push(("x-edited".to_string(), "1".to_string()));
+    let edits = BreakpointEdits {
+        headers: Some(headers),
+        body: Some("edited body".to_string()),
+        ..Default::default()
+    };
+    assert!(
+        state
+            .breakpoints
+            .resolve(paused.id, BreakpointDecision::Continue(edits))
+    );
+    let response = sent.await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(echoed_header(response.body(), "x-edited"), Some("1"));
+    assert_eq!(echoed_header(response.body(), "content-length"), Some("11"));
+
+    // Left alone, the request passes through unchanged once the pause ends.
+    let sent = tokio::spawn(send(proxy, post()));
+    wait_for_pause().await;
+    let response = sent.await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(echoed_header(response.body(), "x-edited"), None);
+    assert_eq!(echoed_header(response.body(), "content-length"), Some("8"));
+    assert!(state.breakpoints.get_pending().is_empty());
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_set_edited_body_framing() {
+    use hyper::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
+
+    let original = || {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(4));
+        headers
+    };
+    let body = Bytes::from_static(b"abcd");
+
+    let mut headers = original();
+    headers.insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+    let edited = set_edited_body(&mut headers, body.clone(), Some("xy".to_string()), false);
+    assert_eq!(edited, "xy");
+    assert_eq!(headers[CONTENT_LENGTH], "2");
+    assert!(!headers.contains_key(TRANSFER_ENCODING));
+
+    // Unedited bodies and bodiless messages keep their framing.
+    for (edit, bodiless) in [(None, false), (Some("xy".to_string()), true)] {
+        let mut headers = original();
+        assert_eq!(
+            set_edited_body(&mut headers, body.clone(), edit, bodiless),
+            body
+        );
+        assert_eq!(headers, original());
+    }
+}
+
+fn gunzip(body: &[u8]) -> String {
+    use std::io::Read;
+    let mut text = String::new();
+    flate2::read::GzDecoder::new(body)
+        .read_to_string(&mut text)
+        .unwrap();
+    text
+}
+
+#[tokio::test]
+async fn test_compression_follows_accept_encoding() {
+    let (upstream, _) = start_upstream().await;
+    let proxy = start_proxy_with(
+        upstream,
+        "",
+        "[routes.response_headers]\nset = { \"content-type\" = \"text/plain\" }\n\n\
+         [routes.compression]\nencodings = [\"gzip\"]\nmin_bytes = 10",
+    )
+    .await
+    .0;
+
+    let req = request(Method::GET)
+        .header("accept-encoding", "br, gzip;q=0.8")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let response = send_raw(proxy, req).await;
+    assert_eq!(response.headers()["content-encoding"], "gzip");
+    assert_eq!(response.headers()["vary"], "accept-encoding");
+    assert!(gunzip(response.body()).starts_with("GET /echo\n"));
+
+    let plain = send(
+        proxy,
+        request(Method::GET).body(Full::new(Bytes::new())).unwrap(),
+    )
+    .await;
+    assert!(!plain.headers().contains_key("content-encoding"));
+    assert!(plain.body().starts_with("GET /echo\n"));
+}
+
+/// Upstream answering every request with `text` gzip-encoded.
+async fn start_gzip_upstream(text: &'static str) -> SocketAddr {
+    use std::io::Write;
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(text.as_bytes()).unwrap();
+    let encoded = Bytes::from(encoder.finish().unwrap());
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let encoded = encoded.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |_req: Request<Incoming>| {
+                    let response = Response::builder()
+                        .header("content-type", "text/html")
+                        .header("content-encoding", "gzip")
+                        .body(Full::new(encoded.clone()))
+                        .unwrap();
+                    async move { Ok::<_, std::convert::Infallible>(response) }
+                });
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_decompress_makes_upstream_bodies_readable() {
+    let text = "<p>hello from a compressing upstream</p>";
+    let upstream = start_gzip_upstream(text).await;
+    let proxy = start_proxy_with(
+        upstream,
+        "",
+        "[routes.compression]\nencodings = []\ndecompress = true",
+    )
+    .await
+    .0;
+
+    let req = request(Method::GET)
+        .header("accept-encoding", "gzip")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let response = send(proxy, req).await;
+    assert!(!response.headers().contains_key("content-encoding"));
+    assert_eq!(response.body(), text);
+}
+
+#[tokio::test]
+async fn test_cache_hits_render_response_headers_per_client() {
+    let (upstream, hits) = start_upstream().await;
+    let proxy = start_proxy_with(
+        upstream,
+        "",
+        "[routes.response_headers]\nset = { \"x-client\" = \"{client_ip}\", \"x-request\" = \"{request_id}\" }\n\n\
+         [routes.cache]\ndefault_ttl_secs = 60",
+    )
+    .await
+    .0;
+    let call = || request(Method::GET).body(Full::new(Bytes::new())).unwrap();
+
+    let first = send_from("127.0.0.1".parse().unwrap(), proxy, call()).await;
+    let second = send_from("127.0.0.2".parse().unwrap(), proxy, call()).await;
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+    assert_eq!(first.headers()["x-client"], "127.0.0.1");
+    assert_eq!(second.headers()["x-client"], "127.0.0.2");
+    assert_ne!(first.headers()["x-request"], second.headers()["x-request"]);
+}
@@ -0,0 +1,245 @@
+use crate::config::{RateLimit, RateLimitKey};
+use hyper::Request;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Buckets kept before the least recently used are dropped.
+const MAX_BUCKETS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Position in `Buckets::recency`.
+    used: u64,
+    allowed: u64,
+    limited: u64,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.requests_per_second).min(limit.burst as f64);
+        self.updated = now;
+    }
+}
+
+/// Snapshot of one bucket for `/metrics`.
+#[derive(Debug, Clone, Serialize)]
+pub struct BucketStats {
+    pub route: String,
+    pub key: String,
+    pub tokens: f64,
+    pub allowed: u64,
+    pub limited: u64,
+}
+
+type BucketKey = (String, String);
+
+#[derive(Default)]
+struct Buckets {
+    map: HashMap<BucketKey, (RateLimit, Bucket)>,
+    /// Keys by last use, oldest first.
+    recency: BTreeMap<u64, BucketKey>,
+    next_use: u64,
+}
+
+/// Token buckets per route and client key.
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self {
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    /// Takes a token for `key` on `route`. `Err` carries how long until the
+    /// next token is available.
+    pub fn check(&self, route: &str, limit: &RateLimit, key: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut guard = self.buckets.lock();
+        let buckets = &mut *guard;
+        let used = buckets.next_use;
+        buckets.next_use += 1;
+        let bucket_key = (route.to_string(), key.to_string());
+        if !buckets.map.contains_key(&bucket_key) {
+            while buckets.map.len() >= MAX_BUCKETS
+                && let Some((_, oldest)) = buckets.recency.pop_first()
+            {
+                buckets.map.remove(&oldest);
+            }
+        }
+
+        let (stored, bucket) = buckets.map.entry(bucket_key.clone()).or_insert_with(|| {
+            (
+                limit.clone(),
+                Bucket {
+                    tokens: limit.burst as f64,
+                    updated: now,
+                    used,
+                    allowed: 0,
+                    limited: 0,
+                },
+            )
+        });
+        buckets.recency.remove(&bucket.used);
+        buckets.recency.insert(used, bucket_key);
+        bucket.used = used;
+        *stored = limit.clone();
+        bucket.refill(limit, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.allowed += 1;
+            Ok(())
+        } else {
+            bucket.limited += 1;
+            let missing = 1.0 - bucket.tokens;
+            Err(
+                Duration::try_from_secs_f64(missing / limit.requests_per_second)
+                    .unwrap_or(Duration::MAX),
+            )
+        }
+    }
+
+    pub fn stats(&self) -> Vec<BucketStats> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock();
+        let mut stats: Vec<BucketStats> = buckets
+            .map
+            .iter_mut()
+            .map(|((route, key), (limit, bucket))| {
+                bucket.refill(limit, now);
+                BucketStats {
+                    route: route.clone(),
+                    key: key.clone(),
+                    tokens: bucket.tokens,
+                    allowed: bucket.allowed,
+                    limited: bucket.limited,
+                }
+            })
+            .collect();
+        stats.sort_by(|a, b| (&a.route, &a.key).cmp(&(&b.route, &b.key)));
+        stats
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The bucket key for `req`, falling back to the client IP.
+pub fn client_key<B>(key: &RateLimitKey, req: &Request<B>, client_ip: IpAddr) -> String {
+    let value = match key {
+        RateLimitKey::ClientIp => None,
+        RateLimitKey::Header(name) => req
+            .headers()
+            .get(name.as_str())
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string),
+        RateLimitKey::Query(name) => req.uri().query().and_then(|query| {
+            query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.to_string())
+        }),
+    };
+    value.unwrap_or_else(|| client_ip.to_string())
+}
+
+/// Whole seconds for `Retry-After`, rounded up.
+pub fn retry_after_secs(wait: Duration) -> u64 {
+    wait.as_secs_f64().ceil().max(1.0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(requests_per_second: f64, burst: u32) -> RateLimit {
+        RateLimit {
+            requests_per_second,
+            burst,
+            key: RateLimitKey::ClientIp,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bucket_refills_over_time() {
+        let limiter = RateLimiter::new();
+        let limit = limit(2.0, 3);
+
+        for _ in 0..3 {
+            assert!(limiter.check("api", &limit, "10.0.0.1").is_ok());
+        }
+        let wait = limiter.check("api", &limit, "10.0.0.1").unwrap_err();
+        assert_eq!(wait, Duration::from_millis(500));
+        assert_eq!(retry_after_secs(wait), 1);
+
+        // Other clients and routes have their own buckets.
+        assert!(limiter.check("api", &limit, "10.0.0.2").is_ok());
+        assert!(limiter.check("web", &limit, "10.0.0.1").is_ok());
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert!(limiter.check("api", &limit, "10.0.0.1").is_ok());
+        assert!(limiter.check("api", &limit, "10.0.0.1").is_err());
+
+        // Refill never exceeds the burst size.
+        tokio::time::advance(Duration::from_secs(60)).await;
+        let stats = limiter.stats();
+        assert_eq!(stats[0].route, "api");
+        assert_eq!(stats[0].key, "10.0.0.1");
+        assert_eq!(stats[0].tokens, 3.0);
+        assert_eq!((stats[0].allowed, stats[0].limited), (4, 2));
+    }
+
+    #[test]
+    fn test_least_recently_used_buckets_go_first() {
+        let limiter = RateLimiter::new();
+        let limit = limit(1.0, 5);
+        for i in 0..MAX_BUCKETS {
+            assert!(limiter.check("api", &limit, &i.to_string()).is_ok());
+        }
+        // Touch the oldest so the next one in line is evicted instead.
+        assert!(limiter.check("api", &limit, "0").is_ok());
+        assert!(limiter.check("api", &limit, "new").is_ok());
+
+        let stats = limiter.stats();
+        assert_eq!(stats.len(), MAX_BUCKETS);
+        assert!(stats.iter().any(|s| s.key == "0" && s.allowed == 2));
+        assert!(!stats.iter().any(|s| s.key == "1"));
+    }
+
+    #[test]
+    fn test_client_key() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let req = Request::builder()
+            .uri("/items?page=2&api_key=abc")
+            .header("x-api-key", "xyz")
+            .body(())
+            .unwrap();
+
+        assert_eq!(client_key(&RateLimitKey::ClientIp, &req, ip), "10.0.0.1");
+        assert_eq!(
+            client_key(&RateLimitKey::Header("X-Api-Key".to_string()), &req, ip),
+            "xyz"
+        );
+        assert_eq!(
+            client_key(&RateLimitKey::Query("api_key".to_string()), &req, ip),
+            "abc"
+        );
+        assert_eq!(
+            client_key(&RateLimitKey::Query("token".to_string()), &req, ip),
+            "10.0.0.1"
+        );
+    }
+}
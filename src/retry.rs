@@ -0,0 +1,222 @@
+use crate::config::{RetryOn, RetryPolicy};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, combinators::BoxBody};
+use hyper::body::Body;
+use hyper::{Method, Request, Response};
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+type BoxedBody = BoxBody<Bytes, GenericError>;
+
+/// Retries a route may bank, and the allowance it starts with.
+const BUDGET_CAP: f64 = 10.0;
+
+/// Response extension on error responses made up by the proxy, saying why
+/// no upstream response could be relayed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The upstream could not be reached, so it never saw the request.
+    Connect,
+    /// The exchange failed after the request was sent.
+    Reset,
+    Timeout,
+    /// Another attempt would fail too: the client's body broke or the
+    /// request deadline passed.
+    Final,
+}
+
+pub fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+/// Whether `response` to a `method` request is worth another attempt.
+pub fn should_retry<B>(policy: &RetryPolicy, method: &Method, response: &Response<B>) -> bool {
+    let idempotent = is_idempotent(method);
+    match response.extensions().get::<Failure>() {
+        Some(Failure::Connect) => policy.retry_on.contains(&RetryOn::ConnectFailure),
+        Some(Failure::Reset) => idempotent && policy.retry_on.contains(&RetryOn::Reset),
+        Some(Failure::Timeout) => idempotent && policy.retry_on.contains(&RetryOn::Timeout),
+        Some(Failure::Final) => false,
+        None => idempotent && policy.statuses.contains(&response.status().as_u16()),
+    }
+}
+
+/// Wait before retry number `retry` (1-based): exponential with full jitter.
+pub fn backoff(policy: &RetryPolicy, retry: u32) -> Duration {
+    let ceiling = policy
+        .backoff_ms
+        .saturating_mul(1 << retry.saturating_sub(1).min(16))
+        .min(policy.max_backoff_ms);
+    Duration::from_millis(rand::random_range(0..=ceiling))
+}
+
+/// Whether the body of `req` has a known length of at most `limit` bytes.
+pub fn can_buffer<B: Body>(req: &Request<B>, limit: u64) -> bool {
+    req.body()
+        .size_hint()
+        .exact()
+        .is_some_and(|len| len <= limit)
+}
+
+/// A request held in memory so it can be sent more than once.
+pub struct Replay {
+    parts: hyper::http::request::Parts,
+    body: Bytes,
+}
+
+impl Replay {
+    pub async fn capture(req: Request<BoxedBody>) -> Result<Self, GenericError> {
+        let (parts, body) = req.into_parts();
+        let body = body.collect().await?.to_bytes();
+        Ok(Self { parts, body })
+    }
+
+    pub fn request(&self) -> Request<BoxedBody> {
+        let body = Full::new(self.body.clone())
+            .map_err(|never| match never {})
+            .boxed();
+        Request::from_parts(self.parts.clone(), body)
+    }
+}
+
+/// Per-route retry allowance. Each request earns `budget_ratio` of a retry
+/// and each retry spends one, so a failing upstream sees at most that share
+/// of extra traffic.
+pub struct RetryBudgets {
+    tokens: Mutex<BTreeMap<String, f64>>,
+}
+
+impl RetryBudgets {
+    pub fn new() -> Self {
+        Self {
+            tokens: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn deposit(&self, route: &str, ratio: f64) {
+        let mut tokens = self.tokens.lock();
+        let balance = tokens.entry(route.to_string()).or_insert(BUDGET_CAP);
+        *balance = (*balance + ratio).min(BUDGET_CAP);
+    }
+
+    /// Spends a retry, or returns false when the route has none left.
+    pub fn withdraw(&self, route: &str) -> bool {
+        let mut tokens = self.tokens.lock();
+        let balance = tokens.entry(route.to_string()).or_insert(BUDGET_CAP);
+        if *balance >= 1.0 {
+            *balance -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn stats(&self) -> BTreeMap<String, f64> {
+        self.tokens.lock().clone()
+    }
+}
+
+impl Default for RetryBudgets {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::StatusCode;
+
+    fn policy() -> RetryPolicy {
+        toml::from_str("retry_on = [\"connect_failure\", \"timeout\"]").unwrap()
+    }
+
+    fn response(status: StatusCode, failure: Option<Failure>) -> Response<()> {
+        let mut response = Response::new(());
+        *response.status_mut() = status;
+        if let Some(failure) = failure {
+            response.extensions_mut().insert(failure);
+        }
+        response
+    }
+
+    #[test]
+    fn test_should_retry_respects_idempotency() {
+        let policy = policy();
+        let cases = [
+            (
+                Method::POST,
+                StatusCode::BAD_GATEWAY,
+                Some(Failure::Connect),
+                true,
+            ),
+            (
+                Method::POST,
+                StatusCode::GATEWAY_TIMEOUT,
+                Some(Failure::Timeout),
+                false,
+            ),
+            (
+                Method::GET,
+                StatusCode::GATEWAY_TIMEOUT,
+                Some(Failure::Timeout),
+                true,
+            ),
+            (
+                Method::GET,
+                StatusCode::BAD_GATEWAY,
+                Some(Failure::Reset),
+                false,
+            ),
+            (
+                Method::GET,
+                StatusCode::GATEWAY_TIMEOUT,
+                Some(Failure::Final),
+                false,
+            ),
+            (Method::PUT, StatusCode::SERVICE_UNAVAILABLE, None, true),
+            (Method::PATCH, StatusCode::SERVICE_UNAVAILABLE, None, false),
+            (Method::GET, StatusCode::INTERNAL_SERVER_ERROR, None, false),
+        ];
+        for (method, status, failure, expected) in cases {
+            assert_eq!(
+                should_retry(&policy, &method, &response(status, failure)),
+                expected,
+                "{} {} {:?}",
+                method,
+                status,
+                failure
+            );
+        }
+    }
+
+    #[test]
+    fn test_backoff_capped() {
+        let policy = policy();
+        for retry in 1..40 {
+            assert!(backoff(&policy, retry) <= Duration::from_millis(policy.max_backoff_ms));
+        }
+        assert!(backoff(&policy, 1) <= Duration::from_millis(policy.backoff_ms));
+    }
+
+    #[test]
+    fn test_budget_limits_retries() {
+        let budgets = RetryBudgets::new();
+        for _ in 0..10 {
+            assert!(budgets.withdraw("api"));
+        }
+        assert!(!budgets.withdraw("api"));
+
+        for _ in 0..5 {
+            budgets.deposit("api", 0.2);
+        }
+        assert!(budgets.withdraw("api"));
+        assert!(!budgets.withdraw("api"));
+        assert!(budgets.withdraw("web"));
+    }
+}
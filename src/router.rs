@@ -0,0 +1,620 @@
+use crate::config::{Route, RouteConditions};
+use crate::url_path;
+use hyper::Request;
+use regex::Regex;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use tracing::warn;
+
+/// How a route's host patterns matched; later variants are more specific.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HostMatch {
+    /// A `*.suffix` pattern, carrying the suffix length.
+    Wildcard(usize),
+    Exact,
+}
+
+impl std::fmt::Display for HostMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HostMatch::Wildcard(_) => write!(f, "wildcard host"),
+            HostMatch::Exact => write!(f, "exact host"),
+        }
+    }
+}
+
+/// Why a route whose host and prefix matched still did not take a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection<'a> {
+    PathRegex,
+    Method,
+    Header(&'a str),
+    Query(&'a str),
+    ClientIp,
+}
+
+impl std::fmt::Display for Rejection<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rejection::PathRegex => write!(f, "path_regex did not match"),
+            Rejection::Method => write!(f, "method not in match.methods"),
+            Rejection::Header(name) => write!(f, "header {} missing or different", name),
+            Rejection::Query(name) => write!(f, "query parameter {} missing or different", name),
+            Rejection::ClientIp => write!(f, "client address outside match.client_cidrs"),
+        }
+    }
+}
+
+struct CompiledRoute {
+    route: Route,
+    path_regex: Option<Regex>,
+}
+
+impl CompiledRoute {
+    fn constrained(&self) -> bool {
+        let conditions = &self.route.conditions;
+        self.path_regex.is_some()
+            || !conditions.methods.is_empty()
+            || !conditions.headers.is_empty()
+            || !conditions.query.is_empty()
+            || !conditions.client_cidrs.is_empty()
+    }
+
+    fn rejection<B>(&self, req: &Request<B>, client_ip: IpAddr) -> Option<Rejection<'_>> {
+        if let Some(regex) = &self.path_regex
+            && !regex.is_match(req.uri().path())
+        {
+            return Some(Rejection::PathRegex);
+        }
+        conditions_rejection(&self.route.conditions, req, client_ip)
+    }
+}
+
+/// Radix tree over path prefixes. Sibling labels never share a first
+/// character, so a lookup follows a single branch.
+#[derive(Default)]
+struct PrefixTree {
+    label: String,
+    children: Vec<PrefixTree>,
+    routes: Vec<usize>,
+}
+
+impl PrefixTree {
+    fn insert(&mut self, key: &str, route: usize) {
+        if key.is_empty() {
+            self.routes.push(route);
+            return;
+        }
+        for child in &mut self.children {
+            let common = common_prefix_len(&child.label, key);
+            if common == 0 {
+                continue;
+            }
+            if common < child.label.len() {
+                let tail = PrefixTree {
+                    label: child.label[common..].to_string(),
+                    children: std::mem::take(&mut child.children),
+                    routes: std::mem::take(&mut child.routes),
+                };
+                child.label.truncate(common);
+                child.children = vec![tail];
+            }
+            child.insert(&key[common..], route);
+            return;
+        }
+        self.children.push(PrefixTree {
+            label: key.to_string(),
+            children: Vec::new(),
+            routes: vec![route],
+        });
+    }
+
+    /// Routes whose prefix covers `path` up to a segment boundary, as
+    /// `(route, prefix length)`.
+    fn lookup(&self, path: &str, out: &mut Vec<(usize, usize)>) {
+        let mut node = self;
+        let mut depth = 0;
+        loop {
+            if url_path::has_prefix(path, &path[..depth]) {
+                out.extend(node.routes.iter().map(|&route| (route, depth)));
+            }
+            let rest = &path[depth..];
+            match node.children.iter().find(|c| rest.starts_with(&c.label)) {
+                Some(child) => {
+                    depth += child.label.len();
+                    node = child;
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+fn common_prefix_len(a: &str, b: &str) -> usize {
+    a.char_indices()
+        .zip(b.chars())
+        .take_while(|((_, x), y)| x == y)
+        .last()
+        .map_or(0, |((i, x), _)| i + x.len_utf8())
+}
+
+/// A route whose host and path prefix fit a request.
+pub struct Candidate<'a> {
+    pub route: &'a Route,
+    pub host: HostMatch,
+    /// Set when the route's regex or `match` conditions turned it down.
+    pub rejected: Option<Rejection<'a>>,
+}
+
+/// The route chosen for a request, with its position in the matcher.
+#[derive(Clone, Copy)]
+pub struct RouteMatch<'a> {
+    pub route: &'a Route,
+    pub index: usize,
+}
+
+/// Every route's verdict on one request, for `charles routes explain`.
+pub struct Explanation<'a> {
+    /// In the order they are tried; the first one not rejected is chosen.
+    pub candidates: Vec<Candidate<'a>>,
+    /// Routes whose host patterns or path prefix do not fit.
+    pub unmatched: Vec<(&'a Route, &'static str)>,
+}
+
+impl Explanation<'_> {
+    pub fn selected(&self) -> Option<&Route> {
+        self.candidates
+            .iter()
+            .find(|c| c.rejected.is_none())
+            .map(|c| c.route)
+    }
+}
+
+/// Routes compiled into per-host prefix trees. The most specific route
+/// wins: highest `priority`, then exact host over wildcard (longer suffix
+/// first), then longest path prefix, then routes with a regex or `match`
+/// conditions over plain ones, then declaration order.
+pub struct RouteMatcher {
+    routes: Vec<CompiledRoute>,
+    exact: HashMap<String, PrefixTree>,
+    /// Keyed by the part after `*.`.
+    wildcard: HashMap<String, PrefixTree>,
+}
+
+impl RouteMatcher {
+    /// Routes with an invalid `path_regex` never match; `Config::from_file`
+    /// rejects them before this point.
+    pub fn new(routes: Vec<Route>) -> Self {
+        let mut exact: HashMap<String, PrefixTree> = HashMap::new();
+        let mut wildcard: HashMap<String, PrefixTree> = HashMap::new();
+        for (index, route) in routes.iter().enumerate() {
+            for pattern in &route.hosts {
+                let tree = match pattern.strip_prefix("*.") {
+                    Some(suffix) => wildcard.entry(suffix.to_string()).or_default(),
+                    None => exact.entry(pattern.clone()).or_default(),
+                };
+                tree.insert(&route.path_prefix, index);
+            }
+        }
+
+        let routes = routes
+            .into_iter()
+            .map(|route| {
+                let path_regex = route.path_regex.as_deref().map(|pattern| {
+                    Regex::new(pattern).unwrap_or_else(|e| {
+                        warn!("Route {} has an invalid path_regex: {}", route.name, e);
+                        Regex::new(r"[^\s\S]").expect("never-matching pattern")
+                    })
+                });
+                CompiledRoute { route, path_regex }
+            })
+            .collect();
+        Self {
+            routes,
+            exact,
+            wildcard,
+        }
+    }
+
+    /// `(route index, host match, prefix length)` for every route whose
+    /// host and prefix fit, most specific first.
+    fn candidates(&self, host: &str, path: &str) -> Vec<(usize, HostMatch, usize)> {
+        let mut found = Vec::new();
+        let mut hits = Vec::new();
+        if let Some(tree) = self.exact.get(host) {
+            tree.lookup(path, &mut hits);
+            found.extend(hits.drain(..).map(|(i, len)| (i, HostMatch::Exact, len)));
+        }
+        // `*.example.com` covers `example.com` itself and any subdomain.
+        let suffixes = std::iter::once(host).chain(
+            host.char_indices()
+                .filter(|&(_, c)| c == '.')
+                .map(|(i, _)| &host[i + 1..]),
+        );
+        for suffix in suffixes {
+            if let Some(tree) = self.wildcard.get(suffix) {
+                tree.lookup(path, &mut hits);
+                let matched = HostMatch::Wildcard(suffix.len());
+                found.extend(hits.drain(..).map(|(i, len)| (i, matched, len)));
+            }
+        }
+
+        found.sort_by_key(|&(i, host, len)| {
+            let compiled = &self.routes[i];
+            (
+                Reverse(compiled.route.priority),
+                Reverse(host),
+                Reverse(len),
+                Reverse(compiled.constrained()),
+                i,
+            )
+        });
+        // A route listing several matching hosts keeps its best match.
+        let mut seen = vec![false; self.routes.len()];
+        found.retain(|&(i, _, _)| !std::mem::replace(&mut seen[i], true));
+        found
+    }
+
+    pub fn find_route<B>(
+        &self,
+        req: &Request<B>,
+        host: &str,
+        client_ip: IpAddr,
+    ) -> Option<RouteMatch<'_>> {
+        self.candidates(host, req.uri().path())
+            .into_iter()
+            .find(|&(i, _, _)| self.routes[i].rejection(req, client_ip).is_none())
+            .map(|(index, _, _)| RouteMatch {
+                route: &self.routes[index].route,
+                index,
+            })
+    }
+
+    pub fn explain<B>(&self, req: &Request<B>, host: &str, client_ip: IpAddr) -> Explanation<'_> {
+        let path = req.uri().path();
+        let candidates: Vec<Candidate> = self
+            .candidates(host, path)
+            .into_iter()
+            .map(|(i, host, _)| Candidate {
+                route: &self.routes[i].route,
+                host,
+                rejected: self.routes[i].rejection(req, client_ip),
+            })
+            .collect();
+        let unmatched = self
+            .routes
+            .iter()
+            .map(|compiled| &compiled.route)
+            .filter(|route| !candidates.iter().any(|c| std::ptr::eq(c.route, *route)))
+            .map(|route| {
+                let host_ok = route
+                    .hosts
+                    .iter()
+                    .any(|pattern| crate::proxy::host_matches(pattern, host));
+                let reason = if host_ok {
+                    "path prefix does not match"
+                } else {
+                    "host does not match"
+                };
+                (route, reason)
+            })
+            .collect();
+        Explanation {
+            candidates,
+            unmatched,
+        }
+    }
+
+    /// The path to request from the `matched` route's upstream for a
+    /// client `path`.
+    pub fn upstream_path(&self, matched: RouteMatch<'_>, path: &str) -> String {
+        let route = matched.route;
+        if let Some(template) = &route.rewrite_path
+            && let Some(captures) = self.routes[matched.index]
+                .path_regex
+                .as_ref()
+                .and_then(|regex| regex.captures(path))
+        {
+            let mut expanded = String::new();
+            captures.expand(template, &mut expanded);
+            return url_path::tidy(&expanded);
+        }
+
+        if route.strip_prefix {
+            let stripped = path.strip_prefix(&route.path_prefix).unwrap_or(path);
+            let rewrite = route.rewrite_prefix.as_deref().unwrap_or_default();
+            url_path::join(rewrite, stripped)
+        } else {
+            path.to_string()
+        }
+    }
+}
+
+fn conditions_rejection<'a, B>(
+    conditions: &'a RouteConditions,
+    req: &Request<B>,
+    client_ip: IpAddr,
+) -> Option<Rejection<'a>> {
+    let method = req.method().as_str();
+    if !conditions.methods.is_empty()
+        && !conditions
+            .methods
+            .iter()
+            .any(|m| m.eq_ignore_ascii_case(method))
+    {
+        return Some(Rejection::Method);
+    }
+    for (name, expected) in &conditions.headers {
+        let found = req
+            .headers()
+            .get_all(name.as_str())
+            .iter()
+            .any(|value| expected == "*" || value.as_bytes() == expected.as_bytes());
+        if !found {
+            return Some(Rejection::Header(name));
+        }
+    }
+    for (name, expected) in &conditions.query {
+        if !query_values(req.uri().query(), name).any(|value| expected == "*" || value == expected)
+        {
+            return Some(Rejection::Query(name));
+        }
+    }
+    if !conditions.client_cidrs.is_empty()
+        && !conditions
+            .client_cidrs
+            .iter()
+            .any(|net| net.contains(&client_ip))
+    {
+        return Some(Rejection::ClientIp);
+    }
+    None
+}
+
+/// Raw values of query parameter `name`; a bare `name` has an empty value.
+fn query_values<'a>(query: Option<&'a str>, name: &'a str) -> impl Iterator<Item = &'a str> {
+    query
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(move |pair| match pair.split_once('=') {
+            Some((key, value)) => (key == name).then_some(value),
+            None => (pair == name).then_some(""),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher() -> RouteMatcher {
+        let config: crate::config::Config = toml::from_str(
+            r#"
+                listen = "127.0.0.1:0"
+
+                [control]
+                listen = "127.0.0.1:0"
+
+                [[routes]]
+                name = "user"
+                hosts = ["example.com"]
+                path_prefix = "/users/"
+                path_regex = '^/users/(?P<id>\d+)(?P<rest>/.*)?$'
+                rewrite_path = "/v2/accounts/${id}${rest}"
+                upstreams = []
+
+                [routes.match]
+                methods = ["get"]
+                headers = { "x-canary" = "1", authorization = "*" }
+                query = { debug = "*" }
+                client_cidrs = ["10.0.0.0/8"]
+
+                [[routes]]
+                name = "api"
+                hosts = ["*.example.com", "example.com"]
+                path_prefix = "/api"
+                strip_prefix = true
+                rewrite_prefix = "/v1"
+                upstreams = []
+
+                [[routes]]
+                name = "fallback"
+                hosts = ["example.com"]
+                path_prefix = "/"
+                upstreams = []
+            "#,
+        )
+        .unwrap();
+        RouteMatcher::new(config.routes)
+    }
+
+    fn find<'a>(matcher: &'a RouteMatcher, req: &Request<()>, ip: &str) -> &'a str {
+        let route = matcher.find_route(req, "example.com", ip.parse().unwrap());
+        route.map_or("none", |matched| matched.route.name.as_str())
+    }
+
+    #[test]
+    fn test_conditions_and_regex() {
+        let matcher = matcher();
+        let full = || {
+            Request::builder()
+                .uri("/users/42/posts?debug")
+                .header("x-canary", "1")
+                .header("authorization", "Bearer t")
+        };
+
+        let req = full().body(()).unwrap();
+        assert_eq!(find(&matcher, &req, "10.1.2.3"), "user");
+        let route = matcher.find_route(&req, "example.com", "10.1.2.3".parse().unwrap());
+        assert_eq!(
+            matcher.upstream_path(route.unwrap(), "/users/42/posts"),
+            "/v2/accounts/42/posts"
+        );
+
+        let cases = [
+            (full().method("POST"), "10.1.2.3"),
+            (full().header("x-canary", "0"), "192.168.0.1"),
+            (full().uri("/users/abc?debug"), "10.1.2.3"),
+            (full().uri("/users/42"), "10.1.2.3"),
+        ];
+        for (req, ip) in cases {
+            assert_eq!(find(&matcher, &req.body(()).unwrap(), ip), "fallback");
+        }
+        let req = Request::builder()
+            .uri("/users/42?debug=1")
+            .header("x-canary", "1")
+            .body(())
+            .unwrap();
+        assert_eq!(find(&matcher, &req, "10.1.2.3"), "fallback");
+    }
+
+    #[test]
+    fn test_prefix_rewrite() {
+        let matcher = matcher();
+        let req = Request::builder().uri("/api/items").body(()).unwrap();
+        let route = matcher
+            .find_route(&req, "www.example.com", "127.0.0.1".parse().unwrap())
+            .unwrap();
+        assert_eq!(route.route.name, "api");
+        assert_eq!(matcher.upstream_path(route, "/api/items"), "/v1/items");
+    }
+
+    #[test]
+    fn test_most_specific_route_wins() {
+        let config: crate::config::Config = toml::from_str(
+            r#"
+                listen = "127.0.0.1:0"
+
+                [control]
+                listen = "127.0.0.1:0"
+
+                [[routes]]
+                name = "root"
+                hosts = ["example.com"]
+                path_prefix = "/"
+                upstreams = []
+
+                [[routes]]
+                name = "any-api"
+                hosts = ["*.example.com"]
+                path_prefix = "/api/v1"
+                upstreams = []
+
+                [[routes]]
+                name = "api"
+                hosts = ["example.com", "*.example.com"]
+                path_prefix = "/api"
+                upstreams = []
+
+                [[routes]]
+                name = "api-posts"
+                hosts = ["example.com"]
+                path_prefix = "/api"
+                upstreams = []
+                match = { methods = ["POST"] }
+
+                [[routes]]
+                name = "pinned"
+                hosts = ["*.com"]
+                path_prefix = "/"
+                priority = 10
+                upstreams = []
+                match = { headers = { "x-pin" = "1" } }
+            "#,
+        )
+        .unwrap();
+        let matcher = RouteMatcher::new(config.routes);
+        let find = |method: &str, host: &str, path: &str, pin: bool| {
+            let mut req = Request::builder().method(method).uri(path);
+            if pin {
+                req = req.header("x-pin", "1");
+            }
+            let req = req.body(()).unwrap();
+            matcher
+                .find_route(&req, host, "127.0.0.1".parse().unwrap())
+                .map(|matched| matched.route.name.clone())
+        };
+
+        let cases = [
+            ("GET", "example.com", "/api/v1/x", false, Some("api")),
+            (
+                "GET",
+                "www.example.com",
+                "/api/v1/x",
+                false,
+                Some("any-api"),
+            ),
+            ("GET", "www.example.com", "/api/v2", false, Some("api")),
+            (
+                "POST",
+                "example.com",
+                "/api/items",
+                false,
+                Some("api-posts"),
+            ),
+            ("GET", "example.com", "/apiary", false, Some("root")),
+            ("GET", "example.com", "/api", false, Some("api")),
+            ("GET", "example.com", "/about", false, Some("root")),
+            ("GET", "example.com", "/api", true, Some("pinned")),
+            ("GET", "other.org", "/", false, None),
+        ];
+        for (method, host, path, pin, expected) in cases {
+            assert_eq!(
+                find(method, host, path, pin).as_deref(),
+                expected,
+                "{method} {host}{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_explain_reports_each_route() {
+        let matcher = matcher();
+        let req = Request::builder()
+            .uri("/users/42?debug")
+            .header("x-canary", "1")
+            .body(())
+            .unwrap();
+        let explanation = matcher.explain(&req, "example.com", "10.0.0.1".parse().unwrap());
+        let verdicts: Vec<_> = explanation
+            .candidates
+            .iter()
+            .map(|c| (c.route.name.as_str(), c.host, c.rejected))
+            .collect();
+        assert_eq!(
+            verdicts,
+            vec![
+                (
+                    "user",
+                    HostMatch::Exact,
+                    Some(Rejection::Header("authorization"))
+                ),
+                ("fallback", HostMatch::Exact, None),
+            ]
+        );
+        assert_eq!(explanation.selected().unwrap().name, "fallback");
+        assert_eq!(explanation.unmatched.len(), 1);
+        assert_eq!(explanation.unmatched[0].0.name, "api");
+        assert_eq!(explanation.unmatched[0].1, "path prefix does not match");
+    }
+
+    #[test]
+    fn test_prefix_tree_splits_shared_labels() {
+        let mut tree = PrefixTree::default();
+        for (index, prefix) in ["/api/users", "/api/uploads", "/api", "/", "/ü/x", "/ü"]
+            .into_iter()
+            .enumerate()
+        {
+            tree.insert(prefix, index);
+        }
+        let mut hits = Vec::new();
+        tree.lookup("/api/uploads/1", &mut hits);
+        assert_eq!(hits, vec![(3, 1), (2, 4), (1, 12)]);
+        hits.clear();
+        tree.lookup("/ü/x/y", &mut hits);
+        assert_eq!(hits, vec![(3, 1), (5, 3), (4, 5)]);
+        hits.clear();
+        tree.lookup("/ü/xy", &mut hits);
+        assert_eq!(hits, vec![(3, 1), (5, 3)], "only whole segments match");
+    }
+}
@@ -1,9 +1,23 @@
+use crate::balancer::Balancers;
+use crate::breakpoint::BreakpointManager;
+use crate::cache::{CacheStatus, ResponseCache};
+use crate::canary::CanaryManager;
+use crate::circuit::{CircuitBreakers, CircuitState};
+use crate::concurrency::ConcurrencyManager;
+use crate::fault::FaultManager;
+use crate::map_remote::MapRemoteManager;
+use crate::rate_limit::RateLimiter;
+use crate::retry::RetryBudgets;
+use crate::throttle::ThrottleManager;
+use crate::timeout::Stage;
 use chrono::{DateTime, Utc};
 use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RequestLog {
+    pub id: String,
     pub timestamp: DateTime<Utc>,
     pub method: String,
     pub path: String,
@@ -11,38 +25,146 @@ pub struct RequestLog {
     pub status: u16,
     pub duration_ms: u64,
     pub upstream: String,
+    /// Fault injected into this exchange, if any.
+    #[serde(default)]
+    pub fault: Option<String>,
+    /// Times the request was sent upstream; 0 when the proxy answered itself.
+    #[serde(default)]
+    pub attempts: u32,
+    /// Frames seen on an upgraded WebSocket connection, if captured.
+    #[serde(default)]
+    pub websocket_messages: Vec<WebSocketMessage>,
+    /// Whether the response came from the route's cache.
+    #[serde(default)]
+    pub cache: Option<CacheStatus>,
+    /// How the mirrored copy of this request fared, when compared.
+    #[serde(default)]
+    pub mirror: Option<MirrorDiff>,
 }
 
-#[derive(Debug, Clone)]
+/// The shadow upstream's answer next to the primary one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MirrorDiff {
+    pub upstream: String,
+    pub primary_status: u16,
+    pub shadow_status: u16,
+    /// Whether the bodies were equal; unknown when either was cut short or
+    /// longer than the route's `max_body_bytes`.
+    pub body_matches: Option<bool>,
+}
+
+impl MirrorDiff {
+    pub fn differs(&self) -> bool {
+        self.primary_status != self.shadow_status || self.body_matches == Some(false)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebSocketMessage {
+    pub timestamp: DateTime<Utc>,
+    /// `client` for frames sent to the upstream, `server` for the reverse.
+    pub direction: String,
+    pub opcode: String,
+    pub length: u64,
+    /// Payload start, lossily decoded as UTF-8.
+    pub preview: String,
+}
+
+/// A finished CONNECT tunnel on the forward-proxy listener.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TunnelLog {
+    pub id: String,
+    pub opened_at: DateTime<Utc>,
+    pub client: String,
+    /// `host:port` from the CONNECT request.
+    pub target: String,
+    /// Bytes sent from the client to the target.
+    pub bytes_up: u64,
+    /// Bytes sent from the target to the client.
+    pub bytes_down: u64,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct ProxyMetrics {
     pub total_requests: u64,
     pub active_requests: u64,
     pub total_errors: u64,
+    pub timeouts: TimeoutCounts,
+    /// Shadow requests that failed; never part of `total_errors`.
+    pub mirror_errors: u64,
     pub upstreams_status: Vec<UpstreamStatus>,
 }
 
-#[derive(Debug, Clone)]
+/// Timeouts seen so far, by stage. Each also counts towards `total_errors`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TimeoutCounts {
+    pub header_read: u64,
+    pub request_body: u64,
+    pub connect: u64,
+    pub response_header: u64,
+    pub request: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UpstreamStatus {
     pub url: String,
+    /// False while the circuit is open.
     pub healthy: bool,
+    /// Failures in the circuit breaker's current window.
     pub failures: u32,
+    #[serde(default)]
+    pub requests: u32,
+    #[serde(default)]
+    pub circuit: CircuitState,
+    #[serde(default)]
+    pub in_flight: usize,
+    /// Moving average of time to the response head.
+    #[serde(default)]
+    pub latency_ewma_ms: Option<f64>,
 }
 
 pub struct SharedState {
     pub request_logs: RwLock<Vec<RequestLog>>,
+    pub tunnels: RwLock<Vec<TunnelLog>>,
     pub metrics: RwLock<ProxyMetrics>,
+    pub breakpoints: BreakpointManager,
+    pub map_remote: MapRemoteManager,
+    pub throttle: ThrottleManager,
+    pub faults: FaultManager,
+    pub rate_limits: RateLimiter,
+    pub concurrency: ConcurrencyManager,
+    pub retries: RetryBudgets,
+    pub circuits: CircuitBreakers,
+    pub balancers: Balancers,
+    pub canary: CanaryManager,
+    pub cache: ResponseCache,
 }
 
 impl SharedState {
     pub fn new() -> Arc<Self> {
         Arc::new(SharedState {
             request_logs: RwLock::new(Vec::new()),
+            tunnels: RwLock::new(Vec::new()),
             metrics: RwLock::new(ProxyMetrics {
                 total_requests: 0,
                 active_requests: 0,
                 total_errors: 0,
+                timeouts: TimeoutCounts::default(),
+                mirror_errors: 0,
                 upstreams_status: Vec::new(),
             }),
+            breakpoints: BreakpointManager::new(),
+            map_remote: MapRemoteManager::new(),
+            throttle: ThrottleManager::new(),
+            faults: FaultManager::new(),
+            rate_limits: RateLimiter::new(),
+            concurrency: ConcurrencyManager::new(),
+            retries: RetryBudgets::new(),
+            circuits: CircuitBreakers::new(),
+            balancers: Balancers::new(),
+            canary: CanaryManager::new(),
+            cache: ResponseCache::new(),
         })
     }
 
@@ -55,6 +177,34 @@ impl SharedState {
         }
     }
 
+    /// Attaches a captured WebSocket frame to the log entry of its handshake.
+    pub fn add_websocket_message(&self, request_id: &str, message: WebSocketMessage) {
+        let mut logs = self.request_logs.write();
+        if let Some(log) = logs.iter_mut().rev().find(|log| log.id == request_id) {
+            log.websocket_messages.push(message);
+            // Keep only last 500 frames per connection
+            if log.websocket_messages.len() > 500 {
+                log.websocket_messages.remove(0);
+            }
+        }
+    }
+
+    pub fn add_tunnel_log(&self, tunnel: TunnelLog) {
+        let mut tunnels = self.tunnels.write();
+        tunnels.push(tunnel);
+        // Keep only last 1000 tunnels
+        if tunnels.len() > 1000 {
+            tunnels.remove(0);
+        }
+    }
+
+    pub fn set_mirror_diff(&self, request_id: &str, diff: MirrorDiff) {
+        let mut logs = self.request_logs.write();
+        if let Some(log) = logs.iter_mut().rev().find(|log| log.id == request_id) {
+            log.mirror = Some(diff);
+        }
+    }
+
     pub fn increment_total_requests(&self) {
         let mut metrics = self.metrics.write();
         metrics.total_requests += 1;
@@ -77,6 +227,23 @@ impl SharedState {
         metrics.total_errors += 1;
     }
 
+    pub fn record_mirror_error(&self) {
+        self.metrics.write().mirror_errors += 1;
+    }
+
+    pub fn record_timeout(&self, stage: Stage) {
+        let mut metrics = self.metrics.write();
+        metrics.total_errors += 1;
+        let counter = match stage {
+            Stage::HeaderRead => &mut metrics.timeouts.header_read,
+            Stage::RequestBody => &mut metrics.timeouts.request_body,
+            Stage::Connect => &mut metrics.timeouts.connect,
+            Stage::ResponseHeader => &mut metrics.timeouts.response_header,
+            Stage::Request => &mut metrics.timeouts.request,
+        };
+        *counter += 1;
+    }
+
     pub fn update_upstream_status(&self, statuses: Vec<UpstreamStatus>) {
         let mut metrics = self.metrics.write();
         metrics.upstreams_status = statuses;
@@ -86,8 +253,25 @@ impl SharedState {
         self.request_logs.read().clone()
     }
 
+    pub fn get_tunnel_logs(&self) -> Vec<TunnelLog> {
+        self.tunnels.read().clone()
+    }
+
     pub fn get_metrics(&self) -> ProxyMetrics {
-        self.metrics.read().clone()
+        let mut metrics = self.metrics.read().clone();
+        for status in &mut metrics.upstreams_status {
+            if let Some(circuit) = self.circuits.snapshot(&status.url) {
+                status.healthy = circuit.state != CircuitState::Open;
+                status.failures = circuit.failures;
+                status.requests = circuit.requests;
+                status.circuit = circuit.state;
+            }
+            if let Some(load) = self.balancers.stats(&status.url) {
+                status.in_flight = load.in_flight;
+                status.latency_ewma_ms = load.latency_ewma_ms;
+            }
+        }
+        metrics
     }
 }
 
@@ -95,12 +279,26 @@ impl Default for SharedState {
     fn default() -> Self {
         SharedState {
             request_logs: RwLock::new(Vec::new()),
+            tunnels: RwLock::new(Vec::new()),
             metrics: RwLock::new(ProxyMetrics {
                 total_requests: 0,
                 active_requests: 0,
                 total_errors: 0,
+                timeouts: TimeoutCounts::default(),
+                mirror_errors: 0,
                 upstreams_status: Vec::new(),
             }),
+            breakpoints: BreakpointManager::new(),
+            map_remote: MapRemoteManager::new(),
+            throttle: ThrottleManager::new(),
+            faults: FaultManager::new(),
+            rate_limits: RateLimiter::new(),
+            concurrency: ConcurrencyManager::new(),
+            retries: RetryBudgets::new(),
+            circuits: CircuitBreakers::new(),
+            balancers: Balancers::new(),
+            canary: CanaryManager::new(),
+            cache: ResponseCache::new(),
         }
     }
 }
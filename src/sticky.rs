@@ -0,0 +1,107 @@
+use crate::balancer;
+use crate::config::{Route, StickyConfig};
+use hyper::Request;
+use hyper::header::HeaderValue;
+
+/// The cookie value naming `url`: a hash, so upstream addresses never
+/// reach the client.
+pub fn upstream_id(sticky: &StickyConfig, url: &str) -> String {
+    let secret = sticky.secret.as_deref().unwrap_or_default();
+    format!("{:016x}", balancer::hash(&format!("{}\0{}", secret, url)))
+}
+
+/// The upstream the affinity cookie on `req` points at, if it is still one
+/// of the route's.
+pub fn pinned<'a, B>(sticky: &StickyConfig, route: &'a Route, req: &Request<B>) -> Option<&'a str> {
+    let id = balancer::cookie(req, &sticky.cookie)?;
+    route
+        .all_upstreams()
+        .find(|upstream| upstream_id(sticky, &upstream.url) == id)
+        .map(|upstream| upstream.url.as_str())
+}
+
+/// `Set-Cookie` value pinning the client to `url`, scoped to the route's
+/// path prefix.
+pub fn set_cookie(sticky: &StickyConfig, route: &Route, url: &str) -> Option<HeaderValue> {
+    let path = if route.path_prefix.is_empty() {
+        "/"
+    } else {
+        &route.path_prefix
+    };
+    let mut cookie = format!(
+        "{}={}; Path={}; HttpOnly; SameSite=Lax",
+        sticky.cookie,
+        upstream_id(sticky, url),
+        path
+    );
+    if let Some(max_age) = sticky.max_age_secs {
+        cookie.push_str(&format!("; Max-Age={}", max_age));
+    }
+    if sticky.secure {
+        cookie.push_str("; Secure");
+    }
+    HeaderValue::from_str(&cookie).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route() -> Route {
+        toml::from_str(
+            r#"
+                name = "legacy"
+                hosts = ["example.com"]
+                path_prefix = "/app"
+
+                [sticky]
+                cookie = "sid"
+                max_age_secs = 60
+                secure = true
+
+                [[upstreams]]
+                url = "http://a"
+
+                [[upstreams]]
+                url = "http://b"
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_cookie_round_trips_to_upstream() {
+        let route = route();
+        let sticky = route.sticky.as_ref().unwrap();
+        let cookie = set_cookie(sticky, &route, "http://b").unwrap();
+        let cookie = cookie.to_str().unwrap();
+        assert!(cookie.ends_with("; Path=/app; HttpOnly; SameSite=Lax; Max-Age=60; Secure"));
+        assert!(!cookie.contains("http://b"));
+
+        let pair = cookie.split(';').next().unwrap();
+        let req = Request::builder()
+            .header("cookie", format!("theme=dark; {}", pair))
+            .body(())
+            .unwrap();
+        assert_eq!(pinned(sticky, &route, &req), Some("http://b"));
+
+        let stale = Request::builder()
+            .header("cookie", "sid=0123456789abcdef")
+            .body(())
+            .unwrap();
+        assert_eq!(pinned(sticky, &route, &stale), None);
+    }
+
+    #[test]
+    fn test_secret_changes_ids() {
+        let route = route();
+        let mut sticky = route.sticky.clone().unwrap();
+        let plain = upstream_id(&sticky, "http://a");
+        sticky.secret = Some("s3cret".to_string());
+        assert_ne!(upstream_id(&sticky, "http://a"), plain);
+        assert_ne!(
+            upstream_id(&sticky, "http://a"),
+            upstream_id(&sticky, "http://b")
+        );
+    }
+}
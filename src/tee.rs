@@ -0,0 +1,147 @@
+use bytes::{Bytes, BytesMut};
+use hyper::body::{Body, Frame, SizeHint};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+type Done = Box<dyn FnOnce(Captured) + Send + Sync>;
+
+/// A response as the client received it.
+pub struct Captured {
+    pub status: u16,
+    /// `None` when the body was longer than the cap or did not finish.
+    pub body: Option<Bytes>,
+}
+
+/// Passes a response body through untouched while keeping a copy of up
+/// to `limit` bytes, handed to `done` once the client has it all.
+pub struct TeeBody<B> {
+    inner: B,
+    status: u16,
+    copy: Option<BytesMut>,
+    limit: usize,
+    done: Option<Done>,
+}
+
+impl<B> TeeBody<B> {
+    pub fn new(
+        inner: B,
+        status: u16,
+        limit: u64,
+        done: impl FnOnce(Captured) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            inner,
+            status,
+            copy: Some(BytesMut::new()),
+            limit: usize::try_from(limit).unwrap_or(usize::MAX),
+            done: Some(Box::new(done)),
+        }
+    }
+
+    fn finish(&mut self, complete: bool) {
+        if let Some(done) = self.done.take() {
+            let body = self.copy.take().filter(|_| complete).map(BytesMut::freeze);
+            done(Captured {
+                status: self.status,
+                body,
+            });
+        }
+    }
+}
+
+impl<B> Body for TeeBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: Into<GenericError>,
+{
+    type Data = Bytes;
+    type Error = GenericError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, GenericError>>> {
+        let this = self.get_mut();
+        match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+            Some(Ok(frame)) => {
+                if let (Some(data), Some(copy)) = (frame.data_ref(), &mut this.copy) {
+                    if copy.len() + data.len() > this.limit {
+                        this.copy = None;
+                    } else {
+                        copy.extend_from_slice(data);
+                    }
+                }
+                // The connection stops polling once the body says it ended.
+                if this.inner.is_end_stream() {
+                    this.finish(true);
+                }
+                Poll::Ready(Some(Ok(frame)))
+            }
+            Some(Err(e)) => {
+                this.finish(false);
+                Poll::Ready(Some(Err(e.into())))
+            }
+            None => {
+                this.finish(true);
+                Poll::Ready(None)
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl<B> Drop for TeeBody<B> {
+    fn drop(&mut self) {
+        // Dropped before the end: the client went away mid-body.
+        self.finish(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{BodyExt, Full};
+    use tokio::sync::oneshot;
+
+    fn sender(tx: oneshot::Sender<Captured>) -> impl FnOnce(Captured) + Send + Sync {
+        move |captured| {
+            let _ = tx.send(captured);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tee_hands_over_body_when_done() {
+        let (tx, rx) = oneshot::channel();
+        let body = TeeBody::new(Full::new(Bytes::from("hello")), 200, 16, sender(tx));
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "hello");
+        let captured = rx.await.unwrap();
+        assert_eq!(captured.status, 200);
+        assert_eq!(captured.body.as_deref(), Some(&b"hello"[..]));
+    }
+
+    #[tokio::test]
+    async fn test_tee_gives_up_past_limit_or_early_drop() {
+        let (tx, rx) = oneshot::channel();
+        let body = TeeBody::new(Full::new(Bytes::from("hello")), 200, 4, sender(tx));
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "hello");
+        assert!(rx.await.unwrap().body.is_none());
+
+        let (tx, rx) = oneshot::channel();
+        drop(TeeBody::new(
+            Full::new(Bytes::from("hello")),
+            502,
+            16,
+            sender(tx),
+        ));
+        let captured = rx.await.unwrap();
+        assert_eq!((captured.status, captured.body), (502, None));
+    }
+}
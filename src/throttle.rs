@@ -0,0 +1,298 @@
+use crate::config::{Route, ThrottleConfig, ThrottleProfile};
+use anyhow::{Result, bail};
+use bytes::Bytes;
+use hyper::body::{Body, Frame, SizeHint};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::time::Sleep;
+
+/// Presets available without any configuration.
+pub fn builtin_profiles() -> Vec<ThrottleProfile> {
+    let preset =
+        |name: &str, down: u64, up: u64, latency_ms: u64, jitter_ms: u64| ThrottleProfile {
+            name: name.to_string(),
+            downstream_kbps: Some(down),
+            upstream_kbps: Some(up),
+            latency_ms,
+            jitter_ms,
+            stall_probability: 0.0,
+            stall_ms: 1000,
+        };
+    vec![
+        preset("gprs", 50, 20, 500, 100),
+        preset("edge", 240, 200, 400, 80),
+        preset("3g", 1600, 768, 150, 50),
+        preset("dsl", 2000, 256, 25, 5),
+        preset("4g", 12000, 5000, 50, 20),
+    ]
+}
+
+/// Profiles plus which one applies globally and per route.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ThrottleStatus {
+    pub profiles: Vec<ThrottleProfile>,
+    pub active: Option<String>,
+    pub routes: BTreeMap<String, String>,
+}
+
+pub struct ThrottleManager {
+    status: RwLock<ThrottleStatus>,
+}
+
+impl ThrottleManager {
+    pub fn new() -> Self {
+        Self {
+            status: RwLock::new(ThrottleStatus {
+                profiles: builtin_profiles(),
+                ..ThrottleStatus::default()
+            }),
+        }
+    }
+
+    /// Loads custom profiles and the global and per-route selections.
+    pub fn configure(&self, config: &ThrottleConfig, routes: &[Route]) -> Result<()> {
+        let mut profiles = builtin_profiles();
+        for profile in &config.profiles {
+            profiles.retain(|p| p.name != profile.name);
+            profiles.push(profile.clone());
+        }
+        let known = |name: &str| profiles.iter().any(|p| p.name == name);
+
+        if let Some(name) = &config.active
+            && !known(name)
+        {
+            bail!("Unknown throttle profile: {}", name);
+        }
+        let mut selected = BTreeMap::new();
+        for route in routes {
+            if let Some(name) = &route.throttle {
+                if !known(name) {
+                    bail!(
+                        "Unknown throttle profile for route {}: {}",
+                        route.name,
+                        name
+                    );
+                }
+                selected.insert(route.name.clone(), name.clone());
+            }
+        }
+
+        *self.status.write() = ThrottleStatus {
+            profiles,
+            active: config.active.clone(),
+            routes: selected,
+        };
+        Ok(())
+    }
+
+    pub fn get_status(&self) -> ThrottleStatus {
+        self.status.read().clone()
+    }
+
+    /// Selects `profile` globally or for one route; `None` turns it off.
+    pub fn set_active(&self, route: Option<&str>, profile: Option<String>) -> Result<()> {
+        let mut status = self.status.write();
+        if let Some(name) = &profile
+            && !status.profiles.iter().any(|p| &p.name == name)
+        {
+            bail!("Unknown throttle profile: {}", name);
+        }
+        match (route, profile) {
+            (Some(route), Some(profile)) => {
+                status.routes.insert(route.to_string(), profile);
+            }
+            (Some(route), None) => {
+                status.routes.remove(route);
+            }
+            (None, profile) => status.active = profile,
+        }
+        Ok(())
+    }
+
+    /// The profile for traffic on `route`, falling back to the global one.
+    pub fn profile_for(&self, route: Option<&str>) -> Option<ThrottleProfile> {
+        let status = self.status.read();
+        let name = route
+            .and_then(|route| status.routes.get(route))
+            .or(status.active.as_ref())?;
+        status.profiles.iter().find(|p| &p.name == name).cloned()
+    }
+}
+
+impl Default for ThrottleManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `latency_ms` shifted by a random amount within `jitter_ms`.
+pub fn latency(profile: &ThrottleProfile) -> Duration {
+    let jitter = profile.jitter_ms as i64;
+    let offset = if jitter > 0 {
+        rand::random_range(-jitter..=jitter)
+    } else {
+        0
+    };
+    Duration::from_millis((profile.latency_ms as i64 + offset).max(0) as u64)
+}
+
+/// Body wrapper that releases data in chunks no faster than the given rate.
+pub struct ThrottledBody<B> {
+    inner: B,
+    bytes_per_sec: Option<u64>,
+    stall_probability: f64,
+    stall: Duration,
+    pending: Bytes,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<B> ThrottledBody<B> {
+    pub fn new(inner: B, kbps: Option<u64>, profile: &ThrottleProfile) -> Self {
+        Self {
+            inner,
+            bytes_per_sec: kbps.map(|kbps| (kbps * 1000 / 8).max(1)),
+            stall_probability: profile.stall_probability,
+            stall: Duration::from_millis(profile.stall_ms),
+            pending: Bytes::new(),
+            sleep: None,
+        }
+    }
+
+    /// Up to a tenth of a second's worth of data, so output stays smooth.
+    fn chunk_len(&self) -> usize {
+        match self.bytes_per_sec {
+            Some(rate) => self.pending.len().min((rate as usize / 10).max(1)),
+            None => self.pending.len(),
+        }
+    }
+
+    fn delay_for(&self, len: usize) -> Duration {
+        let mut delay = match self.bytes_per_sec {
+            Some(rate) => Duration::from_secs_f64(len as f64 / rate as f64),
+            None => Duration::ZERO,
+        };
+        if self.stall_probability > 0.0 && rand::random::<f64>() < self.stall_probability {
+            delay += self.stall;
+        }
+        delay
+    }
+}
+
+impl<B> Body for ThrottledBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, B::Error>>> {
+        let this = self.get_mut();
+        loop {
+            // Each chunk waits out its own transfer time before it is released.
+            if let Some(sleep) = this.sleep.as_mut() {
+                ready!(sleep.as_mut().poll(cx));
+                this.sleep = None;
+                let len = this.chunk_len();
+                return Poll::Ready(Some(Ok(Frame::data(this.pending.split_to(len)))));
+            }
+            if !this.pending.is_empty() {
+                let delay = this.delay_for(this.chunk_len());
+                this.sleep = Some(Box::pin(tokio::time::sleep(delay)));
+                continue;
+            }
+            match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => this.pending = data,
+                    Err(frame) => return Poll::Ready(Some(Ok(frame))),
+                },
+                other => return Poll::Ready(other),
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.pending.is_empty() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let inner = self.inner.size_hint();
+        let pending = self.pending.len() as u64;
+        let mut hint = SizeHint::new();
+        hint.set_lower(inner.lower() + pending);
+        if let Some(upper) = inner.upper() {
+            hint.set_upper(upper + pending);
+        }
+        hint
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{BodyExt, Full};
+    use tokio::time::Instant;
+
+    fn profile(name: &str) -> ThrottleProfile {
+        ThrottleProfile {
+            name: name.to_string(),
+            downstream_kbps: Some(8),
+            upstream_kbps: None,
+            latency_ms: 100,
+            jitter_ms: 0,
+            stall_probability: 0.0,
+            stall_ms: 1000,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_body_rate_limited() {
+        // 8 kbps is 1000 bytes per second.
+        let body = Full::new(Bytes::from(vec![b'x'; 2500]));
+        let throttled = ThrottledBody::new(body, Some(8), &profile("slow"));
+        assert_eq!(throttled.size_hint().exact(), Some(2500));
+
+        let start = Instant::now();
+        let collected = throttled.collect().await.unwrap().to_bytes();
+        assert_eq!(collected.len(), 2500);
+        assert_eq!(start.elapsed(), Duration::from_millis(2500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stalls_add_delay() {
+        let mut stalling = profile("lossy");
+        stalling.stall_probability = 1.0;
+        let body = Full::new(Bytes::from_static(b"abc"));
+        let throttled = ThrottledBody::new(body, None, &stalling);
+
+        let start = Instant::now();
+        throttled.collect().await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_route_profile_overrides_global() {
+        let manager = ThrottleManager::new();
+        assert_eq!(manager.profile_for(Some("api")), None);
+
+        manager.set_active(None, Some("dsl".to_string())).unwrap();
+        manager
+            .set_active(Some("api"), Some("3g".to_string()))
+            .unwrap();
+        assert_eq!(manager.profile_for(Some("api")).unwrap().name, "3g");
+        assert_eq!(manager.profile_for(Some("web")).unwrap().name, "dsl");
+        assert_eq!(manager.profile_for(None).unwrap().name, "dsl");
+
+        manager.set_active(Some("api"), None).unwrap();
+        assert_eq!(manager.profile_for(Some("api")).unwrap().name, "dsl");
+        assert!(manager.set_active(None, Some("56k".to_string())).is_err());
+        assert_eq!(latency(&profile("x")), Duration::from_millis(100));
+    }
+}
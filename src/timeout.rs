@@ -0,0 +1,407 @@
+use crate::config::TimeoutConfig;
+use crate::state::SharedState;
+use bytes::Bytes;
+use hyper::StatusCode;
+use hyper::body::{Body, Frame, SizeHint};
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+
+const DEFAULT_HEADER_READ_MS: u64 = 30_000;
+const DEFAULT_BODY_IDLE_MS: u64 = 30_000;
+const DEFAULT_CONNECT_MS: u64 = 10_000;
+
+/// The part of an exchange that ran out of time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    HeaderRead,
+    RequestBody,
+    Connect,
+    ResponseHeader,
+    Request,
+}
+
+impl Stage {
+    /// Client-side stalls are the client's fault (408); the rest are the
+    /// upstream's (504).
+    pub fn status(self) -> StatusCode {
+        match self {
+            Stage::HeaderRead | Stage::RequestBody => StatusCode::REQUEST_TIMEOUT,
+            Stage::Connect | Stage::ResponseHeader | Stage::Request => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+}
+
+impl std::fmt::Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stage::HeaderRead => write!(f, "request header read"),
+            Stage::RequestBody => write!(f, "request body idle"),
+            Stage::Connect => write!(f, "upstream connect"),
+            Stage::ResponseHeader => write!(f, "upstream response header"),
+            Stage::Request => write!(f, "request deadline"),
+        }
+    }
+}
+
+/// Body error raised when a [`TimeoutBody`] runs out of time.
+#[derive(Debug, Clone, Copy)]
+pub struct TimedOut(pub Stage);
+
+impl std::fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} timeout", self.0)
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Finds which stage timed out among the causes of a client error. The
+/// connector reports connect timeouts as `io::ErrorKind::TimedOut`.
+pub fn stage_of(err: &(dyn Error + 'static)) -> Option<Stage> {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(TimedOut(stage)) = err.downcast_ref::<TimedOut>() {
+            return Some(*stage);
+        }
+        if let Some(io) = err.downcast_ref::<std::io::Error>()
+            && io.kind() == std::io::ErrorKind::TimedOut
+        {
+            return Some(Stage::Connect);
+        }
+        source = err.source();
+    }
+    None
+}
+
+/// Whether a client connection has sent request bytes since the last
+/// response went out. Hyper's header-read timeout also runs while a
+/// keep-alive connection sits idle between requests, and only a timeout
+/// with part of a request in hand is a stalled client.
+#[derive(Clone, Default)]
+pub struct Activity(Arc<AtomicBool>);
+
+impl Activity {
+    pub fn request_pending(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Client connection that keeps its [`Activity`] up to date.
+pub struct ActivityIo<S> {
+    inner: S,
+    activity: Activity,
+}
+
+impl<S> ActivityIo<S> {
+    pub fn new(inner: S) -> (Self, Activity) {
+        let activity = Activity::default();
+        let io = Self {
+            inner,
+            activity: activity.clone(),
+        };
+        (io, activity)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ActivityIo<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if buf.filled().len() > before {
+            this.activity.0.store(true, Ordering::Relaxed);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ActivityIo<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        if written > 0 {
+            this.activity.0.store(false, Ordering::Relaxed);
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+pub fn header_read(global: &TimeoutConfig) -> Option<Duration> {
+    resolve_ms(global.header_read_ms, None, DEFAULT_HEADER_READ_MS)
+}
+
+fn resolve_ms(global: Option<u64>, route: Option<u64>, default: u64) -> Option<Duration> {
+    match route.or(global).unwrap_or(default) {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+/// Timeouts for one request, resolved from its route and the global settings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    pub body_idle: Option<Duration>,
+    pub connect: Option<Duration>,
+    pub response_header: Option<Duration>,
+    /// Limit on the whole exchange, counted from [`Timeouts::start`].
+    pub request: Option<Duration>,
+    /// When the whole exchange must be over, once started.
+    pub deadline: Option<Instant>,
+}
+
+impl Timeouts {
+    pub fn resolve(global: &TimeoutConfig, route: Option<&TimeoutConfig>) -> Self {
+        let pick = |field: fn(&TimeoutConfig) -> Option<u64>, default| {
+            resolve_ms(field(global), route.and_then(field), default)
+        };
+        Self {
+            body_idle: pick(|t| t.body_idle_ms, DEFAULT_BODY_IDLE_MS),
+            connect: pick(|t| t.connect_ms, DEFAULT_CONNECT_MS),
+            response_header: pick(|t| t.response_header_ms, 0),
+            request: pick(|t| t.request_ms, 0),
+            deadline: None,
+        }
+    }
+
+    /// Starts the request deadline as the request is first sent upstream,
+    /// so throttling, breakpoints and queueing don't count against it.
+    /// Later calls, such as retries, keep the original deadline.
+    pub fn start(&mut self) {
+        if self.deadline.is_none() {
+            self.deadline = self.request.map(|limit| Instant::now() + limit);
+        }
+    }
+
+    /// The earlier of the response-header timeout and the deadline, with
+    /// the stage to blame when it passes.
+    pub fn response_header_limit(&self) -> Option<(Instant, Stage)> {
+        let header = self
+            .response_header
+            .map(|limit| (Instant::now() + limit, Stage::ResponseHeader));
+        let deadline = self.deadline.map(|at| (at, Stage::Request));
+        match (header, deadline) {
+            (Some(header), Some(deadline)) => Some(if deadline.0 <= header.0 {
+                deadline
+            } else {
+                header
+            }),
+            (header, deadline) => header.or(deadline),
+        }
+    }
+}
+
+/// Body that fails with [`TimedOut`] when no frame arrives within `idle` or
+/// `deadline` passes, counting the timeout against `stage` in `state` if
+/// given.
+pub struct TimeoutBody<B> {
+    inner: B,
+    idle: Option<Duration>,
+    deadline: Option<Instant>,
+    stage: Stage,
+    state: Option<Arc<SharedState>>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    armed: bool,
+}
+
+impl<B> TimeoutBody<B> {
+    pub fn new(
+        inner: B,
+        idle: Option<Duration>,
+        deadline: Option<Instant>,
+        stage: Stage,
+        state: Option<Arc<SharedState>>,
+    ) -> Self {
+        Self {
+            inner,
+            idle,
+            deadline,
+            stage,
+            state,
+            sleep: None,
+            armed: false,
+        }
+    }
+
+    fn next_wake(&self) -> Option<Instant> {
+        let idle = self.idle.map(|idle| Instant::now() + idle);
+        match (idle, self.deadline) {
+            (Some(idle), Some(deadline)) => Some(idle.min(deadline)),
+            (idle, deadline) => idle.or(deadline),
+        }
+    }
+}
+
+impl<B> Body for TimeoutBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: Into<GenericError>,
+{
+    type Data = Bytes;
+    type Error = GenericError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, GenericError>>> {
+        let this = self.get_mut();
+        if let Poll::Ready(frame) = Pin::new(&mut this.inner).poll_frame(cx) {
+            this.armed = false;
+            return Poll::Ready(frame.map(|frame| frame.map_err(Into::into)));
+        }
+
+        if !this.armed {
+            let Some(wake) = this.next_wake() else {
+                return Poll::Pending;
+            };
+            match &mut this.sleep {
+                Some(sleep) => sleep.as_mut().reset(wake),
+                None => this.sleep = Some(Box::pin(tokio::time::sleep_until(wake))),
+            }
+            this.armed = true;
+        }
+        if let Some(sleep) = &mut this.sleep {
+            ready!(sleep.as_mut().poll(cx));
+        }
+        this.armed = false;
+        if let Some(state) = &this.state {
+            state.record_timeout(this.stage);
+        }
+        Poll::Ready(Some(Err(TimedOut(this.stage).into())))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    /// Sends one chunk, then stalls forever.
+    struct Stalled(bool);
+
+    impl Body for Stalled {
+        type Data = Bytes;
+        type Error = GenericError;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, GenericError>>> {
+            if std::mem::replace(&mut self.0, true) {
+                Poll::Pending
+            } else {
+                Poll::Ready(Some(Ok(Frame::data(Bytes::from_static(b"a")))))
+            }
+        }
+    }
+
+    #[test]
+    fn test_route_overrides_global_and_zero_disables() {
+        let global = TimeoutConfig {
+            connect_ms: Some(2000),
+            request_ms: Some(5000),
+            ..Default::default()
+        };
+        let route = TimeoutConfig {
+            body_idle_ms: Some(0),
+            request_ms: Some(1000),
+            ..Default::default()
+        };
+
+        let timeouts = Timeouts::resolve(&global, Some(&route));
+        assert_eq!(timeouts.body_idle, None);
+        assert_eq!(timeouts.connect, Some(Duration::from_millis(2000)));
+        assert_eq!(timeouts.response_header, None);
+        assert_eq!(timeouts.request, Some(Duration::from_millis(1000)));
+
+        let mut defaults = Timeouts::resolve(&TimeoutConfig::default(), None);
+        defaults.start();
+        assert_eq!(defaults.deadline, None);
+        assert_eq!(header_read(&global), Some(Duration::from_secs(30)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_deadline_runs_from_first_start() {
+        let global = TimeoutConfig {
+            request_ms: Some(1000),
+            ..Default::default()
+        };
+        let mut timeouts = Timeouts::resolve(&global, None);
+        assert_eq!(timeouts.deadline, None);
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        timeouts.start();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        assert_eq!(timeouts.deadline, Some(deadline));
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        timeouts.start();
+        assert_eq!(timeouts.deadline, Some(deadline));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_response_header_limit_blames_earlier_timer() {
+        let mut timeouts = Timeouts::resolve(&TimeoutConfig::default(), None);
+        assert_eq!(timeouts.response_header_limit(), None);
+        timeouts.response_header = Some(Duration::from_secs(60));
+        assert_eq!(
+            timeouts.response_header_limit().unwrap().1,
+            Stage::ResponseHeader
+        );
+        timeouts.deadline = Some(Instant::now() + Duration::from_secs(1));
+        assert_eq!(timeouts.response_header_limit().unwrap().1, Stage::Request);
+        timeouts.response_header = None;
+        assert_eq!(timeouts.response_header_limit().unwrap().1, Stage::Request);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_body_times_out_between_chunks() {
+        let state = Arc::new(SharedState::default());
+        let mut body = TimeoutBody::new(
+            Stalled(false),
+            Some(Duration::from_millis(100)),
+            None,
+            Stage::RequestBody,
+            Some(Arc::clone(&state)),
+        );
+
+        assert!(body.frame().await.unwrap().is_ok());
+
+        let err = body.frame().await.unwrap().unwrap_err();
+        assert_eq!(stage_of(&*err), Some(Stage::RequestBody));
+        assert_eq!(state.get_metrics().timeouts.request_body, 1);
+        assert_eq!(state.get_metrics().total_errors, 1);
+    }
+}
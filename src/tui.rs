@@ -1,3 +1,9 @@
+use crate::breakpoint::{BreakpointEdits, BreakpointPhase, PendingBreakpoint};
+use crate::cache::CacheStatus;
+use crate::circuit::CircuitState;
+use crate::config::BreakpointRule;
+use crate::state::{MirrorDiff, RequestLog, UpstreamStatus};
+use crate::throttle::ThrottleStatus;
 use anyhow::Result;
 use crossterm::{
     event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind},
@@ -22,18 +28,52 @@ struct Metrics {
     total_requests: u64,
     active_requests: u64,
     total_errors: u64,
+    #[serde(default)]
+    upstreams: Vec<UpstreamStatus>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Breakpoints {
+    rules: Vec<BreakpointRule>,
+    pending: Vec<PendingBreakpoint>,
 }
 
 enum Tab {
     Stats,
     Requests,
+    Breakpoints,
+}
+
+/// Text entry state for the breakpoints tab.
+enum InputMode {
+    Normal,
+    EditBreakpoint {
+        id: u64,
+        phase: BreakpointPhase,
+        buffer: String,
+    },
+    AbortStatus {
+        id: u64,
+        buffer: String,
+    },
+    NewRule {
+        response: bool,
+        buffer: String,
+    },
 }
 
 pub struct TuiApp {
     control_url: String,
     selected_tab: Tab,
-    requests: Vec<String>,
+    metrics: Option<Metrics>,
+    throttle: ThrottleStatus,
+    requests: Vec<RequestLog>,
     list_state: ListState,
+    breakpoint_rules: Vec<BreakpointRule>,
+    pending_breakpoints: Vec<PendingBreakpoint>,
+    breakpoint_state: ListState,
+    input_mode: InputMode,
+    status_message: Option<String>,
     should_quit: bool,
 }
 
@@ -45,8 +85,15 @@ impl TuiApp {
         Self {
             control_url,
             selected_tab: Tab::Stats,
+            metrics: None,
+            throttle: ThrottleStatus::default(),
             requests: Vec::new(),
             list_state,
+            breakpoint_rules: Vec::new(),
+            pending_breakpoints: Vec::new(),
+            breakpoint_state: ListState::default(),
+            input_mode: InputMode::Normal,
+            status_message: None,
             should_quit: false,
         }
     }
@@ -86,36 +133,14 @@ impl TuiApp {
             terminal.draw(|f| self.ui(f))?;
 
             // Handle events with timeout
-            if event::poll(Duration::from_millis(500))? {
-                if let Event::Key(key) = event::read()? {
-                    if key.kind == KeyEventKind::Press {
-                        match key.code {
-                            KeyCode::Char('q') | KeyCode::Esc => {
-                                self.should_quit = true;
-                            }
-                            KeyCode::Char('c')
-                                if key.modifiers.contains(event::KeyModifiers::CONTROL) =>
-                            {
-                                self.should_quit = true;
-                            }
-                            KeyCode::Char('1') => {
-                                self.selected_tab = Tab::Stats;
-                            }
-                            KeyCode::Char('2') => {
-                                self.selected_tab = Tab::Requests;
-                            }
-                            KeyCode::Down => {
-                                self.next_request();
-                            }
-                            KeyCode::Up => {
-                                self.previous_request();
-                            }
-                            KeyCode::Char('r') => {
-                                self.replay_request();
-                            }
-                            _ => {}
-                        }
-                    }
+            if event::poll(Duration::from_millis(500))?
+                && let Event::Key(key) = event::read()?
+                && key.kind == KeyEventKind::Press
+            {
+                if matches!(self.input_mode, InputMode::Normal) {
+                    self.handle_key(key).await;
+                } else {
+                    self.handle_input_key(key).await;
                 }
             }
 
@@ -127,6 +152,131 @@ impl TuiApp {
         Ok(())
     }
 
+    async fn handle_key(&mut self, key: event::KeyEvent) {
+        let on_breakpoints = matches!(self.selected_tab, Tab::Breakpoints);
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => {
+                self.should_quit = true;
+            }
+            KeyCode::Char('c') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                self.should_quit = true;
+            }
+            KeyCode::Char('1') => {
+                self.selected_tab = Tab::Stats;
+            }
+            KeyCode::Char('2') => {
+                self.selected_tab = Tab::Requests;
+            }
+            KeyCode::Char('3') => {
+                self.selected_tab = Tab::Breakpoints;
+            }
+            KeyCode::Down if on_breakpoints => {
+                self.next_breakpoint();
+            }
+            KeyCode::Up if on_breakpoints => {
+                self.previous_breakpoint();
+            }
+            KeyCode::Down => {
+                self.next_request();
+            }
+            KeyCode::Up => {
+                self.previous_request();
+            }
+            KeyCode::Char('r') => {
+                self.replay_request();
+            }
+            KeyCode::Char('c') if on_breakpoints => {
+                if let Some(pending) = self.selected_breakpoint() {
+                    let id = pending.id;
+                    self.continue_breakpoint(id, BreakpointEdits::default())
+                        .await;
+                }
+            }
+            KeyCode::Char('e') if on_breakpoints => {
+                if let Some(pending) = self.selected_breakpoint() {
+                    self.input_mode = InputMode::EditBreakpoint {
+                        id: pending.id,
+                        phase: pending.phase,
+                        buffer: format_edit(pending),
+                    };
+                }
+            }
+            KeyCode::Char('a') if on_breakpoints => {
+                if let Some(pending) = self.selected_breakpoint() {
+                    self.input_mode = InputMode::AbortStatus {
+                        id: pending.id,
+                        buffer: "502".to_string(),
+                    };
+                }
+            }
+            KeyCode::Char('b') if on_breakpoints => {
+                self.input_mode = InputMode::NewRule {
+                    response: false,
+                    buffer: String::new(),
+                };
+            }
+            KeyCode::Char('B') if on_breakpoints => {
+                self.input_mode = InputMode::NewRule {
+                    response: true,
+                    buffer: String::new(),
+                };
+            }
+            KeyCode::Char('x') if on_breakpoints => {
+                self.clear_breakpoint_rules().await;
+            }
+            KeyCode::Char('t') => {
+                self.cycle_throttle().await;
+            }
+            _ => {}
+        }
+    }
+
+    async fn handle_input_key(&mut self, key: event::KeyEvent) {
+        let submit = match key.code {
+            KeyCode::Esc => {
+                self.input_mode = InputMode::Normal;
+                return;
+            }
+            KeyCode::Char('s') if key.modifiers.contains(event::KeyModifiers::CONTROL) => true,
+            KeyCode::Enter => !matches!(self.input_mode, InputMode::EditBreakpoint { .. }),
+            _ => false,
+        };
+
+        if submit {
+            match std::mem::replace(&mut self.input_mode, InputMode::Normal) {
+                InputMode::EditBreakpoint { id, phase, buffer } => {
+                    self.continue_breakpoint(id, parse_edit(&buffer, phase))
+                        .await;
+                }
+                InputMode::AbortStatus { id, buffer } => match buffer.trim().parse() {
+                    Ok(status) => self.abort_breakpoint(id, status).await,
+                    Err(_) => self.status_message = Some(format!("Invalid status: {}", buffer)),
+                },
+                InputMode::NewRule { response, buffer } => {
+                    self.add_breakpoint_rule(parse_rule(&buffer, response))
+                        .await;
+                }
+                InputMode::Normal => {}
+            }
+            return;
+        }
+
+        let buffer = match &mut self.input_mode {
+            InputMode::EditBreakpoint { buffer, .. }
+            | InputMode::AbortStatus { buffer, .. }
+            | InputMode::NewRule { buffer, .. } => buffer,
+            InputMode::Normal => return,
+        };
+        match key.code {
+            KeyCode::Enter => buffer.push('\n'),
+            KeyCode::Backspace => {
+                buffer.pop();
+            }
+            KeyCode::Char(c) => buffer.push(c),
+            _ => {}
+        }
+    }
+
     fn ui(&mut self, f: &mut Frame) {
         let chunks = Layout::default()
             .direction(Direction::Vertical)
@@ -134,7 +284,7 @@ impl TuiApp {
             .split(f.area());
 
         // Tab bar
-        let tab_titles = vec!["1. Stats", "2. Requests"];
+        let tab_titles = vec!["1. Stats", "2. Requests", "3. Breakpoints"];
         let tabs = Tabs::new(tab_titles)
             .block(
                 Block::default()
@@ -144,6 +294,7 @@ impl TuiApp {
             .select(match self.selected_tab {
                 Tab::Stats => 0,
                 Tab::Requests => 1,
+                Tab::Breakpoints => 2,
             })
             .style(Style::default().fg(Color::White))
             .highlight_style(
@@ -158,11 +309,16 @@ impl TuiApp {
         match self.selected_tab {
             Tab::Stats => self.render_stats(f, chunks[1]),
             Tab::Requests => self.render_requests(f, chunks[1]),
+            Tab::Breakpoints => self.render_breakpoints(f, chunks[1]),
         }
     }
 
     fn render_stats(&self, f: &mut Frame, area: ratatui::layout::Rect) {
-        let text = vec![
+        let status = match &self.metrics {
+            Some(_) => Span::styled("Connected", Style::default().fg(Color::Green)),
+            None => Span::styled("Disconnected", Style::default().fg(Color::Red)),
+        };
+        let mut text = vec![
             Line::from(vec![
                 Span::styled(
                     "Control API: ",
@@ -173,11 +329,59 @@ impl TuiApp {
             Line::from(""),
             Line::from(vec![
                 Span::styled("Status: ", Style::default().add_modifier(Modifier::BOLD)),
-                Span::styled("Connected", Style::default().fg(Color::Green)),
+                status,
             ]),
             Line::from(""),
             Line::from("Use ↑/↓ to navigate, 'r' to replay, 'q'/Esc/Ctrl+C to quit"),
+            Line::from(
+                "Breakpoints: 'c' continue, 'e' edit, 'a' abort, 'b'/'B' add request/response rule, 'x' clear rules",
+            ),
+            Line::from("'t' cycles the global throttle profile"),
         ];
+        text.insert(
+            3,
+            Line::from(vec![
+                Span::styled("Throttle: ", Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(describe_throttle(&self.throttle)),
+            ]),
+        );
+        if let Some(metrics) = &self.metrics {
+            text.insert(
+                3,
+                Line::from(format!("Total Requests: {}", metrics.total_requests)),
+            );
+            text.insert(
+                4,
+                Line::from(format!("Active Requests: {}", metrics.active_requests)),
+            );
+            text.insert(
+                5,
+                Line::from(format!("Total Errors: {}", metrics.total_errors)),
+            );
+            text.insert(6, Line::from(""));
+            let upstreams = metrics.upstreams.iter().map(|upstream| {
+                let latency = upstream
+                    .latency_ewma_ms
+                    .map(|ms| format!(", ~{:.0}ms", ms))
+                    .unwrap_or_default();
+                Line::from(vec![
+                    circuit_badge(upstream.circuit),
+                    Span::raw(format!(
+                        " {} ({}/{} failed, {} in flight{})",
+                        upstream.url,
+                        upstream.failures,
+                        upstream.requests,
+                        upstream.in_flight,
+                        latency
+                    )),
+                ])
+            });
+            let end = 7 + metrics.upstreams.len();
+            text.splice(7..7, upstreams);
+            if !metrics.upstreams.is_empty() {
+                text.insert(end, Line::from(""));
+            }
+        }
 
         let paragraph =
             Paragraph::new(text).block(Block::default().borders(Borders::ALL).title("Statistics"));
@@ -186,10 +390,38 @@ impl TuiApp {
     }
 
     fn render_requests(&mut self, f: &mut Frame, area: ratatui::layout::Rect) {
+        let chunks = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+            .split(area);
+
         let items: Vec<ListItem> = self
             .requests
             .iter()
-            .map(|r| ListItem::new(r.as_str()))
+            .map(|r| {
+                let mut line = format!(
+                    "{} {} {}{} {}ms",
+                    r.status, r.method, r.host, r.path, r.duration_ms
+                );
+                if let Some(fault) = &r.fault {
+                    line.push_str(&format!(" [fault: {}]", fault));
+                }
+                if r.attempts > 1 {
+                    line.push_str(&format!(" [attempts {}]", r.attempts));
+                }
+                if !r.websocket_messages.is_empty() {
+                    line.push_str(&format!(" [ws {}]", r.websocket_messages.len()));
+                }
+                match r.cache {
+                    Some(CacheStatus::Hit) => line.push_str(" [cache hit]"),
+                    Some(CacheStatus::Revalidated) => line.push_str(" [cache revalidated]"),
+                    _ => {}
+                }
+                if r.mirror.as_ref().is_some_and(MirrorDiff::differs) {
+                    line.push_str(" [mirror differs]");
+                }
+                ListItem::new(line)
+            })
             .collect();
 
         let list = List::new(items)
@@ -201,7 +433,176 @@ impl TuiApp {
             )
             .highlight_symbol(">> ");
 
-        f.render_stateful_widget(list, area, &mut self.list_state);
+        f.render_stateful_widget(list, chunks[0], &mut self.list_state);
+
+        let detail: Vec<Line> = match self
+            .list_state
+            .selected()
+            .and_then(|i| self.requests.get(i))
+        {
+            Some(log) => {
+                let mut lines = vec![
+                    Line::from(format!("{} {}{}", log.method, log.host, log.path)),
+                    Line::from(format!(
+                        "Status: {}  Upstream: {}",
+                        log.status, log.upstream
+                    )),
+                    Line::from(format!("At: {}", log.timestamp.format("%H:%M:%S%.3f"))),
+                ];
+                if let Some(cache) = log.cache {
+                    lines.push(Line::from(format!("Cache: {:?}", cache).to_lowercase()));
+                }
+                if let Some(diff) = &log.mirror {
+                    let body = match diff.body_matches {
+                        Some(true) => "same body",
+                        Some(false) => "different body",
+                        None => "body not compared",
+                    };
+                    lines.push(Line::from(format!(
+                        "Mirror: {} answered {} ({})",
+                        diff.upstream, diff.shadow_status, body
+                    )));
+                }
+                lines.push(Line::from(""));
+                lines.extend(log.websocket_messages.iter().map(|m| {
+                    let arrow = if m.direction == "client" {
+                        "→"
+                    } else {
+                        "←"
+                    };
+                    Line::from(format!(
+                        "{} {} {} ({} B) {}",
+                        m.timestamp.format("%H:%M:%S"),
+                        arrow,
+                        m.opcode,
+                        m.length,
+                        m.preview
+                    ))
+                }));
+                lines
+            }
+            None => vec![Line::from("No request selected")],
+        };
+        let paragraph =
+            Paragraph::new(detail).block(Block::default().borders(Borders::ALL).title("Detail"));
+        f.render_widget(paragraph, chunks[1]);
+    }
+
+    fn render_breakpoints(&mut self, f: &mut Frame, area: ratatui::layout::Rect) {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(self.breakpoint_rules.len().max(1) as u16 + 2),
+                Constraint::Min(0),
+                Constraint::Length(3),
+            ])
+            .split(area);
+
+        let rules: Vec<ListItem> = if self.breakpoint_rules.is_empty() {
+            vec![ListItem::new("No rules ('b' request, 'B' response)")]
+        } else {
+            self.breakpoint_rules
+                .iter()
+                .map(|rule| ListItem::new(describe_rule(rule)))
+                .collect()
+        };
+        f.render_widget(
+            List::new(rules).block(Block::default().borders(Borders::ALL).title("Rules")),
+            chunks[0],
+        );
+
+        let items: Vec<ListItem> = self
+            .pending_breakpoints
+            .iter()
+            .map(|p| {
+                let phase = match p.phase {
+                    BreakpointPhase::Request => "REQ ",
+                    BreakpointPhase::Response => "RESP",
+                };
+                let status = p.status.map(|s| format!(" -> {}", s)).unwrap_or_default();
+                ListItem::new(format!(
+                    "#{} {} {} {}{}{} ({})",
+                    p.id,
+                    phase,
+                    p.method,
+                    p.host,
+                    p.path,
+                    status,
+                    p.created_at.format("%H:%M:%S")
+                ))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title("Paused"))
+            .highlight_style(
+                Style::default()
+                    .bg(Color::DarkGray)
+                    .add_modifier(Modifier::BOLD),
+            )
+            .highlight_symbol(">> ");
+        f.render_stateful_widget(list, chunks[1], &mut self.breakpoint_state);
+
+        let (title, text) = match &self.input_mode {
+            InputMode::Normal => (
+                "Status",
+                self.status_message.clone().unwrap_or_else(|| {
+                    "'c' continue, 'e' edit, 'a' abort, 'x' clear rules".to_string()
+                }),
+            ),
+            InputMode::AbortStatus { buffer, .. } => {
+                ("Abort with status (Enter, Esc cancel)", buffer.clone())
+            }
+            InputMode::NewRule { response, buffer } => (
+                if *response {
+                    "New response rule: [METHOD] host[/path] (Enter, Esc cancel)"
+                } else {
+                    "New request rule: [METHOD] host[/path] (Enter, Esc cancel)"
+                },
+                buffer.clone(),
+            ),
+            InputMode::EditBreakpoint { buffer, .. } => {
+                let editor = Paragraph::new(buffer.as_str()).block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title("Edit (Ctrl+S continue, Esc cancel)"),
+                );
+                f.render_widget(ratatui::widgets::Clear, chunks[1]);
+                f.render_widget(editor, chunks[1]);
+                ("Editing", String::new())
+            }
+        };
+        f.render_widget(
+            Paragraph::new(text).block(Block::default().borders(Borders::ALL).title(title)),
+            chunks[2],
+        );
+    }
+
+    fn selected_breakpoint(&self) -> Option<&PendingBreakpoint> {
+        self.breakpoint_state
+            .selected()
+            .and_then(|i| self.pending_breakpoints.get(i))
+    }
+
+    fn next_breakpoint(&mut self) {
+        if self.pending_breakpoints.is_empty() {
+            return;
+        }
+        let i = match self.breakpoint_state.selected() {
+            Some(i) if i + 1 < self.pending_breakpoints.len() => i + 1,
+            _ => 0,
+        };
+        self.breakpoint_state.select(Some(i));
+    }
+
+    fn previous_breakpoint(&mut self) {
+        if self.pending_breakpoints.is_empty() {
+            return;
+        }
+        let i = match self.breakpoint_state.selected() {
+            Some(0) | None => self.pending_breakpoints.len() - 1,
+            Some(i) => i - 1,
+        };
+        self.breakpoint_state.select(Some(i));
     }
 
     fn next_request(&mut self) {
@@ -239,13 +640,81 @@ impl TuiApp {
     }
 
     fn replay_request(&self) {
-        if let Some(selected) = self.list_state.selected() {
-            if selected < self.requests.len() {
-                info!("Replaying request: {}", self.requests[selected]);
-            }
+        if let Some(selected) = self.list_state.selected()
+            && selected < self.requests.len()
+        {
+            let log = &self.requests[selected];
+            info!("Replaying request: {} {}{}", log.method, log.host, log.path);
         }
     }
 
+    async fn continue_breakpoint(&mut self, id: u64, edits: BreakpointEdits) {
+        let url = format!("{}/breakpoints/{}/continue", self.control_url, id);
+        self.post_breakpoint_action(&url, serde_json::to_value(edits).unwrap_or_default())
+            .await;
+    }
+
+    async fn abort_breakpoint(&mut self, id: u64, status: u16) {
+        let url = format!("{}/breakpoints/{}/abort", self.control_url, id);
+        self.post_breakpoint_action(&url, serde_json::json!({ "status": status }))
+            .await;
+    }
+
+    async fn add_breakpoint_rule(&mut self, rule: BreakpointRule) {
+        let url = format!("{}/breakpoints/rules", self.control_url);
+        self.post_breakpoint_action(&url, serde_json::to_value(rule).unwrap_or_default())
+            .await;
+    }
+
+    async fn clear_breakpoint_rules(&mut self) {
+        let url = format!("{}/breakpoints/rules", self.control_url);
+        let result = reqwest::Client::new().delete(&url).send().await;
+        self.status_message = Some(match result {
+            Ok(response) => format!("Clear rules: {}", response.status()),
+            Err(e) => format!("Clear rules failed: {}", e),
+        });
+    }
+
+    /// Switches the global throttle to the next profile, then off.
+    async fn cycle_throttle(&mut self) {
+        let names: Vec<&str> = self
+            .throttle
+            .profiles
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect();
+        let next = match self.throttle.active.as_deref() {
+            None => names.first().copied(),
+            Some(active) => names
+                .iter()
+                .position(|name| *name == active)
+                .and_then(|i| names.get(i + 1))
+                .copied(),
+        };
+        let url = format!("{}/throttle", self.control_url);
+        let result = reqwest::Client::new()
+            .post(&url)
+            .json(&serde_json::json!({ "profile": next }))
+            .send()
+            .await;
+        let message = match result {
+            Ok(response) if response.status().is_success() => {
+                format!("Throttle: {}", next.unwrap_or("off"))
+            }
+            Ok(response) => format!("Throttle: {}", response.status()),
+            Err(e) => format!("Throttle failed: {}", e),
+        };
+        self.status_message = Some(message);
+    }
+
+    async fn post_breakpoint_action(&mut self, url: &str, body: serde_json::Value) {
+        let result = reqwest::Client::new().post(url).json(&body).send().await;
+        self.status_message = Some(match result {
+            Ok(response) => format!("{}: {}", url, response.status()),
+            Err(e) => format!("{}: {}", url, e),
+        });
+    }
+
     async fn fetch_data(&mut self) -> Result<()> {
         // Fetch metrics
         let metrics_url = format!("{}/metrics", self.control_url);
@@ -253,26 +722,190 @@ impl TuiApp {
             .timeout(Duration::from_secs(2))
             .build()?;
 
-        match client.get(&metrics_url).send().await {
-            Ok(response) => {
-                if let Ok(metrics) = response.json::<Metrics>().await {
-                    // Update requests list with dummy data for now
-                    self.requests.clear();
-                    self.requests
-                        .push(format!("Total Requests: {}", metrics.total_requests));
-                    self.requests
-                        .push(format!("Active Requests: {}", metrics.active_requests));
-                    self.requests
-                        .push(format!("Total Errors: {}", metrics.total_errors));
-                }
+        self.metrics = match client.get(&metrics_url).send().await {
+            Ok(response) => response.json::<Metrics>().await.ok(),
+            Err(_) => None,
+        };
+
+        // Fetch request log, newest first
+        let requests_url = format!("{}/requests", self.control_url);
+        if let Ok(response) = client.get(&requests_url).send().await
+            && let Ok(mut logs) = response.json::<Vec<RequestLog>>().await
+        {
+            logs.reverse();
+            self.requests = logs;
+            if self
+                .list_state
+                .selected()
+                .is_none_or(|i| i >= self.requests.len())
+            {
+                self.list_state.select(Some(0));
             }
-            Err(_) => {
-                self.requests.clear();
-                self.requests
-                    .push("Failed to connect to control API".to_string());
+        }
+
+        // Fetch throttle profiles
+        let throttle_url = format!("{}/throttle", self.control_url);
+        if let Ok(response) = client.get(&throttle_url).send().await
+            && let Ok(throttle) = response.json::<ThrottleStatus>().await
+        {
+            self.throttle = throttle;
+        }
+
+        // Fetch breakpoints
+        let breakpoints_url = format!("{}/breakpoints", self.control_url);
+        if let Ok(response) = client.get(&breakpoints_url).send().await
+            && let Ok(breakpoints) = response.json::<Breakpoints>().await
+        {
+            self.breakpoint_rules = breakpoints.rules;
+            self.pending_breakpoints = breakpoints.pending;
+            match self.breakpoint_state.selected() {
+                _ if self.pending_breakpoints.is_empty() => self.breakpoint_state.select(None),
+                Some(i) if i < self.pending_breakpoints.len() => {}
+                _ => self.breakpoint_state.select(Some(0)),
             }
         }
 
         Ok(())
     }
 }
+
+fn circuit_badge(state: CircuitState) -> Span<'static> {
+    let (label, color) = match state {
+        CircuitState::Closed => (" CLOSED ", Color::Green),
+        CircuitState::HalfOpen => (" HALF-OPEN ", Color::Yellow),
+        CircuitState::Open => (" OPEN ", Color::Red),
+    };
+    Span::styled(
+        label,
+        Style::default()
+            .fg(Color::Black)
+            .bg(color)
+            .add_modifier(Modifier::BOLD),
+    )
+}
+
+fn describe_throttle(status: &ThrottleStatus) -> String {
+    let mut text = status.active.clone().unwrap_or_else(|| "off".to_string());
+    if !status.routes.is_empty() {
+        let routes: Vec<String> = status
+            .routes
+            .iter()
+            .map(|(route, profile)| format!("{}={}", route, profile))
+            .collect();
+        text.push_str(&format!(" (routes: {})", routes.join(", ")));
+    }
+    text
+}
+
+fn describe_rule(rule: &BreakpointRule) -> String {
+    let phases = match (rule.request, rule.response) {
+        (true, true) => "req+resp",
+        (true, false) => "req",
+        (false, true) => "resp",
+        (false, false) => "off",
+    };
+    format!(
+        "[{}] {} {}{}",
+        phases,
+        rule.method.as_deref().unwrap_or("*"),
+        rule.host.as_deref().unwrap_or("*"),
+        rule.path_prefix.as_deref().unwrap_or("/")
+    )
+}
+
+/// Parses `[METHOD] host[/path]`, where host may be omitted (`/path`) or `*`.
+fn parse_rule(input: &str, response: bool) -> BreakpointRule {
+    let mut parts = input.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (Some(method.to_ascii_uppercase()), target),
+        (Some(target), None) => (None, target),
+        _ => (None, ""),
+    };
+    let (host, path) = match target.find('/') {
+        Some(i) => (&target[..i], Some(target[i..].to_string())),
+        None => (target, None),
+    };
+    let host = (!host.is_empty() && host != "*").then(|| host.to_string());
+
+    BreakpointRule {
+        host,
+        path_prefix: path,
+        method,
+        request: !response,
+        response,
+    }
+}
+
+/// Renders a paused exchange as editable text: an optional status line
+/// (responses only), `Name: value` headers, a blank line, then the body.
+fn format_edit(pending: &PendingBreakpoint) -> String {
+    let mut head: Vec<String> = Vec::new();
+    if let Some(status) = pending.status {
+        head.push(status.to_string());
+    }
+    head.extend(
+        pending
+            .headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}", name, value)),
+    );
+    format!("{}\n\n{}", head.join("\n"), pending.body)
+}
+
+fn parse_edit(text: &str, phase: BreakpointPhase) -> BreakpointEdits {
+    let (head, body) = text.split_once("\n\n").unwrap_or((text, ""));
+    let mut lines = head.lines();
+    let status = match phase {
+        BreakpointPhase::Response => lines.next().and_then(|l| l.trim().parse().ok()),
+        BreakpointPhase::Request => None,
+    };
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    BreakpointEdits {
+        status,
+        headers: Some(headers),
+        body: Some(body.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edit_round_trip() {
+        let pending = PendingBreakpoint {
+            id: 1,
+            phase: BreakpointPhase::Response,
+            created_at: chrono::Utc::now(),
+            method: "GET".to_string(),
+            host: "example.com".to_string(),
+            path: "/".to_string(),
+            status: Some(200),
+            headers: vec![("content-type".to_string(), "text/plain".to_string())],
+            body: "hello\n\nworld".to_string(),
+        };
+
+        let edits = parse_edit(&format_edit(&pending), BreakpointPhase::Response);
+        assert_eq!(edits.status, Some(200));
+        assert_eq!(edits.headers, Some(pending.headers.clone()));
+        assert_eq!(edits.body.as_deref(), Some("hello\n\nworld"));
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        let rule = parse_rule("post api.example.com/v1", false);
+        assert_eq!(rule.method.as_deref(), Some("POST"));
+        assert_eq!(rule.host.as_deref(), Some("api.example.com"));
+        assert_eq!(rule.path_prefix.as_deref(), Some("/v1"));
+        assert!(rule.request && !rule.response);
+
+        let rule = parse_rule("/api", true);
+        assert_eq!(rule.host, None);
+        assert_eq!(rule.path_prefix.as_deref(), Some("/api"));
+        assert!(rule.response && !rule.request);
+    }
+}
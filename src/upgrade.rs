@@ -0,0 +1,284 @@
+use crate::state::{SharedState, WebSocketMessage};
+use chrono::Utc;
+use hyper::HeaderMap;
+use hyper::header::{CONNECTION, HeaderValue, UPGRADE};
+use hyper::upgrade::OnUpgrade;
+use hyper_util::rt::TokioIo;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, warn};
+
+/// Returns the requested protocol if the message asks to upgrade the
+/// connection (`Connection: upgrade` plus an `Upgrade` header).
+pub fn requested_protocol(headers: &HeaderMap) -> Option<HeaderValue> {
+    let wants_upgrade = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    if wants_upgrade {
+        headers.get(UPGRADE).cloned()
+    } else {
+        None
+    }
+}
+
+/// Puts back the upgrade headers removed by hop-by-hop stripping.
+pub fn restore_headers(headers: &mut HeaderMap, protocol: HeaderValue) {
+    headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(UPGRADE, protocol);
+}
+
+pub fn is_websocket(protocol: &HeaderValue) -> bool {
+    protocol.as_bytes().eq_ignore_ascii_case(b"websocket")
+}
+
+/// Where captured frames go: the log entry of the handshake request.
+pub struct Capture {
+    pub state: Arc<SharedState>,
+    pub request_id: String,
+    pub max_preview: usize,
+}
+
+/// Copies bytes both ways between the upgraded client and upstream
+/// connections once both sides have switched protocols.
+pub fn splice(client: OnUpgrade, upstream: OnUpgrade, capture: Option<Capture>) {
+    tokio::spawn(async move {
+        let (client, upstream) = match tokio::try_join!(client, upstream) {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                warn!("Connection upgrade failed: {}", e);
+                return;
+            }
+        };
+        let (sent, received) = relay(TokioIo::new(client), TokioIo::new(upstream), capture).await;
+        debug!(
+            "Upgraded connection closed: {} bytes up, {} bytes down",
+            sent, received
+        );
+    });
+}
+
+/// Copies bytes between `client` and `upstream` until both directions have
+/// closed. Returns the byte counts sent up and down.
+pub async fn relay<C, U>(client: C, upstream: U, capture: Option<Capture>) -> (u64, u64)
+where
+    C: AsyncRead + AsyncWrite,
+    U: AsyncRead + AsyncWrite,
+{
+    let (client_read, client_write) = tokio::io::split(client);
+    let (upstream_read, upstream_write) = tokio::io::split(upstream);
+
+    let capture = capture.map(Arc::new);
+    let to_upstream = pump(
+        client_read,
+        upstream_write,
+        capture.clone().map(|c| (c, "client")),
+    );
+    let to_client = pump(upstream_read, client_write, capture.map(|c| (c, "server")));
+    tokio::join!(to_upstream, to_client)
+}
+
+async fn pump<R, W>(
+    mut reader: R,
+    mut writer: W,
+    capture: Option<(Arc<Capture>, &'static str)>,
+) -> u64
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut parser = FrameParser::default();
+    let mut buf = vec![0u8; 16 * 1024];
+    let mut total = 0;
+    loop {
+        let n = match reader.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        if writer.write_all(&buf[..n]).await.is_err() {
+            break;
+        }
+        total += n as u64;
+
+        if let Some((capture, direction)) = &capture {
+            for frame in parser.feed(&buf[..n], capture.max_preview) {
+                capture.state.add_websocket_message(
+                    &capture.request_id,
+                    WebSocketMessage {
+                        timestamp: Utc::now(),
+                        direction: direction.to_string(),
+                        opcode: frame.opcode.to_string(),
+                        length: frame.length,
+                        preview: String::from_utf8_lossy(&frame.preview).into_owned(),
+                    },
+                );
+            }
+        }
+    }
+    let _ = writer.shutdown().await;
+    total
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub opcode: &'static str,
+    pub length: u64,
+    pub preview: Vec<u8>,
+}
+
+struct PartialFrame {
+    opcode: &'static str,
+    mask: Option<[u8; 4]>,
+    length: u64,
+    offset: u64,
+    preview: Vec<u8>,
+}
+
+/// Incremental RFC 6455 frame reader that keeps only a payload preview.
+#[derive(Default)]
+pub struct FrameParser {
+    buf: Vec<u8>,
+    current: Option<PartialFrame>,
+}
+
+impl FrameParser {
+    pub fn feed(&mut self, data: &[u8], max_preview: usize) -> Vec<Frame> {
+        self.buf.extend_from_slice(data);
+        let mut frames = Vec::new();
+
+        loop {
+            match &mut self.current {
+                None => match parse_header(&self.buf) {
+                    Some((frame, consumed)) => {
+                        self.buf.drain(..consumed);
+                        self.current = Some(frame);
+                    }
+                    None => break,
+                },
+                Some(frame) => {
+                    let remaining = frame.length - frame.offset;
+                    let take = remaining.min(self.buf.len() as u64) as usize;
+                    for &byte in &self.buf[..take] {
+                        if frame.preview.len() < max_preview {
+                            let byte = match frame.mask {
+                                Some(mask) => byte ^ mask[(frame.offset % 4) as usize],
+                                None => byte,
+                            };
+                            frame.preview.push(byte);
+                        }
+                        frame.offset += 1;
+                    }
+                    self.buf.drain(..take);
+
+                    if frame.offset < frame.length {
+                        break;
+                    }
+                    let frame = self.current.take().unwrap();
+                    frames.push(Frame {
+                        opcode: frame.opcode,
+                        length: frame.length,
+                        preview: frame.preview,
+                    });
+                }
+            }
+        }
+
+        frames
+    }
+}
+
+fn parse_header(buf: &[u8]) -> Option<(PartialFrame, usize)> {
+    if buf.len() < 2 {
+        return None;
+    }
+    let opcode = match buf[0] & 0x0f {
+        0x0 => "continuation",
+        0x1 => "text",
+        0x2 => "binary",
+        0x8 => "close",
+        0x9 => "ping",
+        0xa => "pong",
+        _ => "reserved",
+    };
+    let masked = buf[1] & 0x80 != 0;
+    let (length, mut pos) = match buf[1] & 0x7f {
+        126 => (
+            u16::from_be_bytes(buf.get(2..4)?.try_into().ok()?) as u64,
+            4,
+        ),
+        127 => (u64::from_be_bytes(buf.get(2..10)?.try_into().ok()?), 10),
+        n => (n as u64, 2),
+    };
+    let mask = if masked {
+        let mask: [u8; 4] = buf.get(pos..pos + 4)?.try_into().ok()?;
+        pos += 4;
+        Some(mask)
+    } else {
+        None
+    };
+
+    Some((
+        PartialFrame {
+            opcode,
+            mask,
+            length,
+            offset: 0,
+            preview: Vec::new(),
+        },
+        pos,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn masked_text(payload: &[u8], mask: [u8; 4]) -> Vec<u8> {
+        let mut frame = vec![0x81, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn test_requested_protocol() {
+        let mut headers = HeaderMap::new();
+        headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+        assert!(requested_protocol(&headers).is_none());
+        headers.insert(CONNECTION, HeaderValue::from_static("keep-alive, Upgrade"));
+        assert!(is_websocket(&requested_protocol(&headers).unwrap()));
+    }
+
+    #[test]
+    fn test_parse_frames_across_reads() {
+        let mut bytes = masked_text(b"hello", [1, 2, 3, 4]);
+        // Unmasked binary frame with a 16-bit length
+        bytes.extend_from_slice(&[0x82, 126, 0x01, 0x00]);
+        bytes.extend(std::iter::repeat_n(7u8, 256));
+        // Empty close frame
+        bytes.extend_from_slice(&[0x88, 0x00]);
+
+        let mut parser = FrameParser::default();
+        let mut frames = Vec::new();
+        for chunk in bytes.chunks(3) {
+            frames.extend(parser.feed(chunk, 8));
+        }
+
+        assert_eq!(frames.len(), 3);
+        assert_eq!(
+            frames[0],
+            Frame {
+                opcode: "text",
+                length: 5,
+                preview: b"hello".to_vec()
+            }
+        );
+        assert_eq!(frames[1].opcode, "binary");
+        assert_eq!(frames[1].length, 256);
+        assert_eq!(frames[1].preview, vec![7u8; 8]);
+        assert_eq!(frames[2].opcode, "close");
+        assert_eq!(frames[2].length, 0);
+    }
+}
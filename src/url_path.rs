@@ -0,0 +1,200 @@
+/// A path whose `..` segments climb above the root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EscapesRoot;
+
+impl std::fmt::Display for EscapesRoot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "path climbs above the root")
+    }
+}
+
+impl std::error::Error for EscapesRoot {}
+
+enum Segment {
+    Current,
+    Parent,
+    Other,
+}
+
+fn classify(segment: &str) -> Segment {
+    if segment.len() > 6 {
+        return Segment::Other;
+    }
+    match segment.to_ascii_lowercase().replace("%2e", ".").as_str() {
+        "." => Segment::Current,
+        ".." => Segment::Parent,
+        _ => Segment::Other,
+    }
+}
+
+/// Resolves `.` and `..` segments and collapses repeated slashes. The path
+/// stays percent-encoded; only encoded dots are looked through, so `%2e%2e`
+/// cannot sneak a `..` past route matching. A trailing slash is kept, and
+/// added after a final dot segment as RFC 3986 does. Paths not starting
+/// with `/`, such as `*`, are returned unchanged.
+pub fn normalize(path: &str) -> Result<String, EscapesRoot> {
+    if !path.starts_with('/') {
+        return Ok(path.to_string());
+    }
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing = false;
+    for segment in path.split('/').skip(1) {
+        trailing = true;
+        match classify(segment) {
+            Segment::Current => {}
+            Segment::Parent => {
+                segments.pop().ok_or(EscapesRoot)?;
+            }
+            Segment::Other if segment.is_empty() => {}
+            Segment::Other => {
+                segments.push(segment);
+                trailing = false;
+            }
+        }
+    }
+
+    let mut normalized = String::with_capacity(path.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if trailing || normalized.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// Whether `path` starts with `prefix` at a segment boundary: `/api`
+/// covers `/api` and `/api/users` but not `/apiv2`.
+pub fn has_prefix(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix).is_some_and(|rest| {
+        prefix.is_empty() || prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/')
+    })
+}
+
+/// Appends `rest` to `base` with exactly one slash between them.
+pub fn join(base: &str, rest: &str) -> String {
+    if rest.is_empty() {
+        return tidy(base);
+    }
+    tidy(&format!(
+        "{}/{}",
+        base.trim_end_matches('/'),
+        rest.trim_start_matches('/')
+    ))
+}
+
+/// Percent-decodes one path segment, or `None` if an escape is malformed
+/// or the result is not UTF-8.
+pub fn decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Collapses repeated slashes and makes sure the path starts with one.
+pub fn tidy(path: &str) -> String {
+    let mut tidied = String::with_capacity(path.len() + 1);
+    for c in std::iter::once('/').chain(path.chars()) {
+        if !(c == '/' && tidied.ends_with('/')) {
+            tidied.push(c);
+        }
+    }
+    tidied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let cases = [
+            ("/", Ok("/")),
+            ("/api/users", Ok("/api/users")),
+            ("/api//users/", Ok("/api/users/")),
+            ("/api/./users", Ok("/api/users")),
+            ("/api/../admin", Ok("/admin")),
+            ("/api/users/..", Ok("/api/")),
+            ("/api/%2e%2e/admin", Ok("/admin")),
+            ("/api/%2E./admin", Ok("/admin")),
+            ("/api/.%2e", Ok("/")),
+            ("/a/%2e/b", Ok("/a/b")),
+            ("/files/a%2Fb/%20c", Ok("/files/a%2Fb/%20c")),
+            ("/files/..data/.hidden", Ok("/files/..data/.hidden")),
+            ("/files/%2e%2e%2f", Ok("/files/%2e%2e%2f")),
+            ("*", Ok("*")),
+            ("/..", Err(EscapesRoot)),
+            ("/api/../../etc/passwd", Err(EscapesRoot)),
+            ("/%2e%2e/etc", Err(EscapesRoot)),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(
+                normalize(path).as_deref().map_err(|e| *e),
+                expected,
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_has_prefix() {
+        let cases = [
+            ("/api", "/api", true),
+            ("/api/", "/api", true),
+            ("/api/users", "/api", true),
+            ("/apiv2/secret", "/api", false),
+            ("/api", "/api/", false),
+            ("/api/users", "/api/", true),
+            ("/anything", "/", true),
+            ("/anything", "", true),
+            ("/other", "/api", false),
+        ];
+        for (path, prefix, expected) in cases {
+            assert_eq!(has_prefix(path, prefix), expected, "{path} under {prefix}");
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode("a%20b").as_deref(), Some("a b"));
+        assert_eq!(decode("%2e%2E").as_deref(), Some(".."));
+        assert_eq!(decode("a%2Fb").as_deref(), Some("a/b"));
+        assert_eq!(decode("caf%C3%A9").as_deref(), Some("café"));
+        assert_eq!(decode("plain").as_deref(), Some("plain"));
+        assert_eq!(decode("%2"), None);
+        assert_eq!(decode("%zz"), None);
+        assert_eq!(decode("%ff"), None);
+    }
+
+    #[test]
+    fn test_join_and_tidy() {
+        let cases = [
+            ("/v1", "/users", "/v1/users"),
+            ("/v1/", "/users", "/v1/users"),
+            ("/v1", "users", "/v1/users"),
+            ("/v1", "", "/v1"),
+            ("/v1", "/", "/v1/"),
+            ("/", "", "/"),
+            ("", "", "/"),
+            ("", "/users", "/users"),
+            ("v1//", "//a%2Fb", "/v1/a%2Fb"),
+        ];
+        for (base, rest, expected) in cases {
+            assert_eq!(join(base, rest), expected, "{base:?} + {rest:?}");
+        }
+        assert_eq!(tidy("a//b///c/"), "/a/b/c/");
+        assert_eq!(tidy("/"), "/");
+    }
+}